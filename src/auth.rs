@@ -1,9 +1,17 @@
+use crate::endpoints::{REDDIT_OAUTH_URL, REDDIT_URL};
 use crate::errors::GertError;
+use crate::ratelimit::RateLimiter;
 
-use log::debug;
+use log::{debug, info};
 use reqwest::header::AUTHORIZATION;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Log in again this long before the access token expires, so that requests in flight do
+/// not race the expiry
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
 
 /// To generate the Reddit Client ID and secret, go to reddit [preferences](https://www.reddit.com/prefs/apps)
 pub struct Client<'a> {
@@ -15,11 +23,21 @@ pub struct Client<'a> {
     username: &'a str,
     /// Login password
     password: &'a str,
+    /// Scope requested for the access token
+    scope: &'a str,
     /// Reqwest client
     session: &'a reqwest::Client,
+    /// Base URL of the access token endpoint
+    base_url: &'a str,
+    /// Base URL of the endpoints that use the access token
+    oauth_url: &'a str,
+    /// The access token of the last login
+    auth: AsyncMutex<Option<Auth>>,
+    /// Shared with the other requests to the reddit API
+    rate_limiter: RateLimiter,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Auth {
     /// The generated bearer access token for the application
     pub access_token: String,
@@ -29,6 +47,27 @@ pub struct Auth {
     expires_in: i32,
     /// Scope of the access token. This app requires * scope
     scope: String,
+    /// When the access token was obtained
+    #[serde(skip, default = "SystemTime::now")]
+    issued_at: SystemTime,
+}
+
+/// Body of the access token responses that carry no token
+#[derive(Deserialize, Debug)]
+struct AuthError {
+    error: String,
+}
+
+impl Auth {
+    /// When reddit stops accepting the access token
+    pub fn expires_at(&self) -> SystemTime {
+        self.issued_at + Duration::from_secs(self.expires_in.max(0) as u64)
+    }
+
+    /// Whether the access token expired or is about to
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() + EXPIRY_MARGIN >= self.expires_at()
+    }
 }
 
 impl<'a> Client<'a> {
@@ -37,9 +76,58 @@ impl<'a> Client<'a> {
         secret: &'a str,
         username: &'a str,
         password: &'a str,
+        scope: &'a str,
         session: &'a reqwest::Client,
     ) -> Self {
-        Self { client_id: id, client_secret: secret, username, password, session }
+        Self {
+            client_id: id,
+            client_secret: secret,
+            username,
+            password,
+            scope,
+            session,
+            base_url: REDDIT_URL,
+            oauth_url: REDDIT_OAUTH_URL,
+            auth: AsyncMutex::new(None),
+            rate_limiter: RateLimiter::new(0),
+        }
+    }
+
+    /// Count the logins against the limit of the other requests to the reddit API
+    pub fn with_rate_limiter(mut self, rate_limiter: &RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter.clone();
+        self
+    }
+
+    /// Send the requests to other hosts than reddit, such as a mirror or a mock server
+    pub fn with_base_urls(mut self, base_url: &'a str, oauth_url: &'a str) -> Self {
+        self.base_url = base_url;
+        self.oauth_url = oauth_url;
+        self
+    }
+
+    /// Base URL of the endpoints that use the access token
+    pub fn oauth_url(&self) -> &str {
+        self.oauth_url
+    }
+
+    /// A valid access token, logging in again if the last one expired
+    pub async fn access_token(&self) -> Result<String, GertError> {
+        let cached = self.auth.lock().await.clone();
+        match cached {
+            Some(auth) if !auth.is_expired() => Ok(auth.access_token),
+            Some(_) => {
+                info!("Access token expired, logging in to Reddit again");
+                Ok(self.login().await?.access_token)
+            }
+            None => Ok(self.login().await?.access_token),
+        }
+    }
+
+    /// Log in again after reddit rejected the access token before its expiry
+    pub async fn refresh(&self) -> Result<String, GertError> {
+        info!("Access token was rejected, logging in to Reddit again");
+        Ok(self.login().await?.access_token)
     }
 
     pub async fn login(&self) -> Result<Auth, GertError> {
@@ -50,10 +138,13 @@ impl<'a> Client<'a> {
         body.insert("username", self.username);
         body.insert("password", self.password);
         body.insert("grant_type", &grant_type);
+        body.insert("scope", self.scope);
 
-        let auth = self
+        let url = format!("{}/api/v1/access_token", self.base_url);
+        self.rate_limiter.acquire().await;
+        let response = self
             .session
-            .post("https://www.reddit.com/api/v1/access_token")
+            .post(&url)
             // base64 encoded <clientID>:<clientSecret> should be sent as a basic token
             // along with the body of the message
             .header(AUTHORIZATION, format!("Basic {}", basic_token))
@@ -61,11 +152,188 @@ impl<'a> Client<'a> {
             // the API does not accept JSON body when trying to obtain a bearer token
             .form(&body)
             .send()
-            .await?
-            .json::<Auth>()
             .await?;
 
+        let status = response.status();
+        if !status.is_success() {
+            return Err(GertError::LoginFailed(format!("reddit responded with {}", status)));
+        }
+        let text = response.text().await?;
+        let auth = match serde_json::from_str::<Auth>(&text) {
+            Ok(auth) => auth,
+            // reddit rejects wrong credentials with a 200 and an error field
+            Err(_) => match serde_json::from_str::<AuthError>(&text) {
+                Ok(AuthError { error }) => return Err(GertError::LoginFailed(error)),
+                Err(_) => return Err(GertError::JsonParseError(url)),
+            },
+        };
+
         debug!("Access token is: {}", auth.access_token);
+        *self.auth.lock().await = Some(auth.clone());
         Ok(auth)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::User;
+    use serde_json::json;
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn token(access_token: &str) -> serde_json::Value {
+        json!({
+            "access_token": access_token,
+            "token_type": "bearer",
+            "expires_in": 3600,
+            "scope": "*"
+        })
+    }
+
+    async fn mock_token(server: &MockServer, response: ResponseTemplate) {
+        Mock::given(method("POST"))
+            .and(path("/api/v1/access_token"))
+            .respond_with(response)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let server = MockServer::start().await;
+        mock_token(&server, ResponseTemplate::new(200).set_body_json(token("abc"))).await;
+        let session = reqwest::Client::new();
+        let uri = server.uri();
+        let client =
+            Client::new("id", "secret", "user", "pass", "*", &session).with_base_urls(&uri, &uri);
+
+        let auth = client.login().await.unwrap();
+        assert_eq!(auth.access_token, "abc");
+        assert!(!auth.is_expired());
+        assert_eq!(client.access_token().await.unwrap(), "abc");
+    }
+
+    #[tokio::test]
+    async fn test_login_rejected() {
+        let server = MockServer::start().await;
+        let body = json!({ "error": "invalid_grant" });
+        mock_token(&server, ResponseTemplate::new(200).set_body_json(body)).await;
+        let session = reqwest::Client::new();
+        let uri = server.uri();
+        let client =
+            Client::new("id", "secret", "user", "wrong", "*", &session).with_base_urls(&uri, &uri);
+
+        match client.login().await {
+            Err(GertError::LoginFailed(error)) => assert_eq!(error, "invalid_grant"),
+            other => panic!("expected a login failure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_malformed_response() {
+        let server = MockServer::start().await;
+        mock_token(&server, ResponseTemplate::new(200).set_body_string("<html></html>")).await;
+        let session = reqwest::Client::new();
+        let uri = server.uri();
+        let client =
+            Client::new("id", "secret", "user", "pass", "*", &session).with_base_urls(&uri, &uri);
+
+        assert!(matches!(client.login().await, Err(GertError::JsonParseError(_))));
+
+        let server = MockServer::start().await;
+        mock_token(&server, ResponseTemplate::new(401)).await;
+        let uri = server.uri();
+        let client =
+            Client::new("id", "secret", "user", "pass", "*", &session).with_base_urls(&uri, &uri);
+        assert!(matches!(client.login().await, Err(GertError::LoginFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_about_refreshes_rejected_token() {
+        let server = MockServer::start().await;
+        mock_token(&server, ResponseTemplate::new(200).set_body_json(token("abc"))).await;
+        Mock::given(method("GET"))
+            .and(path("/user/user/about"))
+            .and(header("authorization", "Bearer abc"))
+            .respond_with(ResponseTemplate::new(401))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        let about = json!({
+            "kind": "t2",
+            "data": {
+                "comment_karma": 1,
+                "created": 0.0,
+                "created_utc": 0.0,
+                "has_subscribed": false,
+                "has_verified_email": true,
+                "hide_from_robots": false,
+                "id": "abc123",
+                "is_employee": false,
+                "is_friend": false,
+                "is_gold": false,
+                "is_mod": false,
+                "link_karma": 2,
+                "name": "user"
+            }
+        });
+        Mock::given(method("GET"))
+            .and(path("/user/user/about"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(about))
+            .mount(&server)
+            .await;
+        let session = reqwest::Client::new();
+        let uri = server.uri();
+        let client =
+            Client::new("id", "secret", "user", "pass", "*", &session).with_base_urls(&uri, &uri);
+
+        let user = User::new(&client, "user", &session, &RateLimiter::new(0));
+        let about = user.about().await.unwrap();
+        assert_eq!(about.data.id, "abc123");
+        // one login for the first token and another one after it was rejected
+        let logins = server.received_requests().await.unwrap();
+        let logins = logins.iter().filter(|r| r.url.path() == "/api/v1/access_token").count();
+        assert_eq!(logins, 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions() {
+        let server = MockServer::start().await;
+        mock_token(&server, ResponseTemplate::new(200).set_body_json(token("abc"))).await;
+        let page = |names: &[&str], after: Option<&str>| {
+            let children: Vec<_> = names
+                .iter()
+                .map(|name| json!({"kind": "t5", "data": {"display_name": name}}))
+                .collect();
+            json!({"kind": "Listing", "data": {"after": after, "children": children}})
+        };
+        Mock::given(method("GET"))
+            .and(path("/subreddits/mine/subscriber"))
+            .and(query_param("after", "t5_page2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(&["u_spez", "aww"], None)))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/subreddits/mine/subscriber"))
+            .and(header("authorization", "Bearer abc"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(page(&["pics", "u_kn0thing"], Some("t5_page2"))),
+            )
+            .mount(&server)
+            .await;
+        let session = reqwest::Client::new();
+        let uri = server.uri();
+        let client =
+            Client::new("id", "secret", "user", "pass", "*", &session).with_base_urls(&uri, &uri);
+
+        let user = User::new(&client, "user", &session, &RateLimiter::new(0));
+        let (subreddits, users) = user.subscriptions().await.unwrap();
+        assert_eq!(subreddits, vec!["pics", "aww"]);
+        assert_eq!(users, vec!["kn0thing", "spez"]);
+        let pages = server.received_requests().await.unwrap();
+        let pages = pages.iter().filter(|r| r.url.path() == "/subreddits/mine/subscriber").count();
+        assert_eq!(pages, 2);
+    }
+}
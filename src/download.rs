@@ -1,21 +1,49 @@
 use futures::future::join_all;
 use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::path::Path;
 use std::process::Stdio;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{fs, io};
 use tokio::sync::{Mutex as AsyncMutex, Semaphore};
 
 use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
+use reqwest::header::{HeaderName, CONTENT_LENGTH, LAST_MODIFIED};
+use serde::Serialize;
 use url::{Position, Url};
 
+mod events;
+mod export;
+mod hosts;
+mod manifest;
+mod phash;
+
+use crate::embed::{embed_image_metadata, embed_video_metadata, EmbeddedMetadata};
+use crate::endpoints::Endpoints;
 use crate::errors::GertError;
+use crate::ratelimit::HostRateLimiter;
 use crate::structs::Post;
-use crate::structs::{RedGif, StreamableApiResponse, TokenResponse};
-use crate::utils::{check_path_present, check_url_has_mime_type, contains_any, parse_mpd};
+use crate::structs::{
+    GiphyApiResponse, ImgurAlbumResponse, RedGif, StreamableApiResponse, TokenResponse,
+    TwitterSyndicationResponse,
+};
+use crate::utils::{
+    check_path_present, check_url_has_mime_type, contains_any, normalize_imgur_url, parse_mpd,
+    render_subreddit_dir,
+};
+use crate::verify::looks_corrupt;
+use events::Event;
+pub use events::EventLog;
+pub use export::PostExport;
+use hosts::{default_hosts, MediaDownloader};
+pub use manifest::Manifest;
+use manifest::ManifestEntry;
+use phash::{hash_file, HASHED_EXTENSIONS};
+pub use phash::{PerceptualDedupe, NEAR_DUPLICATES_FILE};
 
 pub static JPG: &str = "jpg";
 pub static PNG: &str = "png";
@@ -24,17 +52,44 @@ pub const GIF: &str = "gif";
 pub const GIFV: &str = "gifv";
 pub const MP4: &str = "mp4";
 pub const ZIP: &str = "zip";
+pub const M4A: &str = "m4a";
+pub const MKV: &str = "mkv";
 
 // static REDDIT_DOMAIN: &str = "reddit.com";
 pub static REDDIT_IMAGE_SUBDOMAIN: &str = "i.redd.it";
 pub static REDDIT_VIDEO_SUBDOMAIN: &str = "v.redd.it";
+pub static REDDIT_PREVIEW_SUBDOMAIN: &str = "preview.redd.it";
+pub static REDDIT_EXTERNAL_PREVIEW_SUBDOMAIN: &str = "external-preview.redd.it";
+pub static REDDIT_PREVIEW_SUBDOMAINS: [&str; 2] =
+    [REDDIT_PREVIEW_SUBDOMAIN, REDDIT_EXTERNAL_PREVIEW_SUBDOMAIN];
+/// Heights of the DASH_<height>.mp4 streams reddit encodes videos to, highest first
+pub static REDDIT_VIDEO_RESOLUTIONS: [&str; 7] = ["1080", "720", "480", "360", "240", "220", "96"];
+/// Values accepted by --on-exists, see `OnExists`
+pub static ON_EXISTS_POLICIES: [&str; 4] = ["skip", "overwrite", "rename", "newer"];
+/// Extensions --fix-extensions renames files to, with the extensions that hold the same format
+static DETECTED_EXTENSIONS: [(&str, &[&str]); 7] = [
+    (JPG, &[JPEG]),
+    (PNG, &[]),
+    (GIF, &[]),
+    ("webp", &[]),
+    (MP4, &[M4A, "m4v"]),
+    ("webm", &[]),
+    (MKV, &[]),
+];
+/// Values accepted by --merge-format, see `MergeFormat`
+pub static MERGE_FORMATS: [&str; 2] = [MP4, MKV];
+/// Values accepted by --convert-to, see `VideoCodec`
+pub static VIDEO_CODECS: [&str; 3] = ["webm", "av1", "h265"];
 // static REDDIT_GALLERY_PATH: &str = "gallery";
 
 pub static IMGUR_DOMAIN: &str = "imgur.com";
 pub static IMGUR_SUBDOMAIN: &str = "i.imgur.com";
 
 pub static REDGIFS_DOMAIN: &str = "redgifs.com";
-static REDGIFS_API_PREFIX: &str = "https://api.redgifs.com/v2";
+/// Number of times the redgif token is requested before giving up
+const REDGIF_TOKEN_ATTEMPTS: u32 = 3;
+/// Post titles are truncated to this many characters to avoid file system limits
+pub const MAX_TITLE_LENGTH: usize = 200;
 
 pub static GIPHY_DOMAIN: &str = "giphy.com";
 static GIPHY_MEDIA_SUBDOMAIN: &str = "media.giphy.com";
@@ -52,11 +107,13 @@ static GIPHY_MEDIA_SUBDOMAINS: [&str; 6] = [
     GIPHY_MEDIA_SUBDOMAIN_4,
 ];
 
+pub static TWITTER_DOMAIN: &str = "twitter.com";
+pub static X_DOMAIN: &str = "x.com";
+
 pub static STREAMABLE_DOMAIN: &str = "streamable.com";
-static STREAMABLE_API: &str = "https://api.streamable.com/videos";
 
 /// Media Types Supported
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum MediaType {
     Gallery,
     RedditImage,
@@ -69,54 +126,333 @@ pub enum MediaType {
     ImgurAlbum,
     ImgurUnknown,
     StreamableVideo,
+    Tweet,
+    /// Reddit hosted preview of a video on an unsupported host
+    RedditVideoPreview,
+    /// Thumbnail of a post on an unsupported host
+    Thumbnail,
     Unsupported,
 }
 
+/// Counts of a finished run, as logged at the end of it
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub supported: u16,
+    pub unsupported: u16,
+    pub downloaded: u16,
+    pub skipped: u16,
+    pub failed: u16,
+    /// Media downloaded, failed and skipped for each media type
+    pub hosts: BTreeMap<String, HostSummary>,
+    /// Media downloaded, failed and skipped for each subreddit
+    pub subreddits: BTreeMap<String, HostSummary>,
+    pub seconds: f64,
+    /// Total size of the media saved
+    pub bytes: u64,
+    /// Average download speed over the whole run
+    pub megabytes_per_second: f64,
+    /// Fullnames of the posts with at least one media that failed to download
+    pub failed_posts: Vec<String>,
+    /// Links of the posts no host supports, in the order they were processed
+    pub unsupported_urls: Vec<String>,
+    /// Total size of the media found by a dry run with --estimate
+    pub estimated_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostSummary {
+    pub downloaded: u16,
+    pub failed: u16,
+    pub skipped: u16,
+}
+
+/// What to do when the file a media would be saved to already exists. Gifs converted to
+/// mp4 and unzipped imgur albums count as existing files for `Skip`, `Rename` and `Newer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnExists {
+    /// Keep the existing file
+    #[default]
+    Skip,
+    /// Download the media again, replacing the existing file
+    Overwrite,
+    /// Save the media under a numbered name when the file holds the media of another URL,
+    /// and keep the file when it holds the media of the same URL
+    Rename,
+    /// Download the media again when the server reports a version different from the file
+    Newer,
+}
+
+/// Values accepted by --image-resolution, see `ImageResolution`
+pub static IMAGE_RESOLUTIONS: [&str; 4] = ["source", "max", "1080", "720"];
+
+/// Size of the reddit images to download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageResolution {
+    /// The image as it was uploaded
+    #[default]
+    Source,
+    /// The widest resized copy reddit made of the image
+    Max,
+    /// The widest resized copy at most this many pixels wide
+    Width(i64),
+}
+
+/// Container the video and audio streams of reddit videos are merged into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeFormat {
+    /// Replaces the video stream, but can not hold the Opus audio of some newer videos
+    #[default]
+    Mp4,
+    /// Saved under the name of the video stream with an mkv extension, holds any audio codec
+    Mkv,
+}
+
+/// Codec videos and gifs are transcoded to with --convert-to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// VP9 and Opus in a webm file
+    Webm,
+    /// AV1 in an mp4 file, encoded with SVT-AV1
+    Av1,
+    /// H.265 in an mp4 file, encoded with x265
+    H265,
+}
+
+impl VideoCodec {
+    fn name(&self) -> &'static str {
+        match self {
+            VideoCodec::Webm => "webm",
+            VideoCodec::Av1 => "av1",
+            VideoCodec::H265 => "h265",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            VideoCodec::Webm => "webm",
+            VideoCodec::Av1 | VideoCodec::H265 => MP4,
+        }
+    }
+
+    /// Quality used without --crf, about the quality of the downloaded videos
+    fn default_crf(&self) -> u32 {
+        match self {
+            VideoCodec::Webm => 32,
+            VideoCodec::Av1 => 35,
+            VideoCodec::H265 => 28,
+        }
+    }
+}
+
+/// Settings that control what the downloader fetches and how it is stored
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Directory to save the media to
+    pub data_directory: String,
+    /// Download the media instead of only printing the URLs
+    pub should_download: bool,
+    /// Print the URL each media resolves to after following redirects instead of saving it
+    pub resolve_only: bool,
+    /// Add up the size of the media when not downloading them
+    pub estimate_size: bool,
+    /// Use human readable names for files instead of MD5 hashes
+    pub use_human_readable: bool,
+    /// Name files after the fullname of their post instead of a hash of the media URL, so that
+    /// a post keeps its file name whatever URL its media resolves to
+    pub name_by_post_id: bool,
+    /// Join the words of post titles with dashes in file and folder names
+    pub slugify: bool,
+    /// Capitalize the words of post titles in file and folder names instead of lowercasing them
+    pub title_case: bool,
+    /// Truncate post titles in file and folder names to this many characters instead of
+    /// `MAX_TITLE_LENGTH`
+    pub max_title_length: Option<usize>,
+    /// Whether ffmpeg is installed for post processing
+    pub ffmpeg_available: bool,
+    /// Disable gif to mp4 conversion
+    pub conserve_gifs: bool,
+    /// Rename downloaded files whose content does not match their extension
+    pub fix_extensions: bool,
+    /// Only keep the audio of reddit, streamable and redgifs videos
+    pub audio_only: bool,
+    /// Save the video and audio streams of reddit videos as separate files instead of merging them
+    pub no_video_merge: bool,
+    /// Container the streams of reddit videos are merged into
+    pub merge_format: MergeFormat,
+    /// Download the animated gif variant of reddit images when available
+    pub prefer_gif_variant: bool,
+    /// Download the mp4 reddit made of i.redd.it gifs instead of the gif, so that no
+    /// conversion with ffmpeg is needed
+    pub prefer_reddit_mp4: bool,
+    /// Download a resized copy of reddit images from the post preview instead of the source
+    pub image_resolution: ImageResolution,
+    /// Transcode the downloaded videos and gifs to this codec
+    pub convert_to: Option<VideoCodec>,
+    /// Constant rate factor of the transcoding, lower is better quality and larger files
+    pub crf: Option<u32>,
+    /// Encoder preset of the transcoding to av1 or h265, eg: medium or 8
+    pub preset: Option<String>,
+    /// Keep the downloaded file next to the transcoded one
+    pub keep_original_after_transcode: bool,
+    /// Download the uploaded file of redgifs and streamable videos instead of the transcode
+    /// when the API exposes it
+    pub prefer_original: bool,
+    /// Only download media with these extensions
+    pub only_extensions: Option<Vec<String>>,
+    /// Never download media with these extensions
+    pub skip_extensions: Vec<String>,
+    /// Save the items of each gallery in their own folder
+    pub gallery_subfolders: bool,
+    /// Write a <gallery>.json listing the items of each gallery in order
+    pub gallery_index: bool,
+    /// Key used to resolve giphy links with the giphy API
+    pub giphy_api_key: Option<String>,
+    /// Requests per minute for each host media are downloaded from, see `DEFAULT_HOST_LIMITS`
+    pub host_rate_limits: HashMap<String, u32>,
+    /// Imgur API client id used to download the images of albums whose zip is corrupt
+    pub imgur_client_id: Option<String>,
+    /// Skip reddit images already downloaded as part of another post or gallery
+    pub dedupe_across_galleries: bool,
+    /// Highest reddit video resolution to probe for when the MPD file is unavailable
+    pub video_quality: Option<u32>,
+    /// Skip videos shorter than this many seconds
+    pub min_duration: Option<u64>,
+    /// Skip videos longer than this many seconds
+    pub max_duration: Option<u64>,
+    /// Download the media of tweets
+    pub enable_twitter: bool,
+    /// Download the reddit hosted preview of videos on unsupported hosts
+    pub use_video_preview: bool,
+    /// Download the thumbnail of posts on unsupported hosts
+    pub thumbnail_fallback: bool,
+    /// Nitter instance used to resolve tweets instead of the twitter syndication API
+    pub nitter_instance: Option<String>,
+    /// Base URLs of the APIs the media are resolved with
+    pub endpoints: Endpoints,
+    /// Milliseconds to wait before starting each post
+    pub sleep_between: u64,
+    /// Write the post details into the EXIF data of images and the tags of videos
+    pub embed_metadata: bool,
+    /// Record the permalink of the post of each file in the urls.txt of its subreddit folder
+    pub write_urls: bool,
+    /// Log the downloaded, failed and skipped media of each subreddit at the end of the run
+    pub per_subreddit_report: bool,
+    /// What to do when a file with the same name already exists
+    pub on_exists: OnExists,
+    /// Download existing files again when they are tiny or an HTML page, see `looks_corrupt`
+    pub replace_corrupt: bool,
+    /// Template for the name of the subreddit folders, see `render_subreddit_dir`
+    pub subreddit_dir_template: Option<String>,
+    /// Name galleries with a single item like a single image
+    pub flatten_single_galleries: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Downloader {
     posts: Vec<Post>,
-    data_directory: String,
-    should_download: bool,
-    use_human_readable: bool,
-    ffmpeg_available: bool,
     session: reqwest::Client,
-    conserve_gifs: bool,
+    options: DownloadOptions,
+    /// Paces the requests to each media host, apart from the reddit API budget
+    host_rate_limiter: HostRateLimiter,
     supported: Arc<AsyncMutex<u16>>,
     skipped: Arc<AsyncMutex<u16>>,
     downloaded: Arc<AsyncMutex<u16>>,
     failed: Arc<AsyncMutex<u16>>,
     unsupported: Arc<AsyncMutex<u16>>,
-    ephemeral_token: Option<String>,
+    /// Links of the posts no host supports, reported with --strict
+    unsupported_urls: Arc<AsyncMutex<Vec<String>>>,
+    /// Total size of the media saved so far
+    bytes_downloaded: Arc<AsyncMutex<u64>>,
+    /// Bytes the transcoded files take less than the downloaded ones, negative if more
+    bytes_saved: Arc<AsyncMutex<i64>>,
+    /// Perceptual hashes of the images saved so far, with --phash-dedupe
+    perceptual_dedupe: Option<Arc<PerceptualDedupe>>,
+    /// Images deleted as near duplicates of an image saved earlier
+    near_duplicates: Arc<AsyncMutex<u16>>,
+    /// Fullnames of the posts with a failed download
+    failed_posts: Arc<AsyncMutex<BTreeSet<String>>>,
+    /// Total size of the media found by a dry run and the number of media of unknown size
+    estimated: Arc<AsyncMutex<(u64, u16)>>,
+    /// Held while appending to a urls.txt file so that the lines of two tasks do not mix
+    urls_file: Arc<AsyncMutex<()>>,
+    /// Number of media (downloaded, failed, skipped) for each media type
+    host_stats: Arc<AsyncMutex<HashMap<MediaType, (u16, u16, u16)>>>,
+    /// Number of media (downloaded, failed, skipped) for each subreddit
+    subreddit_stats: Arc<AsyncMutex<HashMap<String, (u16, u16, u16)>>>,
+    /// Reddit media ids scheduled so far, used by --dedupe-across-galleries
+    seen_media_ids: Arc<AsyncMutex<HashSet<String>>>,
+    /// Downloaders of the supported hosts, tried in order
+    hosts: Arc<Vec<Box<dyn MediaDownloader>>>,
+    /// Temporary redgif API token, shared so that an expired token is refreshed once
+    ephemeral_token: Arc<AsyncMutex<Option<String>>>,
+    /// Where the events of the run are streamed to with --output-json-lines
+    events: Option<Arc<EventLog>>,
+    /// Where the processed posts are written to with --export-json
+    export: Option<Arc<PostExport>>,
+    /// Index of the downloaded media with --output-manifest-sqlite
+    manifest: Option<Arc<Manifest>>,
 }
 
 impl Downloader {
-    pub fn new(
-        posts: Vec<Post>,
-        data_directory: &str,
-        should_download: bool,
-        use_human_readable: bool,
-        ffmpeg_available: bool,
-        session: reqwest::Client,
-        conserve_gifs: bool,
-    ) -> Downloader {
+    pub fn new(posts: Vec<Post>, session: reqwest::Client, options: DownloadOptions) -> Downloader {
+        let hosts = Arc::new(default_hosts(&options));
+        let host_rate_limiter = HostRateLimiter::new(options.host_rate_limits.clone());
         Downloader {
             posts,
-            data_directory: data_directory.to_owned(),
-            should_download,
-            use_human_readable,
-            ffmpeg_available,
             session,
-            conserve_gifs,
+            options,
+            host_rate_limiter,
             supported: Arc::new(AsyncMutex::new(0)),
             skipped: Arc::new(AsyncMutex::new(0)),
             downloaded: Arc::new(AsyncMutex::new(0)),
             failed: Arc::new(AsyncMutex::new(0)),
             unsupported: Arc::new(AsyncMutex::new(0)),
-            ephemeral_token: None,
+            unsupported_urls: Arc::new(AsyncMutex::new(Vec::new())),
+            bytes_downloaded: Arc::new(AsyncMutex::new(0)),
+            bytes_saved: Arc::new(AsyncMutex::new(0)),
+            perceptual_dedupe: None,
+            near_duplicates: Arc::new(AsyncMutex::new(0)),
+            failed_posts: Arc::new(AsyncMutex::new(BTreeSet::new())),
+            estimated: Arc::new(AsyncMutex::new((0, 0))),
+            urls_file: Arc::new(AsyncMutex::new(())),
+            host_stats: Arc::new(AsyncMutex::new(HashMap::new())),
+            subreddit_stats: Arc::new(AsyncMutex::new(HashMap::new())),
+            seen_media_ids: Arc::new(AsyncMutex::new(HashSet::new())),
+            hosts,
+            ephemeral_token: Arc::new(AsyncMutex::new(None)),
+            events: None,
+            export: None,
+            manifest: None,
+        }
+    }
+
+    /// Stream the events of the run to the log as they happen
+    pub fn set_event_log(&mut self, events: EventLog) {
+        self.events = Some(Arc::new(events));
+    }
+
+    /// Write the data of every processed post to the export
+    pub fn set_export(&mut self, export: PostExport) {
+        self.export = Some(Arc::new(export));
+    }
+
+    /// Delete the images that look like one already in the output directory
+    pub fn set_perceptual_dedupe(&mut self, dedupe: PerceptualDedupe) {
+        self.perceptual_dedupe = Some(Arc::new(dedupe));
+    }
+
+    /// Record every media saved or already on disk in the manifest
+    pub fn set_manifest(&mut self, manifest: Manifest) {
+        self.manifest = Some(Arc::new(manifest));
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.emit(&event);
         }
     }
 
-    pub async fn run(&mut self) -> Result<(), GertError> {
+    pub async fn run(&mut self) -> Result<RunSummary, GertError> {
         let start = Instant::now();
         if self.maybe_get_redgif_token().await.is_err() {
             error!("Could not create Redgif API token.");
@@ -129,6 +465,10 @@ impl Downloader {
 
         for i in 0..posts.len() {
             let permit = semaphore.clone().acquire_owned().await.unwrap();
+            if self.options.sleep_between > 0 && i > 0 {
+                // pace the start of the tasks, a freed permit is not used right away
+                tokio::time::sleep(Duration::from_millis(self.options.sleep_between)).await;
+            }
             let dl = downloader.clone();
             let posts = Arc::clone(&posts);
             let handle = tokio::spawn(async move {
@@ -140,6 +480,9 @@ impl Downloader {
         }
 
         join_all(handles).await;
+        if let Some(export) = &self.export {
+            export.finish()?;
+        }
 
         let end = Instant::now();
         info!("#####################################");
@@ -149,18 +492,96 @@ impl Downloader {
         info!("Number of media downloaded: {}", *self.downloaded.lock().await);
         info!("Number of media skipped: {}", *self.skipped.lock().await);
         info!("Number of media failed to download: {}", *self.failed.lock().await);
-        info!("Time taken: {:.2} seconds", (end - start).as_secs_f64());
+        let host_stats = self.host_stats.lock().await;
+        if !host_stats.is_empty() {
+            info!("Breakdown per host (downloaded / failed / skipped):");
+            let mut hosts: Vec<_> = host_stats.iter().collect();
+            hosts.sort_by_key(|(media_type, _)| format!("{:?}", media_type));
+            for (media_type, (downloaded, failed, skipped)) in hosts {
+                info!(
+                    "{:<16} {:>5} / {:>5} / {:>5}",
+                    format!("{:?}", media_type),
+                    downloaded,
+                    failed,
+                    skipped
+                );
+            }
+        }
+        let subreddit_stats = self.subreddit_stats.lock().await;
+        if self.options.per_subreddit_report && !subreddit_stats.is_empty() {
+            info!("Breakdown per subreddit (downloaded / failed / skipped):");
+            let mut subreddits: Vec<_> = subreddit_stats.iter().collect();
+            subreddits.sort_by_key(|(subreddit, _)| subreddit.to_lowercase());
+            for (subreddit, (downloaded, failed, skipped)) in subreddits {
+                info!("{:<24} {:>5} / {:>5} / {:>5}", subreddit, downloaded, failed, skipped);
+            }
+        }
+        let seconds = (end - start).as_secs_f64();
+        let bytes = *self.bytes_downloaded.lock().await;
+        let megabytes = bytes as f64 / 1_000_000.0;
+        let megabytes_per_second = if seconds > 0.0 { megabytes / seconds } else { 0.0 };
+        info!("Data downloaded: {:.2} MB ({:.2} MB/s)", megabytes, megabytes_per_second);
+        if self.perceptual_dedupe.is_some() {
+            info!(
+                "Number of near duplicate images removed: {}",
+                *self.near_duplicates.lock().await
+            );
+        }
+        if self.options.convert_to.is_some() {
+            let saved = *self.bytes_saved.lock().await as f64 / 1_000_000.0;
+            info!("Space saved by transcoding: {:.2} MB", saved);
+        }
+        let (estimated_bytes, unknown_size) = *self.estimated.lock().await;
+        if self.options.estimate_size {
+            info!(
+                "Estimated size of the {} media found: {:.2} MB ({} of unknown size)",
+                *self.supported.lock().await,
+                estimated_bytes as f64 / 1_000_000.0,
+                unknown_size
+            );
+        }
+        info!("Time taken: {:.2} seconds", seconds);
         info!("#####################################");
         info!("FIN.");
 
-        Ok(())
+        let hosts = host_stats
+            .iter()
+            .map(|(media_type, (downloaded, failed, skipped))| {
+                let summary =
+                    HostSummary { downloaded: *downloaded, failed: *failed, skipped: *skipped };
+                (format!("{:?}", media_type), summary)
+            })
+            .collect();
+        let subreddits = subreddit_stats
+            .iter()
+            .map(|(subreddit, (downloaded, failed, skipped))| {
+                let summary =
+                    HostSummary { downloaded: *downloaded, failed: *failed, skipped: *skipped };
+                (subreddit.clone(), summary)
+            })
+            .collect();
+        Ok(RunSummary {
+            supported: *self.supported.lock().await,
+            unsupported: *self.unsupported.lock().await,
+            downloaded: *self.downloaded.lock().await,
+            skipped: *self.skipped.lock().await,
+            failed: *self.failed.lock().await,
+            hosts,
+            subreddits,
+            seconds,
+            bytes,
+            megabytes_per_second,
+            failed_posts: self.failed_posts.lock().await.iter().cloned().collect(),
+            unsupported_urls: self.unsupported_urls.lock().await.clone(),
+            estimated_bytes: self.options.estimate_size.then_some(estimated_bytes),
+        })
     }
 
     /// Generate a file name in the right format that Gert expects
     fn generate_file_name(
         &self,
         url: &str,
-        subreddit: &str,
+        directory: &str,
         extension: &str,
         name: &str,
         title: &str,
@@ -168,7 +589,12 @@ impl Downloader {
     ) -> String {
         let idx = index.unwrap_or(0);
 
-        return if !self.use_human_readable {
+        if self.options.name_by_post_id && !self.options.use_human_readable {
+            let name = if idx > 0 { format!("{}_{}", name, idx) } else { String::from(name) };
+            return format!("{}/{}/{}.{}", self.options.data_directory, directory, name, extension);
+        }
+
+        return if !self.options.use_human_readable {
             // create a hash for the media using the URL the media is located at
             // this helps to make sure the media download always writes the same file
             // name irrespective of how many times it's run. If run more than once, the
@@ -181,18 +607,15 @@ impl Downloader {
             let hash = md5::compute(parsed.as_str());
 
             if idx > 0 {
-                format!("{}/{}/{:x}_{}.{}", self.data_directory, subreddit, hash, idx, extension)
+                format!(
+                    "{}/{}/{:x}_{}.{}",
+                    self.options.data_directory, directory, hash, idx, extension
+                )
             } else {
-                format!("{}/{}/{:x}.{}", self.data_directory, subreddit, hash, extension)
+                format!("{}/{}/{:x}.{}", self.options.data_directory, directory, hash, extension)
             }
         } else {
-            let disallowed_chars = [' ', '.', '/', '\\', ':', '=', '?', '"', '<', '>', '|', '*'];
-            let canonical_title: String = title
-                .to_lowercase()
-                .chars()
-                .take(200) // Truncate to avoid file system limits
-                .map(|c| if disallowed_chars.contains(&c) { '_' } else { c })
-                .collect();
+            let canonical_title = canonical_title(title, &self.options);
             // create a canonical human readable file name using the post's title
             // note that the name of the post is something of the form t3_<randomstring>
             let canonical_name: String =
@@ -200,36 +623,83 @@ impl Downloader {
                     .replace('.', "_");
             format!(
                 "{}/{}/{}_{}.{}",
-                self.data_directory, subreddit, canonical_title, canonical_name, extension
+                self.options.data_directory, directory, canonical_title, canonical_name, extension
             )
         };
     }
 
-    async fn maybe_get_redgif_token(&mut self) -> Result<()> {
-        let mut needs_token = false;
-        if self.ephemeral_token.is_none() {
-            for post in self.posts.iter() {
-                if post.get_type() == MediaType::RedGif {
-                    needs_token = true;
-                    break;
+    async fn maybe_get_redgif_token(&self) -> Result<()> {
+        let needs_token = self.posts.iter().any(|post| post.get_type() == MediaType::RedGif);
+        if needs_token {
+            self.redgif_token().await?;
+        }
+        Ok(())
+    }
+
+    /// Get the current redgif token, requesting one if the run started without it
+    async fn redgif_token(&self) -> Result<String> {
+        let mut token = self.ephemeral_token.lock().await;
+        if let Some(current) = token.as_ref() {
+            return Ok(current.clone());
+        }
+        let fresh = self.fetch_redgif_token().await?;
+        *token = Some(fresh.clone());
+        Ok(fresh)
+    }
+
+    /// Request a temporary token from the redgif API, retrying failures with backoff
+    async fn fetch_redgif_token(&self) -> Result<String> {
+        let url = format!("{}/auth/temporary", self.options.endpoints.redgifs_api);
+        let mut attempt = 1;
+        loop {
+            self.host_rate_limiter.acquire(&url).await;
+            let result: Result<TokenResponse> = async {
+                self.session
+                    .get(&url)
+                    .send()
+                    .await
+                    .context("Error contacting redgif API")?
+                    .error_for_status()
+                    .context("Redgif API refused to create a token")?
+                    .json::<TokenResponse>()
+                    .await
+                    .context("Error parsing redgif API response")
+            }
+            .await;
+            match result {
+                Ok(response) => return Ok(response.token),
+                Err(e) if attempt < REDGIF_TOKEN_ATTEMPTS => {
+                    let backoff = Duration::from_secs(2u64.pow(attempt - 1));
+                    warn!("{:#}, retrying in {}s", e, backoff.as_secs());
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
                 }
+                Err(e) => return Err(e),
             }
         }
+    }
 
-        if needs_token {
-            let url = format!("{}/auth/temporary", REDGIFS_API_PREFIX);
-            let response = self
-                .session
-                .get(url)
-                .send()
-                .await
-                .context("Error contacting redgif API")?
-                .json::<TokenResponse>()
-                .await
-                .context("Error parsing redgif API response")?;
-            self.ephemeral_token = Some(response.token);
+    /// Replace a token the redgif API rejected. Tasks holding the same expired token wait
+    /// for the first one to refresh it instead of each requesting a new one.
+    async fn refresh_redgif_token(&self, rejected: &str) -> Result<String> {
+        let mut token = self.ephemeral_token.lock().await;
+        if let Some(current) = token.as_ref().filter(|current| current.as_str() != rejected) {
+            return Ok(current.clone());
         }
-        Ok(())
+        debug!("Redgif token expired, requesting a new one");
+        let fresh = self.fetch_redgif_token().await?;
+        *token = Some(fresh.clone());
+        Ok(fresh)
+    }
+
+    async fn get_redgif(&self, api_url: &str, token: &str) -> Result<reqwest::Response> {
+        self.host_rate_limiter.acquire(api_url).await;
+        self.session
+            .get(api_url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .context("Error contacting redgif API")
     }
 
     /// Download media from the given url and save to data directory. Also create data directory if not present already
@@ -243,6 +713,7 @@ impl Downloader {
             Err(_e) => return Err(GertError::CouldNotCreateDirectory),
         }
 
+        self.host_rate_limiter.acquire(url).await;
         let maybe_response = self.session.get(url).send().await;
         if let Ok(response) = maybe_response {
             // debug!("URL Response: {:#?}", response);
@@ -257,10 +728,16 @@ impl Downloader {
                 return Err(GertError::ImgurRemovedError);
             }
 
+            // imgur cuts the zips of large albums short, other hosts may send a compressed length
+            let expected = response.content_length().filter(|_| file_name.ends_with(ZIP));
             let maybe_data = response.bytes().await;
 
             if let Ok(data) = maybe_data {
                 debug!("Bytes length of the data: {:#?}", data.len());
+                if let Some(expected) = expected.filter(|expected| *expected != data.len() as u64) {
+                    let received = data.len() as u64;
+                    return Err(GertError::IncompleteDownload(url.to_string(), received, expected));
+                }
                 let maybe_output = File::create(file_name);
                 match maybe_output {
                     Ok(mut output) => {
@@ -268,6 +745,7 @@ impl Downloader {
                         match io::copy(&mut data.as_ref(), &mut output) {
                             Ok(_) => {
                                 info!("Successfully saved media: {} from url {}", file_name, url);
+                                *self.bytes_downloaded.lock().await += data.len() as u64;
                                 status = true;
                             }
                             Err(_e) => {
@@ -286,80 +764,184 @@ impl Downloader {
     }
 
     async fn process(&self, post: &Post) {
-        debug!("type is : {:?}", post.get_type());
-        let result = match post.get_type() {
-            MediaType::Gallery => self.download_gallery(post).await,
-            MediaType::RedditImage => self.download_reddit_image(post).await,
-            MediaType::RedditGif => self.download_reddit_image(post).await,
-            MediaType::RedditVideo => self.download_reddit_video(post).await,
-            MediaType::RedGif => self.download_redgif(post).await,
-            MediaType::GiphyGif => self.download_giphy(post).await,
-            MediaType::ImgurGif => self.download_imgur_gif(post).await,
-            MediaType::ImgurImage => self.download_imgur_image(post).await,
-            MediaType::ImgurAlbum => self.download_imgur_album(post).await,
-            MediaType::ImgurUnknown => self.download_imgur_unknown(post).await,
-            MediaType::StreamableVideo => self.download_streamable_video(post).await,
-            _ => {
+        let media_type = post.get_type();
+        self.emit(Event::Classified {
+            post: &post.data.name,
+            subreddit: &post.data.subreddit,
+            media_type: format!("{:?}", media_type),
+        });
+        if let Some(export) = &self.export {
+            export.export(&post.data);
+        }
+        if post.is_removed() {
+            let reason = if post.is_deleted() { "deleted by its author" } else { "removed" };
+            let msg = format!("Post {} has been {}. Skipping...", post.data.name, reason);
+            self.skip(&msg, media_type, &post.data.subreddit).await;
+            return;
+        }
+        debug!("type is : {:?}", media_type);
+        let host = match self.hosts.iter().find(|host| host.matches(post, media_type)) {
+            Some(host) => host,
+            None => {
                 debug!("Unsupported URL: {:?}", post.get_url());
                 *self.unsupported.lock().await += 1;
-                Ok(())
+                let url = post.link_url().unwrap_or(&post.data.permalink);
+                self.unsupported_urls.lock().await.push(url.to_owned());
+                return;
             }
         };
-        if let Err(e) = result {
-            self.fail(e).await;
+        if let Err(e) = host.download(self, post, media_type).await {
+            self.fail(e, media_type, &post.data.name, &post.data.subreddit).await;
         }
     }
 
-    async fn download_gallery(&self, post: &Post) -> Result<()> {
-        let gallery = post.data.gallery_data.as_ref().unwrap();
-        let media_metadata = post.data.media_metadata.as_ref().unwrap();
-
-        // collect all the URLs for the images in the album
-        for (index, item) in gallery.items.iter().enumerate() {
-            let mut ext = JPG;
-            if let Some(media) = media_metadata.get(&item.media_id) {
-                ext = media.m.split('/').last().unwrap();
+    /// Download the media whose URLs can be worked out from the post alone
+    async fn download_resolved(&self, post: &Post, media_type: MediaType) -> Result<()> {
+        if self.options.prefer_reddit_mp4 && media_type == MediaType::RedditGif {
+            // gifs without the variant are downloaded, and converted if ffmpeg is available
+            if let Some(url) = post.get_mp4_variant_url() {
+                let task = DownloadTask::from_post(post, url, MP4, None);
+                self.schedule_task(task).await;
+                return Ok(());
+            }
+        }
+        let is_reddit_image = matches!(media_type, MediaType::RedditImage | MediaType::RedditGif);
+        if self.options.prefer_gif_variant && is_reddit_image {
+            // reddit keeps an animated version of some images in the post preview
+            if let Some(url) = post.get_gif_variant_url() {
+                let task = DownloadTask::from_post(post, url, GIF, None);
+                self.schedule_task(task).await;
+                return Ok(());
+            }
+        }
+        if media_type == MediaType::RedditImage {
+            let max_width = match self.options.image_resolution {
+                ImageResolution::Source => None,
+                ImageResolution::Max => Some(None),
+                ImageResolution::Width(width) => Some(Some(width)),
+            };
+            // posts without preview data are downloaded from the source
+            if let Some(url) = max_width.and_then(|max| post.get_preview_resolution_url(max)) {
+                let path = Url::parse(&url)?.path().to_lowercase();
+                let extension = match path.rsplit_once('.') {
+                    Some((_, extension)) if [JPG, JPEG, PNG].contains(&extension) => extension,
+                    _ => JPG,
+                };
+                let task = DownloadTask::from_post(post, url.as_str(), extension, None);
+                self.schedule_task(task).await;
+                return Ok(());
             }
-            let url = format!("https://{}/{}.{}", REDDIT_IMAGE_SUBDOMAIN, item.media_id, ext);
-            let task = DownloadTask::from_post(post, url, ext, Some(index));
-            self.schedule_task(task).await;
         }
-        Ok(())
-    }
 
-    async fn download_reddit_image(&self, post: &Post) -> Result<()> {
-        let url = post.get_url().unwrap();
-        let extension = url.split('.').last().unwrap();
-        let task = DownloadTask::from_post(post, &url, extension, None);
-        self.schedule_task(task).await;
+        let media = post.get_all_urls();
+        if media.is_empty() {
+            bail!("No media found in post {}", post.data.name);
+        }
+        let flatten = self.options.flatten_single_galleries && post.is_single_item_gallery();
+        let title = post.data.title.clone().unwrap_or_default();
+        let gallery_name = format!("{}_{}", post.data.id, canonical_title(&title, &self.options));
+        let directory = Path::new(&self.options.data_directory)
+            .join(self.post_directory(&post.data.subreddit, post.data.created_utc_secs()));
+        let writes_index = media_type == MediaType::Gallery
+            && self.options.gallery_index
+            && self.options.should_download
+            && !self.options.resolve_only;
+        let mut index_items = Vec::new();
+        for mut item in media {
+            if flatten {
+                item.index = None;
+            }
+            let mut index_item = GalleryIndexItem {
+                index: item.index.unwrap_or(0) + 1,
+                media_id: item.media_id.clone(),
+                caption: item.caption.clone(),
+                outbound_url: item.outbound_url.clone(),
+                url: item.url.clone(),
+                file: None,
+            };
+            if let Some(media_id) = &item.media_id {
+                if self.is_duplicate_media(media_id).await {
+                    let msg = format!("Skipping {}, already downloaded", media_id);
+                    self.skip(&msg, media_type, &post.data.subreddit).await;
+                    index_items.push(index_item);
+                    continue;
+                }
+            }
+            let mut task = DownloadTask::from_post(post, item.url, item.extension, item.index);
+            task.metadata.caption = item.caption;
+            task.metadata.outbound_url = item.outbound_url;
+            if media_type == MediaType::Gallery && self.options.gallery_subfolders && !flatten {
+                task.gallery_folder = Some(gallery_name.clone());
+            }
+            if !writes_index {
+                self.schedule_task(task).await;
+                continue;
+            }
+            // files that already exist are skipped, they still belong in the index
+            let expected = self.get_filename(&task);
+            let file = match self.schedule_task(task).await {
+                Some(file) => Some(file),
+                None => Some(expected).filter(|file| check_path_present(file)),
+            };
+            index_item.file = file.map(|file| {
+                let path = Path::new(&file);
+                path.strip_prefix(&directory).unwrap_or(path).display().to_string()
+            });
+            index_items.push(index_item);
+        }
+        if writes_index {
+            let index = GalleryIndex {
+                post: post.data.name.clone(),
+                title,
+                permalink: format!("https://www.reddit.com{}", post.data.permalink),
+                items: index_items,
+            };
+            let path = directory.join(format!("{}.json", gallery_name));
+            let json = serde_json::to_string_pretty(&index)?;
+            if let Err(e) = fs::write(&path, json + "\n") {
+                warn!("Could not write the gallery index {}: {}", path.display(), e);
+            }
+        }
         Ok(())
     }
 
     async fn download_redgif(&self, post: &Post) -> Result<()> {
         let url = post.get_url().unwrap();
         let id = url.split('/').last().unwrap();
-        let api_url = format!("{}/gifs/{}", REDGIFS_API_PREFIX, id);
-        let token = self.ephemeral_token.as_ref().context("No Redgif token found")?;
-        let response = self
-            .session
-            .get(&api_url)
-            .header("Authorization", format! {"Bearer {}", token})
-            .send()
-            .await
-            .context("Error contacting redgif API")?
+        let api_url = format!("{}/gifs/{}", self.options.endpoints.redgifs_api, id);
+        // the token requested at startup may have failed, each post tries again
+        let token = self.redgif_token().await?;
+        let mut response = self.get_redgif(&api_url, &token).await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            // the token is only valid for a while, long runs can outlive it
+            let token = self.refresh_redgif_token(&token).await?;
+            response = self.get_redgif(&api_url, &token).await?;
+        }
+        let response = response
             .json::<RedGif>()
             .await
             .context(format!("Error parsing Redgif API response from {}", api_url))?;
 
-        let task = DownloadTask::from_post(post, response.gif.urls.hd, MP4, None);
+        let urls = response.gif.urls;
+        let url = match urls.original {
+            Some(original) if self.options.prefer_original => original,
+            _ => urls.hd,
+        };
+        let task = DownloadTask::from_post(post, url, MP4, None);
         self.schedule_task(task).await;
         Ok(())
     }
 
     async fn download_reddit_video(&self, post: &Post) -> Result<()> {
-        let post_url = post.data.url.as_ref().unwrap();
+        let post_url = post.link_url().unwrap();
         let extension = post_url.split('.').last().unwrap();
-        let dash_url = &post.data.media.as_ref().unwrap().reddit_video.as_ref().unwrap().dash_url;
+        let reddit_video = post.reddit_video().context("No reddit video found")?;
+        let dash_url = &reddit_video.dash_url;
+        if let Some(duration) = reddit_video.duration {
+            if self.skip_duration(post, duration as f64, MediaType::RedditVideo).await {
+                return Ok(());
+            }
+        }
 
         let url = match extension {
             MP4 => {
@@ -371,29 +953,44 @@ impl Downloader {
                 // if the URL uses the reddit video subdomain, but the link does not
                 // point directly to the mp4, then use the fallback URL to get the
                 // appropriate link. The video quality might range from 96p to 720p
-                post.data
-                    .media
-                    .as_ref()
-                    .context("No media data found")?
-                    .reddit_video
-                    .as_ref()
-                    .context("No fallback url found in reddit video")?
-                    .fallback_url
-                    .replace("?source=fallback", "")
-                    .clone()
+                reddit_video.fallback_url.replace("?source=fallback", "")
             }
         };
 
         let dash_video =
             url.split('/').last().context(format!("Unsupported reddit video URL: {}", url))?;
 
-        let (maybe_video, maybe_audio) = parse_mpd(&dash_url).await;
+        self.host_rate_limiter.acquire(dash_url).await;
+        let (maybe_video, maybe_audio) = parse_mpd(&self.session, dash_url).await;
 
         let mut video_url = url.clone();
         let base_path =
             &url.split('/').collect::<Vec<&str>>()[..url.split('/').count() - 1].join("/");
 
-        if !dash_video.contains("DASH") {
+        if self.options.audio_only {
+            // reddit serves the audio as a separate stream, gifs uploaded as videos have none
+            match maybe_audio {
+                Some(audio) if !reddit_video.is_gif => {
+                    let audio_url = format!("{}/{}", base_path, audio);
+                    let audio_task = DownloadTask::from_post(post, audio_url, M4A, None);
+                    self.schedule_task(audio_task).await;
+                }
+                _ => {
+                    let msg = format!("Video {} has no audio. Skipping...", post_url);
+                    self.skip(&msg, MediaType::RedditVideo, &post.data.subreddit).await;
+                }
+            }
+            return Ok(());
+        }
+
+        if maybe_video.is_none() && self.options.video_quality.is_some() {
+            // the MPD file is unavailable, look for the streams reddit usually encodes to
+            let video_base = dash_url.rsplit_once('/').map(|(base, _)| base).unwrap_or(dash_url);
+            video_url = self
+                .probe_reddit_video(video_base, self.options.video_quality.unwrap())
+                .await
+                .context(format!("Could not find any video stream for {}", post_url))?;
+        } else if !dash_video.contains("DASH") {
             // get the video URL from the MPD file
             if maybe_video.is_none() {
                 bail!("Could not find video in MPD");
@@ -402,84 +999,185 @@ impl Downloader {
             }
         }
 
-        let video_task = DownloadTask::from_post(post, video_url, MP4, None);
+        if self.options.no_video_merge {
+            let mut video_task = DownloadTask::from_post(post, video_url, MP4, None);
+            video_task.suffix = Some("_video");
+            self.schedule_task(video_task).await;
+            if let Some(audio) = maybe_audio.filter(|_| !reddit_video.is_gif) {
+                let audio_url = format!("{}/{}", base_path, audio);
+                let mut audio_task = DownloadTask::from_post(post, audio_url, M4A, None);
+                audio_task.suffix = Some("_audio");
+                self.schedule_task(audio_task).await;
+            }
+            return Ok(());
+        }
+
+        let mut video_task = DownloadTask::from_post(post, video_url, MP4, None);
+        // videos with audio are transcoded once the streams are merged
+        video_task.transcode = maybe_audio.is_none();
         let video_filename = self.schedule_task(video_task).await;
 
         if maybe_audio.is_some() {
             let audio_url = format!("{}/{}", base_path, maybe_audio.unwrap());
-            let audio_task = DownloadTask::from_post(post, audio_url, MP4, Some(1));
+            let mut audio_task = DownloadTask::from_post(post, audio_url, MP4, Some(1));
+            audio_task.transcode = false;
             let audio_filename = self.schedule_task(audio_task).await;
 
-            if let (Some(video_filename), Some(audio_filename)) = (video_filename, audio_filename) {
+            match (video_filename, audio_filename) {
                 // merge the audio and video files
-                if self.stitch_audio_video(&video_filename, &audio_filename).await.is_err() {
-                    debug!("Error merging audio and video files");
+                (Some(video), Some(audio)) => match self.stitch_audio_video(&video, &audio).await {
+                    Ok(merged) => {
+                        self.transcode(merged).await;
+                    }
+                    Err(_) => debug!("Error merging audio and video files"),
+                },
+                (Some(video), None) => {
+                    self.transcode(video).await;
                 }
+                _ => (),
             }
         }
 
         Ok(())
     }
 
-    async fn download_giphy(&self, post: &Post) -> Result<()> {
-        let url = post.data.url.as_ref().unwrap();
-        let parsed = Url::parse(url).unwrap();
-        let extension = url.split('.').last().unwrap();
+    /// Skip the video of the post if it is shorter than --min-duration or longer than
+    /// --max-duration, returns whether it was skipped
+    async fn skip_duration(&self, post: &Post, seconds: f64, media_type: MediaType) -> bool {
+        let too_short = self.options.min_duration.is_some_and(|min| seconds < min as f64);
+        let too_long = self.options.max_duration.is_some_and(|max| seconds > max as f64);
+        if too_short || too_long {
+            let msg = format!(
+                "Video of post {} is {:.0} seconds long, out of the duration range. Skipping...",
+                post.data.name, seconds
+            );
+            self.skip(&msg, media_type, &post.data.subreddit).await;
+        }
+        too_short || too_long
+    }
 
-        if contains_any(url, &GIPHY_MEDIA_SUBDOMAINS) {
-            // if we encounter gif, mp4 or gifv - download as is
-            match extension {
-                GIF | MP4 | GIFV => {
-                    let task = DownloadTask::from_post(post, url, extension, None);
-                    self.schedule_task(task).await;
-                }
-                _ => {
-                    // if the link points to the giphy post rather than the media link,
-                    // use the scheme below to get the actual URL for the gif.
-                    let path = &parsed[Position::AfterHost..Position::AfterPath];
-                    let media_id = path.split('-').last().unwrap();
-                    let giphy_url =
-                        format!("https://{}/media/{}.gif", GIPHY_MEDIA_SUBDOMAIN, media_id);
-                    let task = DownloadTask::from_post(post, giphy_url, GIF, None);
-                    self.schedule_task(task).await;
-                }
+    /// Find the highest quality DASH stream of a reddit video that is not above `max_height`
+    async fn probe_reddit_video(&self, base_path: &str, max_height: u32) -> Option<String> {
+        let heights = REDDIT_VIDEO_RESOLUTIONS.iter().filter_map(|h| h.parse::<u32>().ok());
+        for height in heights.filter(|height| *height <= max_height) {
+            let url = format!("{}/DASH_{}.mp4", base_path, height);
+            self.host_rate_limiter.acquire(&url).await;
+            match self.session.head(&url).send().await {
+                Ok(response) if response.status().is_success() => return Some(url),
+                _ => debug!("No reddit video stream found at {}", url),
             }
         }
-        Ok(())
+        None
     }
 
-    async fn download_imgur_gif(&self, post: &Post) -> Result<()> {
-        let url = post.data.url.as_ref().unwrap();
+    /// Download the reddit hosted copy of a video on an unsupported host. Reddit only keeps
+    /// a short, silent preview for some of them
+    async fn download_video_preview(&self, post: &Post) -> Result<()> {
+        let url = post.get_video_preview_url().context("No reddit video preview found")?;
+        let mut task = DownloadTask::from_post(post, url, MP4, None);
+        task.media_type = MediaType::RedditVideoPreview;
+        self.schedule_task(task).await;
+        Ok(())
+    }
 
-        // if the extension is gifv, then replace gifv->mp4 to get the video URL
-        let task = DownloadTask::from_post(post, url.replace(".gifv", ".mp4"), MP4, None);
+    async fn download_thumbnail(&self, post: &Post) -> Result<()> {
+        let url = post.get_thumbnail_url().context("No thumbnail found")?;
+        let path = Url::parse(&url)?.path().to_lowercase();
+        let extension = match path.rsplit_once('.') {
+            Some((_, extension)) if [JPG, JPEG, PNG, GIF].contains(&extension) => extension,
+            _ => JPG,
+        };
+        let mut task = DownloadTask::from_post(post, url.as_str(), extension, None);
+        task.media_type = MediaType::Thumbnail;
         self.schedule_task(task).await;
         Ok(())
     }
 
-    async fn download_imgur_image(&self, post: &Post) -> Result<()> {
-        let url = post.data.url.as_ref().unwrap();
+    async fn download_giphy(&self, post: &Post) -> Result<()> {
+        let url = post.link_url().unwrap();
+        let parsed = Url::parse(url).unwrap();
         let extension = url.split('.').last().unwrap();
+        let path = &parsed[Position::AfterHost..Position::AfterPath];
+
+        // if we encounter gif, mp4 or gifv on a media subdomain - download as is
+        if contains_any(url, &GIPHY_MEDIA_SUBDOMAINS) && [GIF, MP4, GIFV].contains(&extension) {
+            let task = DownloadTask::from_post(post, url, extension, None);
+            self.schedule_task(task).await;
+            return Ok(());
+        }
+
+        if let Some(api_key) = &self.options.giphy_api_key {
+            let media_id = giphy_media_id(path).context("Could not find the giphy media id")?;
+            let (giphy_url, extension) = self.resolve_giphy(&media_id, api_key).await?;
+            let task = DownloadTask::from_post(post, giphy_url, extension, None);
+            self.schedule_task(task).await;
+            return Ok(());
+        }
+
+        if path.starts_with("/clips/") {
+            bail!("Giphy clips need an API key, set GIPHY_API_KEY or pass --giphy-api-key");
+        }
 
-        let task = DownloadTask::from_post(post, url, extension, None);
+        // if the link points to the giphy post rather than the media link,
+        // use the scheme below to get the actual URL for the gif.
+        let media_id = path.split('-').last().unwrap();
+        let giphy_url = format!("https://{}/media/{}.gif", GIPHY_MEDIA_SUBDOMAIN, media_id);
+        let task = DownloadTask::from_post(post, giphy_url, GIF, None);
         self.schedule_task(task).await;
         Ok(())
     }
 
+    /// Look up the media URL of a giphy gif or clip using the giphy API
+    async fn resolve_giphy(&self, media_id: &str, api_key: &str) -> Result<(String, &'static str)> {
+        let api_url = format!("{}/{}", self.options.endpoints.giphy_api, media_id);
+        self.host_rate_limiter.acquire(&api_url).await;
+        let response = self
+            .session
+            .get(&api_url)
+            .query(&[("api_key", api_key)])
+            .send()
+            .await
+            .context("Error contacting giphy API")?
+            .json::<GiphyApiResponse>()
+            .await
+            .context(format!("Error parsing giphy API response from {}", api_url))?;
+
+        // clips are videos with sound, their source is only listed in the video assets
+        let clip = response.data.video.and_then(|video| video.assets.get("source")?.url.clone());
+        if let Some(clip_url) = clip {
+            return Ok((clip_url, MP4));
+        }
+        let original = response.data.images.original;
+        if let Some(mp4_url) = original.mp4 {
+            return Ok((mp4_url, MP4));
+        }
+        match original.url {
+            Some(gif_url) => Ok((gif_url, GIF)),
+            None => bail!("No media found in giphy API response for {}", media_id),
+        }
+    }
+
     async fn download_imgur_unknown(&self, post: &Post) -> Result<()> {
-        let url = post.data.url.as_ref().unwrap();
+        let url = post.link_url().unwrap();
+        let base_url = normalize_imgur_url(url).unwrap_or_else(|| url.to_owned());
+        let imgur = format!("https://{}", IMGUR_DOMAIN);
+        let base_url = base_url.replacen(&imgur, &self.options.endpoints.imgur, 1);
 
         // try adding the .jpg extension to the URL
-        let url = format!("{}.jpg", url);
-        let success = check_url_has_mime_type(&url, mime::JPEG).await.unwrap_or(false);
+        let url = format!("{}.jpg", base_url);
+        self.host_rate_limiter.acquire(&url).await;
+        let success =
+            check_url_has_mime_type(&self.session, &url, mime::JPEG).await.unwrap_or(false);
         if success {
             let task = DownloadTask::from_post(post, url, JPG, None);
             self.schedule_task(task).await;
             return Ok(());
         }
 
-        let url = format!("{}.png", url);
-        let success = check_url_has_mime_type(&url, mime::PNG).await.unwrap_or(false);
+        let url = format!("{}.png", base_url);
+        self.host_rate_limiter.acquire(&url).await;
+        let success =
+            check_url_has_mime_type(&self.session, &url, mime::PNG).await.unwrap_or(false);
         if success {
             let task = DownloadTask::from_post(post, url, PNG, None);
             self.schedule_task(task).await;
@@ -489,22 +1187,12 @@ impl Downloader {
         bail!("Cannot determine imgur image type");
     }
 
-    async fn download_imgur_album(&self, post: &Post) -> Result<()> {
-        let url = post.data.url.as_ref().unwrap();
-        let mut tokens = url.split('/').collect::<Vec<&str>>();
-        tokens.push("zip");
-        let url = tokens.join("/");
-
-        let task = DownloadTask::from_post(post, url, ZIP, None);
-        self.schedule_task(task).await;
-        Ok(())
-    }
-
     async fn download_streamable_video(&self, post: &Post) -> Result<()> {
         let url = post.get_url().unwrap();
         let parsed = Url::parse(&url).unwrap();
         let video_id = &parsed[Position::AfterHost..Position::AfterPath];
-        let streamable_url = format!("{}{}", STREAMABLE_API, video_id);
+        let streamable_url = format!("{}{}", self.options.endpoints.streamable_api, video_id);
+        self.host_rate_limiter.acquire(&streamable_url).await;
         let response = self
             .session
             .get(&streamable_url)
@@ -521,7 +1209,17 @@ impl Downloader {
             bail!("No mp4 file found in streamable API response")
         }
 
-        let video_url = parsed.files.get(MP4).unwrap().url.borrow().to_owned().unwrap();
+        let original = parsed.files.get("original").filter(|file| file.url.is_some());
+        let file = match original {
+            Some(original) if self.options.prefer_original => original,
+            _ => parsed.files.get(MP4).unwrap(),
+        };
+        if let Some(duration) = file.duration {
+            if self.skip_duration(post, duration, MediaType::StreamableVideo).await {
+                return Ok(());
+            }
+        }
+        let video_url = file.url.borrow().to_owned().unwrap();
 
         let task = DownloadTask::from_post(post, video_url, MP4, None);
         self.schedule_task(task).await;
@@ -529,14 +1227,147 @@ impl Downloader {
         Ok(())
     }
 
-    async fn fail(&self, e: anyhow::Error) {
+    /// Download the images and videos of a tweet, eg: https://twitter.com/<user>/status/<id>,
+    /// https://x.com/<user>/status/<id> or https://mobile.twitter.com/<user>/status/<id>/photo/1
+    async fn download_twitter(&self, post: &Post) -> Result<()> {
+        let url = post.get_url().unwrap();
+        let parsed = Url::parse(&url)?;
+        let tweet_id = parsed
+            .path_segments()
+            .and_then(|mut segments| segments.find(|s| *s == "status").and(segments.next()))
+            .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+            .context(format!("Could not find the tweet id in {}", url))?
+            .to_owned();
+
+        let media = match &self.options.nitter_instance {
+            Some(instance) => self.resolve_tweet_from_nitter(instance, &tweet_id).await?,
+            None => self.resolve_tweet_from_syndication(&tweet_id).await?,
+        };
+        if media.is_empty() {
+            bail!("No media found in tweet {}", url);
+        }
+
+        let single = media.len() == 1;
+        for (index, (media_url, extension)) in media.into_iter().enumerate() {
+            let index = if single { None } else { Some(index) };
+            let task = DownloadTask::from_post(post, media_url, extension, index);
+            self.schedule_task(task).await;
+        }
+        Ok(())
+    }
+
+    /// Find the media of a tweet with the API used by the twitter embed widget
+    async fn resolve_tweet_from_syndication(
+        &self,
+        tweet_id: &str,
+    ) -> Result<Vec<(String, &'static str)>> {
+        let token = twitter_syndication_token(tweet_id);
+        self.host_rate_limiter.acquire(&self.options.endpoints.twitter_syndication_api).await;
+        let response = self
+            .session
+            .get(&self.options.endpoints.twitter_syndication_api)
+            .query(&[("id", tweet_id), ("token", token.as_str())])
+            .send()
+            .await
+            .context("Error contacting twitter syndication API")?
+            .json::<TwitterSyndicationResponse>()
+            .await
+            .context(format!("Error parsing twitter response for tweet {}", tweet_id))?;
+
+        let mut media = Vec::new();
+        for item in response.media_details.unwrap_or_default() {
+            if item.kind == "photo" {
+                let extension = if item.media_url_https.ends_with(PNG) { PNG } else { JPG };
+                media.push((format!("{}?name=orig", item.media_url_https), extension));
+                continue;
+            }
+            // videos and gifs come in several mp4 variants, keep the one with the highest bitrate
+            let best = item
+                .video_info
+                .map(|info| info.variants)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|variant| variant.content_type == "video/mp4")
+                .max_by_key(|variant| variant.bitrate.unwrap_or(0));
+            if let Some(variant) = best {
+                media.push((variant.url, MP4));
+            }
+        }
+        Ok(media)
+    }
+
+    /// Find the media of a tweet by scraping its page on a nitter instance
+    async fn resolve_tweet_from_nitter(
+        &self,
+        instance: &str,
+        tweet_id: &str,
+    ) -> Result<Vec<(String, &'static str)>> {
+        let instance = instance.trim_end_matches('/');
+        let page_url = format!("{}/i/status/{}", instance, tweet_id);
+        self.host_rate_limiter.acquire(&page_url).await;
+        let page = self
+            .session
+            .get(&page_url)
+            .send()
+            .await
+            .context(format!("Error contacting nitter instance {}", instance))?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let images = regex::Regex::new(r#"href="(/pic/orig/[^"]+)""#).unwrap();
+        let videos = regex::Regex::new(r#"<source src="(/video/[^"]+)""#).unwrap();
+        let mut media = Vec::new();
+        for capture in images.captures_iter(&page) {
+            let path = &capture[1];
+            let extension = if path.ends_with(PNG) { PNG } else { JPG };
+            media.push((format!("{}{}", instance, path), extension));
+        }
+        for capture in videos.captures_iter(&page) {
+            media.push((format!("{}{}", instance, &capture[1]), MP4));
+        }
+        Ok(media)
+    }
+
+    async fn fail(
+        &self,
+        e: anyhow::Error,
+        media_type: MediaType,
+        post_name: &str,
+        subreddit: &str,
+    ) {
         error!("{}", e);
+        self.emit(Event::Failed { post: post_name, subreddit, reason: e.to_string() });
         *self.failed.lock().await += 1;
+        self.failed_posts.lock().await.insert(post_name.to_owned());
+        self.host_stats.lock().await.entry(media_type).or_default().1 += 1;
+        self.subreddit_stats.lock().await.entry(subreddit.to_owned()).or_default().1 += 1;
+    }
+
+    /// Index the media of the task, saved at `path`, with --output-manifest-sqlite
+    fn record_in_manifest(&self, path: &str, task: &DownloadTask) {
+        if let Some(manifest) = &self.manifest {
+            if let Err(e) = manifest.record(&task.manifest_entry(path)) {
+                warn!("Could not add {} to the manifest: {}", path, e);
+            }
+        }
+    }
+
+    /// Record the media id and check whether it was already seen during this run
+    async fn is_duplicate_media(&self, media_id: &str) -> bool {
+        if !self.options.dedupe_across_galleries {
+            return false;
+        }
+        !self.seen_media_ids.lock().await.insert(media_id.to_owned())
     }
 
-    async fn skip(&self, msg: &str) {
+    async fn skip(&self, msg: &str, media_type: MediaType, subreddit: &str) {
         debug!("{}", msg);
+        let media_type_name = format!("{:?}", media_type);
+        self.emit(Event::Skipped { subreddit, media_type: media_type_name, reason: msg });
         *self.skipped.lock().await += 1;
+        self.host_stats.lock().await.entry(media_type).or_default().2 += 1;
+        self.subreddit_stats.lock().await.entry(subreddit.to_owned()).or_default().2 += 1;
     }
 
     async fn schedule_task(&self, task: DownloadTask) -> Option<String> {
@@ -545,31 +1376,117 @@ impl Downloader {
             *self.supported.lock().await += 1;
         }
 
-        if !self.should_download {
+        if !self.extension_allowed(&task.extension) {
+            let msg = format!("Media from url {} has a filtered extension. Skipping...", task.url);
+            self.skip(&msg, task.media_type, &task.subreddit).await;
+            return None;
+        }
+
+        if self.options.resolve_only {
+            match self.resolve_final_url(&task.url).await {
+                Ok(url) if url.contains("i.imgur.com/removed") => {
+                    let msg = format!("Media from url {} has been removed from imgur", task.url);
+                    self.skip(&msg, task.media_type, &task.subreddit).await;
+                }
+                Ok(url) => {
+                    println!("{}", url);
+                    let msg = format!("Resolved media at: {}", url);
+                    self.skip(&msg, task.media_type, &task.subreddit).await;
+                }
+                Err(e) => {
+                    let e = anyhow!("Could not resolve media from url {}: {}", task.url, e);
+                    self.fail(e, task.media_type, &task.post_name, &task.subreddit).await;
+                }
+            }
+            return None;
+        }
+
+        if !self.options.should_download {
+            if self.options.estimate_size {
+                let size = self.content_length(&task.url).await;
+                debug!("Size of the media at {}: {:?}", task.url, size);
+                let mut estimated = self.estimated.lock().await;
+                match size {
+                    Some(size) => estimated.0 += size,
+                    None => estimated.1 += 1,
+                }
+            }
             let msg = format!("Found media at: {}", task.url);
-            self.skip(&msg).await;
+            self.skip(&msg, task.media_type, &task.subreddit).await;
             return None;
         }
-        let file_name = self.get_filename(&task);
+        let mut file_name = self.get_filename(&task);
+        if self.options.on_exists == OnExists::Rename {
+            file_name = avoid_collision(file_name, &task.url);
+        }
 
-        if check_path_present(&file_name)
-            || check_path_present(&file_name.replace(".gif", ".mp4"))
-            || check_path_present(&file_name.replace(".zip", ".jpg"))
-        {
+        if self.keep_existing(&file_name, &task.url).await {
             let msg = format!("Media from url {} already downloaded. Skipping...", task.url);
-            self.skip(&msg).await;
+            self.skip(&msg, task.media_type, &task.subreddit).await;
+            self.record_in_manifest(&file_name, &task);
+            // the images of earlier runs are compared with the ones this run saves
+            self.near_duplicate_of(&file_name).await;
+            return None;
+        }
+
+        let dedupe = self.perceptual_dedupe.as_ref();
+        if dedupe.is_some_and(|dedupe| dedupe.was_removed(&url_hash(&task.url))) {
+            let msg =
+                format!("Media from url {} was deleted as a near duplicate. Skipping...", task.url);
+            self.skip(&msg, task.media_type, &task.subreddit).await;
             return None;
         }
 
+        let (post, url) = (task.post_name.as_str(), task.url.as_str());
+        self.emit(Event::Started { post, url, file: &file_name });
         let result = self.download_media(&file_name, &task.url).await;
         match result {
             Ok(true) => {
+                let bytes = fs::metadata(&file_name).map(|metadata| metadata.len()).unwrap_or(0);
+                self.emit(Event::Completed { post, url, file: &file_name, bytes });
                 {
                     *self.downloaded.lock().await += 1;
+                    self.host_stats.lock().await.entry(task.media_type).or_default().0 += 1;
+                    let mut subreddit_stats = self.subreddit_stats.lock().await;
+                    subreddit_stats.entry(task.subreddit.clone()).or_default().0 += 1;
+                }
+                if self.options.on_exists == OnExists::Rename {
+                    if let Err(e) = fs::write(source_sidecar(&file_name), url_hash(&task.url)) {
+                        warn!("Could not record the source of {}: {}", file_name, e);
+                    }
                 }
 
                 match self.post_process(file_name, &task).await {
-                    Ok(filepath) => Some(filepath),
+                    Ok(filepath) => {
+                        let filepath = match task.transcode {
+                            true => self.transcode(filepath).await,
+                            false => filepath,
+                        };
+                        if let Some(original) = self.near_duplicate_of(&filepath).await {
+                            if let Err(e) = fs::remove_file(&filepath) {
+                                warn!("Could not delete the near duplicate {}: {}", filepath, e);
+                                return Some(filepath);
+                            }
+                            info!("Deleted {}, a near duplicate of {}", filepath, original);
+                            if let Some(dedupe) = &self.perceptual_dedupe {
+                                dedupe.record_removed(&url_hash(&task.url), &original);
+                            }
+                            *self.near_duplicates.lock().await += 1;
+                            return None;
+                        }
+                        if self.options.embed_metadata {
+                            if let Err(e) = self.embed_metadata(&filepath, &task).await {
+                                warn!("Could not embed metadata into {}: {}", filepath, e);
+                            }
+                        }
+                        if self.options.write_urls {
+                            if let Err(e) = self.write_url(&filepath, &task).await {
+                                warn!("Could not record the permalink of {}: {}", filepath, e);
+                            }
+                        }
+                        self.record_in_manifest(&filepath, &task);
+                        Some(filepath)
+                    }
                     Err(e) => {
                         error!("Error while post processing: {}", e);
                         None
@@ -577,20 +1494,24 @@ impl Downloader {
                 }
             }
             Ok(false) => {
-                self.fail(anyhow!("Failed to download media from url: {}", task.url)).await;
+                self.fail(
+                    anyhow!("Failed to download media from url: {}", task.url),
+                    task.media_type,
+                    &task.post_name,
+                    &task.subreddit,
+                )
+                .await;
                 None
             }
             Err(GertError::ImgurRemovedError) => {
-                self.skip(&format!(
-                    "Media from url {} has been removed from imgur. Skipping...",
-                    task.url
-                ))
-                .await;
+                let msg =
+                    format!("Media from url {} has been removed from imgur. Skipping...", task.url);
+                self.skip(&msg, task.media_type, &task.subreddit).await;
                 None
             }
             Err(e) => {
-                self.fail(anyhow!("Error while downloading media from url {}: {}", task.url, e))
-                    .await;
+                let e = anyhow!("Error while downloading media from url {}: {}", task.url, e);
+                self.fail(e, task.media_type, &task.post_name, &task.subreddit).await;
                 None
             }
         }
@@ -598,18 +1519,54 @@ impl Downloader {
 
     async fn post_process(
         &self,
-        download_path: String,
+        mut download_path: String,
         task: &DownloadTask,
     ) -> Result<String, GertError> {
-        if !self.ffmpeg_available {
+        let mut extension = task.extension.as_str();
+        if self.options.fix_extensions {
+            if let Some(detected) = detected_extension(&download_path) {
+                let mut fixed = replace_extension(&download_path, detected);
+                if self.options.on_exists == OnExists::Rename {
+                    fixed = avoid_collision(fixed, &task.url);
+                }
+                let keep_existing =
+                    matches!(self.options.on_exists, OnExists::Skip | OnExists::Newer);
+                if keep_existing && check_path_present(&fixed) {
+                    warn!(
+                        "{} holds a {} file, but {} already exists. Keeping its name",
+                        download_path, detected, fixed
+                    );
+                } else {
+                    warn!("{} holds a {} file, renaming it to {}", download_path, detected, fixed);
+                    fs::rename(&download_path, &fixed)?;
+                    if self.options.on_exists == OnExists::Rename {
+                        // the source of the media follows it for the next collision check
+                        let _ = fs::rename(source_sidecar(&download_path), source_sidecar(&fixed));
+                    }
+                    download_path = fixed;
+                    extension = detected;
+                }
+            }
+        }
+
+        if !self.options.ffmpeg_available {
             return Ok(download_path);
         };
 
-        if task.extension == GIF && !self.conserve_gifs {
+        let is_hosted_video =
+            matches!(task.media_type, MediaType::StreamableVideo | MediaType::RedGif);
+        if self.options.audio_only && is_hosted_video && extension == MP4 {
+            return self.extract_audio(&download_path).await;
+        }
+
+        if extension == GIF && !self.options.conserve_gifs {
             //If ffmpeg is installed convert gifs to mp4
-            let output_file = download_path.replace(".gif", ".mp4");
+            let output_file = replace_extension(&download_path, MP4);
             if check_path_present(&output_file) {
-                return Ok(output_file);
+                if self.options.on_exists != OnExists::Overwrite {
+                    return Ok(output_file);
+                }
+                fs::remove_file(&output_file)?;
             }
             debug!("Converting gif to mp4: {}", output_file);
             let mut command = tokio::process::Command::new("ffmpeg")
@@ -635,27 +1592,19 @@ impl Downloader {
                 return Err(GertError::FfmpegError("Failed to convert gif to mp4".into()));
             }
         }
-        if task.extension == ZIP {
-            let file = File::open(&download_path)?;
-            let mut archive = zip::ZipArchive::new(file)?;
-
-            for i in 0..archive.len() {
-                // Unzip the contents of the zip file
-
-                let mut file = archive.by_index(i)?;
-                let extension = file.name().split('.').last().unwrap();
-
-                let filename = self.generate_file_name(
-                    &task.url,
-                    &task.subreddit,
-                    extension,
-                    &task.post_name,
-                    &task.post_title,
-                    Some(i),
-                );
-                debug!("Unzipping file: {}", filename);
-                let mut outfile = fs::File::create(filename)?;
-                io::copy(&mut file, &mut outfile)?;
+        if extension == ZIP {
+            if let Err(e) = self.unzip_album(&download_path, task) {
+                // a corrupt zip would lose the whole album, download the images one by one
+                warn!("Could not unzip {}: {}", download_path, e);
+                match self.recover_imgur_album(task).await {
+                    Ok((recovered, total)) => {
+                        info!("Recovered {} of the {} images of {}", recovered, total, task.url)
+                    }
+                    Err(recovery) => {
+                        warn!("Could not recover the images of {}: {}", task.url, recovery);
+                        return Err(e);
+                    }
+                }
             }
             // Cleanup the zip
             fs::remove_file(&download_path)?;
@@ -664,13 +1613,278 @@ impl Downloader {
         Ok(download_path)
     }
 
+    /// The image seen earlier in the run that the image at `path` is a near duplicate of, with
+    /// --phash-dedupe. Otherwise the image is remembered for the ones that follow
+    async fn near_duplicate_of(&self, path: &str) -> Option<String> {
+        let dedupe = self.perceptual_dedupe.as_ref()?;
+        let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+        if !HASHED_EXTENSIONS.contains(&extension.as_str()) {
+            return None;
+        }
+        // decoding large images would hold up the other downloads
+        let file = path.to_owned();
+        let hash = tokio::task::spawn_blocking(move || hash_file(&file)).await.ok()??;
+        dedupe.find_or_insert(hash, path)
+    }
+
+    /// Re-encode a video or gif with --convert-to, returns the path of the file to keep.
+    /// Other files are returned as they are, and so is the file when ffmpeg fails
+    async fn transcode(&self, path: String) -> String {
+        let codec = match self.options.convert_to {
+            Some(codec) => codec,
+            None => return path,
+        };
+        let extension = Path::new(&path).extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        if ![MP4, MKV, GIF].contains(&extension.to_lowercase().as_str()) {
+            return path;
+        }
+        let target = replace_extension(&path, codec.extension());
+        // an mp4 transcoded to an mp4 can not be written over the file it is read from
+        let output = match target == path {
+            true => add_suffix(&target, &format!("_{}", codec.name())),
+            false => target.clone(),
+        };
+        let crf = self.options.crf.unwrap_or_else(|| codec.default_crf()).to_string();
+        let mut command = tokio::process::Command::new("ffmpeg");
+        command.arg("-y").arg("-i").arg(&path);
+        match codec {
+            VideoCodec::Webm => {
+                command.args(["-c:v", "libvpx-vp9", "-b:v", "0", "-c:a", "libopus"])
+            }
+            VideoCodec::Av1 => command.args(["-c:v", "libsvtav1", "-c:a", "copy"]),
+            VideoCodec::H265 => command.args(["-c:v", "libx265", "-tag:v", "hvc1", "-c:a", "copy"]),
+        };
+        command.arg("-crf").arg(crf);
+        if let Some(preset) = self.options.preset.as_ref().filter(|_| codec != VideoCodec::Webm) {
+            command.arg("-preset").arg(preset);
+        }
+        // gifs can have odd sizes and palettes the encoders do not accept
+        command.args(["-pix_fmt", "yuv420p", "-vf", "scale=trunc(iw/2)*2:trunc(ih/2)*2"]);
+        debug!("Transcoding {} to {}", path, output);
+        let status = command.arg(&output).stdout(Stdio::null()).stderr(Stdio::null()).status();
+        match status.await {
+            Ok(status) if status.success() => (),
+            result => {
+                let _ = fs::remove_file(&output);
+                warn!("Could not transcode {} to {}: {:?}", path, codec.name(), result);
+                return path;
+            }
+        }
+
+        let size = |file: &str| fs::metadata(file).map(|metadata| metadata.len()).unwrap_or(0);
+        *self.bytes_saved.lock().await += size(&path) as i64 - size(&output) as i64;
+        if self.options.keep_original_after_transcode {
+            return output;
+        }
+        let replaced = fs::remove_file(&path).and_then(|_| match output != target {
+            true => fs::rename(&output, &target),
+            false => Ok(()),
+        });
+        match replaced {
+            Ok(_) => target,
+            Err(e) => {
+                warn!("Could not replace {} with its transcoded copy: {}", path, e);
+                output
+            }
+        }
+    }
+
+    fn unzip_album(&self, zip_path: &str, task: &DownloadTask) -> Result<(), GertError> {
+        let file = File::open(zip_path)?;
+        let mut archive = zip::ZipArchive::new(file)?;
+
+        for i in 0..archive.len() {
+            // Unzip the contents of the zip file
+
+            let mut file = archive.by_index(i)?;
+            let extension = file.name().split('.').last().unwrap();
+
+            let filename = self.generate_file_name(
+                &task.url,
+                &self.subreddit_directory(task),
+                extension,
+                &task.post_name,
+                &task.post_title,
+                Some(i),
+            );
+            debug!("Unzipping file: {}", filename);
+            let mut outfile = fs::File::create(filename)?;
+            io::copy(&mut file, &mut outfile)?;
+        }
+        Ok(())
+    }
+
+    /// Download the images of an imgur album one by one with the imgur API, under the names
+    /// the images of the zip would have had. Returns how many of the images were saved
+    async fn recover_imgur_album(&self, task: &DownloadTask) -> Result<(usize, usize)> {
+        let client_id = self.options.imgur_client_id.as_ref().context(
+            "The imgur API needs a client id, set IMGUR_CLIENT_ID or pass --imgur-client-id",
+        )?;
+        let hash = imgur_album_hash(&task.url).context("Could not find the imgur album id")?;
+        let api_url = format!("{}/album/{}/images", self.options.endpoints.imgur_api, hash);
+        self.host_rate_limiter.acquire(&api_url).await;
+        let response = self
+            .session
+            .get(&api_url)
+            .header("Authorization", format!("Client-ID {}", client_id))
+            .send()
+            .await
+            .context("Error contacting imgur API")?
+            .json::<ImgurAlbumResponse>()
+            .await
+            .context(format!("Error parsing imgur API response from {}", api_url))?;
+
+        let mut recovered = 0;
+        for (i, image) in response.data.iter().enumerate() {
+            let extension = image.link.rsplit('.').next().unwrap_or(JPG);
+            let filename = self.generate_file_name(
+                &task.url,
+                &self.subreddit_directory(task),
+                extension,
+                &task.post_name,
+                &task.post_title,
+                Some(i),
+            );
+            match self.download_media(&filename, &image.link).await {
+                Ok(true) => recovered += 1,
+                Ok(false) => warn!("Could not save {} to {}", image.link, filename),
+                Err(e) => warn!("Could not download {}: {}", image.link, e),
+            }
+        }
+        Ok((recovered, response.data.len()))
+    }
+
+    /// Whether an existing file should be kept instead of downloading the media, according
+    /// to the --on-exists policy
+    async fn keep_existing(&self, file_name: &str, url: &str) -> bool {
+        let exists = check_path_present(file_name);
+        if exists && self.options.replace_corrupt && looks_corrupt(Path::new(file_name)) {
+            warn!("{} looks corrupt, downloading it again", file_name);
+            return false;
+        }
+        let processed = processed_file_names(file_name, &self.options)
+            .iter()
+            .any(|name| check_path_present(name));
+        match self.options.on_exists {
+            OnExists::Overwrite => false,
+            OnExists::Newer if exists => !self.remote_differs(file_name, url).await,
+            OnExists::Skip | OnExists::Rename | OnExists::Newer => exists || processed,
+        }
+    }
+
+    /// Whether the server has a different version of the media than the file. Compares the
+    /// Last-Modified date with the modification time of the file or, when the server does not
+    /// send a date, the Content-Length with the size of the file. Assumes the file is up to
+    /// date when the server reports neither.
+    async fn remote_differs(&self, file_name: &str, url: &str) -> bool {
+        let metadata = match fs::metadata(file_name) {
+            Ok(metadata) => metadata,
+            Err(_) => return true,
+        };
+        self.host_rate_limiter.acquire(url).await;
+        let response = match self.session.head(url).send().await {
+            Ok(response) if response.status().is_success() => response,
+            _ => return false,
+        };
+        let header =
+            |name: HeaderName| response.headers().get(name).and_then(|value| value.to_str().ok());
+
+        let remote_modified =
+            header(LAST_MODIFIED).and_then(|date| DateTime::parse_from_rfc2822(date).ok());
+        let local_modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+        if let (Some(remote), Some(local)) = (remote_modified, local_modified) {
+            debug!("{} was modified at {}, the remote media at {}", file_name, local, remote);
+            return remote > local;
+        }
+        match header(CONTENT_LENGTH).and_then(|size| size.parse::<u64>().ok()) {
+            Some(size) => size != metadata.len(),
+            None => false,
+        }
+    }
+
+    /// Size of the media at the URL as reported by a HEAD request, if the server sends it
+    async fn content_length(&self, url: &str) -> Option<u64> {
+        self.host_rate_limiter.acquire(url).await;
+        let response = self.session.head(url).send().await.ok()?.error_for_status().ok()?;
+        response.headers().get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+    }
+
+    /// Follow the redirects of a media URL without downloading the media
+    async fn resolve_final_url(&self, url: &str) -> Result<String, GertError> {
+        self.host_rate_limiter.acquire(url).await;
+        let response = self.session.head(url).send().await?.error_for_status()?;
+        Ok(response.url().to_string())
+    }
+
+    /// Append `<file> <permalink>` to the urls.txt of the subreddit folder of the file
+    async fn write_url(&self, path: &str, task: &DownloadTask) -> io::Result<()> {
+        let directory =
+            Path::new(&self.options.data_directory).join(self.subreddit_directory(task));
+        let file_name = Path::new(path).strip_prefix(&directory).unwrap_or(Path::new(path));
+        let line = format!("{} {}\n", file_name.display(), task.metadata.permalink);
+
+        let _guard = self.urls_file.lock().await;
+        let mut file =
+            fs::OpenOptions::new().create(true).append(true).open(directory.join("urls.txt"))?;
+        io::Write::write_all(&mut file, line.as_bytes())
+    }
+
+    /// Write the details of the post into the downloaded file, when its format supports it
+    async fn embed_metadata(&self, path: &str, task: &DownloadTask) -> Result<(), GertError> {
+        if !check_path_present(path) {
+            // the path of unzipped albums no longer exists
+            return Ok(());
+        }
+        let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+        match extension.as_str() {
+            "jpg" | "jpeg" | "png" => embed_image_metadata(path, &task.metadata),
+            "mp4" if self.options.ffmpeg_available => {
+                embed_video_metadata(path, &task.metadata).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace a video with its audio stream, saved as m4a
+    async fn extract_audio(&self, video_path: &str) -> Result<String, GertError> {
+        let output_file = replace_extension(video_path, M4A);
+        debug!("Extracting audio: {}", output_file);
+        // m4a only holds the AAC of most videos as is, other audio is encoded again
+        for codec in ["copy", "aac"] {
+            let status = tokio::process::Command::new("ffmpeg")
+                .arg("-y")
+                .arg("-i")
+                .arg(video_path)
+                .arg("-vn")
+                .arg("-c:a")
+                .arg(codec)
+                .arg(&output_file)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()?
+                .wait()
+                .await?;
+            if status.success() {
+                fs::remove_file(video_path)?;
+                return Ok(output_file);
+            }
+            let _ = fs::remove_file(&output_file);
+        }
+        // the video is kept when its audio could not be extracted
+        Err(GertError::FfmpegError(format!("Could not extract the audio of {}", video_path)))
+    }
+
     async fn stitch_audio_video(
         &self,
         video_path: &str,
         audio_path: &str,
     ) -> Result<String, GertError> {
-        let output_file = video_path.replace(".mp4", "-merged.mp4");
+        let output_file = match self.options.merge_format {
+            MergeFormat::Mp4 => video_path.replace(".mp4", "-merged.mp4"),
+            MergeFormat::Mkv => replace_extension(video_path, MKV),
+        };
         let mut command = tokio::process::Command::new("ffmpeg")
+            .arg("-y")
             .arg("-i")
             .arg(video_path)
             .arg("-i")
@@ -692,6 +1906,10 @@ impl Downloader {
             fs::remove_file(video_path)?;
             fs::remove_file(audio_path)?;
 
+            if self.options.merge_format == MergeFormat::Mkv {
+                debug!("Successfully merged audio and video: {}", output_file);
+                return Ok(output_file);
+            }
             fs::rename(output_file, video_path)?;
             debug!("Successfully merged audio and video: {}", video_path);
             return Ok(video_path.to_owned());
@@ -701,17 +1919,90 @@ impl Downloader {
         }
     }
 
+    /// Check the extension of a task against the `--only-ext` and `--skip-ext` filters
+    fn extension_allowed(&self, extension: &str) -> bool {
+        let passes = |ext: &str| {
+            let ext = ext.to_lowercase();
+            let listed = match &self.options.only_extensions {
+                Some(only) => only.contains(&ext),
+                None => true,
+            };
+            listed && !self.options.skip_extensions.contains(&ext)
+        };
+
+        if passes(extension) {
+            return true;
+        }
+        // gifs that are going to be converted are also allowed if mp4 is explicitly allowed
+        let converted =
+            extension == GIF && self.options.ffmpeg_available && !self.options.conserve_gifs;
+        let mp4_allowed = match &self.options.only_extensions {
+            Some(only) => only.iter().any(|ext| ext == MP4),
+            None => false,
+        };
+        converted && mp4_allowed && passes(MP4)
+    }
+
+    /// Name of the folder inside the data directory the media of the task are saved to
+    fn subreddit_directory(&self, task: &DownloadTask) -> String {
+        self.post_directory(&task.subreddit, task.metadata.created_utc)
+    }
+
+    /// Name of the folder the media of a post of `subreddit` created at `created_utc` go to
+    fn post_directory(&self, subreddit: &str, created_utc: Option<i64>) -> String {
+        match &self.options.subreddit_dir_template {
+            Some(template) => render_subreddit_dir(template, subreddit, created_utc),
+            None => subreddit.to_owned(),
+        }
+    }
+
     fn get_filename(&self, task: &DownloadTask) -> String {
-        self.generate_file_name(
+        if let Some(folder) = &task.gallery_folder {
+            // items in a gallery folder are simply numbered in the order of the gallery
+            return format!(
+                "{}/{}/{}/{}.{}",
+                self.options.data_directory,
+                self.subreddit_directory(task),
+                folder,
+                task.index.unwrap_or(0) + 1,
+                task.extension
+            );
+        }
+        let file_name = self.generate_file_name(
             &task.url,
-            &task.subreddit,
+            &self.subreddit_directory(task),
             &task.extension,
             &task.post_name,
             &task.post_title,
             task.index,
-        )
+        );
+        match task.suffix {
+            Some(suffix) => add_suffix(&file_name, suffix),
+            None => file_name,
+        }
     }
 }
+/// The items of a gallery in order and the files they were saved to, see --include-gallery-index-json
+#[derive(Debug, Serialize)]
+struct GalleryIndex {
+    post: String,
+    title: String,
+    permalink: String,
+    items: Vec<GalleryIndexItem>,
+}
+
+#[derive(Debug, Serialize)]
+struct GalleryIndexItem {
+    /// Position of the item in the gallery, starting at 1
+    index: usize,
+    media_id: Option<String>,
+    caption: Option<String>,
+    outbound_url: Option<String>,
+    url: String,
+    /// Path of the file relative to the subreddit folder, null when the item was not saved
+    file: Option<String>,
+}
+
 #[derive(Debug)]
 struct DownloadTask {
     url: String,
@@ -719,7 +2010,22 @@ struct DownloadTask {
     extension: String,
     post_name: String,
     post_title: String,
+    /// Position of the media among the media of the post, it is assigned from the order of
+    /// the post (gallery order, video before audio) before the task is scheduled so that a
+    /// file is always named after the same media whatever order the downloads finish in
     index: Option<usize>,
+    media_type: MediaType,
+    /// Folder inside the subreddit directory the gallery item is saved to
+    gallery_folder: Option<String>,
+    /// Added to the file name before the extension, eg: _video and _audio for the streams
+    /// of a reddit video saved separately
+    suffix: Option<&'static str>,
+    /// Details of the post to embed into the file with --embed-metadata
+    metadata: EmbeddedMetadata,
+    score: i64,
+    /// Transcode the file with --convert-to once saved, false for the streams of reddit
+    /// videos that are transcoded once merged
+    transcode: bool,
 }
 impl DownloadTask {
     fn from_post<U: Into<String>, V: Into<String>>(
@@ -735,6 +2041,610 @@ impl DownloadTask {
             post_name: post.data.name.to_owned(),
             post_title: post.data.title.clone().unwrap(),
             index,
+            media_type: post.get_type(),
+            gallery_folder: None,
+            suffix: None,
+            metadata: EmbeddedMetadata::from_post(post),
+            score: post.data.score,
+            transcode: true,
+        }
+    }
+
+    /// Row of the manifest for the media of the task, saved to `local_path`
+    fn manifest_entry<'a>(&'a self, local_path: &'a str) -> ManifestEntry<'a> {
+        ManifestEntry {
+            post_id: &self.post_name,
+            subreddit: &self.subreddit,
+            author: &self.metadata.author,
+            title: &self.post_title,
+            score: self.score,
+            created_utc: self.metadata.created_utc,
+            url: &self.url,
+            media_type: format!("{:?}", self.media_type),
+            local_path,
+            bytes: fs::metadata(local_path).map(|metadata| metadata.len()).unwrap_or(0),
+        }
+    }
+}
+
+/// Extract the album id from an imgur album URL, eg: https://imgur.com/a/<id>/zip
+fn imgur_album_hash(url: &str) -> Option<String> {
+    let parsed = Url::parse(url).ok()?;
+    let segments: Vec<&str> = parsed.path_segments()?.filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        ["a", hash, ..] => Some(hash.to_string()),
+        _ => None,
+    }
+}
+
+/// Extract the media id from the path of a giphy URL, eg: /gifs/some-slug-<id>,
+/// /clips/some-slug-<id> or /media/<id>/giphy.gif
+fn giphy_media_id(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        ["media", id, ..] => Some(id.to_string()),
+        [.., last] => last.split('-').last().map(String::from),
+        [] => None,
+    }
+}
+
+/// The syndication API expects a token derived from the tweet id the same way the embed
+/// widget computes it: `((id / 1e15) * PI).toString(36)` without zeros and dots
+fn twitter_syndication_token(tweet_id: &str) -> String {
+    let value = tweet_id.parse::<f64>().unwrap_or(0.0) / 1e15 * std::f64::consts::PI;
+    let mut integer = value.trunc() as u64;
+    let mut fraction = value.fract();
+
+    let mut digits: Vec<u32> = Vec::new();
+    loop {
+        digits.push((integer % 36) as u32);
+        integer /= 36;
+        if integer == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+
+    // like javascript, only print as many fraction digits as needed to tell the number
+    // apart from its neighbouring floats, rounding the last one
+    let mut delta = 0.5 * (f64::from_bits(value.to_bits() + 1) - value);
+    while fraction >= delta {
+        fraction *= 36.0;
+        delta *= 36.0;
+        let digit = fraction.trunc();
+        fraction -= digit;
+        digits.push(digit as u32);
+        if (fraction > 0.5 || (fraction == 0.5 && digit as u32 % 2 == 1)) && fraction + delta > 1.0
+        {
+            // round up, carrying over to the previous digits
+            while let Some(last) = digits.last_mut() {
+                *last += 1;
+                if *last < 36 {
+                    break;
+                }
+                digits.pop();
+            }
+            break;
+        }
+    }
+    digits
+        .into_iter()
+        .filter_map(|digit| std::char::from_digit(digit, 36))
+        .filter(|c| *c != '0')
+        .collect()
+}
+
+/// Hash of a media URL without its query and fragment
+fn url_hash(url: &str) -> String {
+    let mut parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return format!("{:x}", md5::compute(url)),
+    };
+    parsed.set_query(None);
+    parsed.set_fragment(None);
+    format!("{:x}", md5::compute(parsed.as_str()))
+}
+
+/// Hidden file next to a downloaded media that records the hash of the URL it came from
+fn source_sidecar(file_name: &str) -> String {
+    let path = Path::new(file_name);
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    path.with_file_name(format!(".{}.source", name)).to_string_lossy().into_owned()
+}
+
+/// Find a file name that is either free or already holds the media of this URL, adding
+/// _1, _2, ... before the extension while the name is taken by the media of another URL.
+/// Files without a recorded source are assumed to hold the media of this URL.
+fn avoid_collision(file_name: String, url: &str) -> String {
+    let hash = url_hash(url);
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) => (stem.to_owned(), format!(".{}", extension)),
+        None => (file_name.clone(), String::new()),
+    };
+    let mut candidate = file_name;
+    let mut suffix = 1;
+    loop {
+        if !check_path_present(&candidate) {
+            return candidate;
+        }
+        match fs::read_to_string(source_sidecar(&candidate)) {
+            Ok(source) if source.trim() != hash => {
+                debug!("{} holds the media of another URL", candidate);
+                candidate = format!("{}_{}{}", stem, suffix, extension);
+                suffix += 1;
+            }
+            _ => return candidate,
+        }
+    }
+}
+
+/// Replace the extension of the file name, leaving the directories untouched
+fn replace_extension(file_name: &str, extension: &str) -> String {
+    Path::new(file_name).with_extension(extension).to_string_lossy().into_owned()
+}
+
+/// Append `suffix` to the name of the file, before its extension
+fn add_suffix(file_name: &str, suffix: &str) -> String {
+    let path = Path::new(file_name);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+    let mut name = format!("{}{}", stem, suffix);
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        name = format!("{}.{}", name, extension);
+    }
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Names of the files the media saved at `file_name` can be turned into by post processing:
+/// gifs converted to mp4 unless they are conserved, the first image of an unzipped imgur
+/// album and the audio extracted with --audio-only
+fn processed_file_names(file_name: &str, options: &DownloadOptions) -> Vec<String> {
+    let extension = Path::new(file_name).extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let mut names = match extension {
+        GIF if !options.conserve_gifs => vec![replace_extension(file_name, MP4)],
+        ZIP => vec![replace_extension(file_name, JPG)],
+        MP4 if options.audio_only => vec![replace_extension(file_name, M4A)],
+        MP4 if options.merge_format == MergeFormat::Mkv => vec![replace_extension(file_name, MKV)],
+        _ => Vec::new(),
+    };
+    if let Some(codec) = options.convert_to.filter(|_| [GIF, MP4, MKV].contains(&extension)) {
+        let transcoded = replace_extension(file_name, codec.extension());
+        if transcoded != file_name && !names.contains(&transcoded) {
+            names.push(transcoded);
+        }
+    }
+    if options.fix_extensions {
+        let renamed = DETECTED_EXTENSIONS.iter().map(|(detected, _)| *detected);
+        let renamed = renamed.filter(|detected| *detected != extension);
+        names.extend(renamed.map(|detected| replace_extension(file_name, detected)));
+    }
+    names
+}
+
+/// The extension matching the content of the file when it differs from the one it has.
+/// Formats `DETECTED_EXTENSIONS` does not list are left alone
+fn detected_extension(file_name: &str) -> Option<&'static str> {
+    let kind = match infer::get_from_path(file_name) {
+        Ok(kind) => kind?,
+        Err(e) => {
+            debug!("Could not read {}: {}", file_name, e);
+            return None;
         }
+    };
+    let extension = Path::new(file_name).extension().and_then(|ext| ext.to_str())?.to_lowercase();
+    let (detected, aliases) =
+        DETECTED_EXTENSIONS.iter().find(|(detected, _)| *detected == kind.extension())?;
+    if *detected == extension || aliases.contains(&extension.as_str()) {
+        return None;
+    }
+    Some(*detected)
+}
+
+/// Make a post title safe to use as part of a path
+fn canonical_title(title: &str, options: &DownloadOptions) -> String {
+    let disallowed_chars = [' ', '.', '/', '\\', ':', '=', '?', '"', '<', '>', '|', '*'];
+    let title = match options.title_case {
+        true => title_case(title),
+        false => title.to_lowercase(),
+    };
+    let canonical: String = title
+        .chars()
+        .take(options.max_title_length.unwrap_or(MAX_TITLE_LENGTH))
+        .map(|c| if disallowed_chars.contains(&c) { '_' } else { c })
+        .collect();
+    if !options.slugify {
+        return canonical;
+    }
+    // collapse the runs of separators and drop them from the ends
+    canonical
+        .split(|c: char| c == '_' || c == '-' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Capitalize the first letter of every word and lowercase the rest
+fn title_case(title: &str) -> String {
+    let mut capitalize = true;
+    let mut cased = String::with_capacity(title.len());
+    for c in title.chars() {
+        if capitalize {
+            cased.extend(c.to_uppercase());
+        } else {
+            cased.extend(c.to_lowercase());
+        }
+        capitalize = c.is_whitespace();
+    }
+    cased
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::test_post;
+
+    #[test]
+    fn test_processed_file_names() {
+        let mut options = DownloadOptions::default();
+        let gif = "data/my.gifs/abc.gif";
+        assert_eq!(processed_file_names(gif, &options), vec!["data/my.gifs/abc.mp4"]);
+        assert_eq!(processed_file_names("data/pics/abc.zip", &options), vec!["data/pics/abc.jpg"]);
+        assert!(processed_file_names("data/pics/abc.mp4", &options).is_empty());
+
+        // conserved gifs are never converted, an mp4 of the same name is not the same media
+        options.conserve_gifs = true;
+        assert!(processed_file_names(gif, &options).is_empty());
+
+        options.merge_format = MergeFormat::Mkv;
+        assert_eq!(processed_file_names("data/pics/abc.mp4", &options), vec!["data/pics/abc.mkv"]);
+
+        options.audio_only = true;
+        assert_eq!(processed_file_names("data/pics/abc.mp4", &options), vec!["data/pics/abc.m4a"]);
+
+        let mut options =
+            DownloadOptions { convert_to: Some(VideoCodec::Webm), ..Default::default() };
+        assert_eq!(
+            processed_file_names(gif, &options),
+            vec!["data/my.gifs/abc.mp4", "data/my.gifs/abc.webm"]
+        );
+        assert_eq!(processed_file_names("data/pics/abc.mp4", &options), vec!["data/pics/abc.webm"]);
+        // transcoded to the same extension, the file keeps its name
+        options.convert_to = Some(VideoCodec::H265);
+        assert!(processed_file_names("data/pics/abc.mp4", &options).is_empty());
+        assert!(processed_file_names("data/pics/abc.jpg", &options).is_empty());
+    }
+
+    #[test]
+    fn test_detected_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, contents: &[u8]| {
+            let path = dir.path().join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let jpg = b"\xFF\xD8\xFF\xE0\0\x10JFIF\0";
+        assert_eq!(detected_extension(&file("image.jpg", png)), Some(PNG));
+        assert_eq!(detected_extension(&file("image.png", png)), None);
+        assert_eq!(detected_extension(&file("image.jpeg", jpg)), None);
+        assert_eq!(detected_extension(&file("image.gif", jpg)), Some(JPG));
+        // formats gert does not rename to are left alone
+        assert_eq!(detected_extension(&file("text.jpg", b"just some text")), None);
+
+        let options = DownloadOptions { fix_extensions: true, ..Default::default() };
+        let names = processed_file_names("data/pics/abc.jpg", &options);
+        assert!(names.contains(&String::from("data/pics/abc.png")));
+        assert!(!names.contains(&String::from("data/pics/abc.jpg")));
+    }
+
+    #[tokio::test]
+    async fn test_fix_extension_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let url = "https://i.redd.it/abc.jpg";
+        let task = DownloadTask::from_post(&test_post(url), url, JPG, None);
+        let fix = |on_exists: OnExists| {
+            fs::write(path("abc.jpg"), png).unwrap();
+            fs::write(path("abc.png"), "existing").unwrap();
+            let options = DownloadOptions { fix_extensions: true, on_exists, ..Default::default() };
+            let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+            let (task, download_path) = (&task, path("abc.jpg"));
+            async move { downloader.post_process(download_path, task).await.unwrap() }
+        };
+
+        // the existing file is kept and the download keeps its name
+        assert_eq!(fix(OnExists::Skip).await, path("abc.jpg"));
+        assert_eq!(fs::read_to_string(path("abc.png")).unwrap(), "existing");
+
+        fs::write(source_sidecar(&path("abc.png")), url_hash("https://i.redd.it/other.png"))
+            .unwrap();
+        assert_eq!(fix(OnExists::Rename).await, path("abc_1.png"));
+        assert_eq!(fs::read_to_string(path("abc.png")).unwrap(), "existing");
+
+        assert_eq!(fix(OnExists::Overwrite).await, path("abc.png"));
+        assert_eq!(fs::read(path("abc.png")).unwrap(), png);
+    }
+
+    #[test]
+    fn test_imgur_album_hash() {
+        assert_eq!(imgur_album_hash("https://imgur.com/a/AbC123/zip").as_deref(), Some("AbC123"));
+        assert_eq!(imgur_album_hash("https://imgur.com/a/AbC123").as_deref(), Some("AbC123"));
+        assert_eq!(imgur_album_hash("https://imgur.com/AbC123"), None);
+    }
+
+    #[test]
+    fn test_name_by_post_id() {
+        let options = DownloadOptions {
+            data_directory: String::from("data"),
+            name_by_post_id: true,
+            ..Default::default()
+        };
+        let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+        let name = |url: &str, extension: &str, index: Option<usize>| {
+            downloader.generate_file_name(url, "aww", extension, "t3_abc123", "Cat", index)
+        };
+
+        // the post maps to the same file whichever URL its media resolved to on each run
+        let sd = name("https://media.redgifs.com/SomeGif-mobile.mp4", MP4, None);
+        let hd = name("https://media.redgifs.com/SomeGif.mp4?expires=1", MP4, None);
+        assert_eq!(sd, "data/aww/t3_abc123.mp4");
+        assert_eq!(sd, hd);
+        assert_eq!(name("https://i.imgur.com/AbC123.gifv", MP4, None), sd);
+
+        assert_eq!(name("https://i.redd.it/two.png", PNG, Some(1)), "data/aww/t3_abc123_1.png");
+    }
+
+    #[tokio::test]
+    async fn test_recover_imgur_album() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let links = [format!("{}/one.jpg", server.uri()), format!("{}/two.png", server.uri())];
+        Mock::given(method("GET"))
+            .and(path("/album/AbC123/images"))
+            .and(header("Authorization", "Client-ID my-client"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "data": [{"link": links[0]}, {"link": links[1]}]
+            })))
+            .mount(&server)
+            .await;
+        for (route, body) in [("/one.jpg", "first image"), ("/two.png", "second image")] {
+            Mock::given(method("GET"))
+                .and(path(route))
+                .respond_with(ResponseTemplate::new(200).set_body_string(body))
+                .mount(&server)
+                .await;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            data_directory: dir.path().to_str().unwrap().to_owned(),
+            name_by_post_id: true,
+            imgur_client_id: Some(String::from("my-client")),
+            endpoints: Endpoints { imgur_api: server.uri(), ..Default::default() },
+            ..Default::default()
+        };
+        let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+        let album = "https://imgur.com/a/AbC123/zip";
+        let task = DownloadTask::from_post(&test_post(album), album, ZIP, None);
+
+        assert_eq!(downloader.recover_imgur_album(&task).await.unwrap(), (2, 2));
+        // saved under the names the images of the zip would have had
+        let saved = |name: &str| fs::read_to_string(dir.path().join("pics").join(name)).unwrap();
+        assert_eq!(saved("t3_abc123.jpg"), "first image");
+        assert_eq!(saved("t3_abc123_1.png"), "second image");
+
+        // the API is only used with a client id
+        let options = DownloadOptions { imgur_client_id: None, ..Default::default() };
+        let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+        assert!(downloader.recover_imgur_album(&task).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redgif_token_requested_lazily() {
+        use serde_json::json;
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/auth/temporary"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "token": "abc", "addr": "", "agent": "", "session": "", "rtfm": ""
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let hd = format!("{}/cat.mp4", server.uri());
+        Mock::given(method("GET"))
+            .and(path("/gifs/cat"))
+            .and(header("Authorization", "Bearer abc"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"gif": {
+                "id": "cat",
+                "urls": {
+                    "sd": hd, "hd": hd, "poster": "", "thumbnail": "", "vthumbnail": ""
+                }
+            }})))
+            .mount(&server)
+            .await;
+
+        let options = DownloadOptions {
+            endpoints: Endpoints { redgifs_api: server.uri(), ..Default::default() },
+            ..Default::default()
+        };
+        let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+        // no token was requested at startup, as if every attempt had failed
+        assert!(downloader.ephemeral_token.lock().await.is_none());
+        let gif = test_post("https://redgifs.com/watch/cat");
+        downloader.download_redgif(&gif).await.unwrap();
+        downloader.download_redgif(&gif).await.unwrap();
+        assert_eq!(downloader.ephemeral_token.lock().await.as_deref(), Some("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_gallery_index() {
+        use serde_json::{json, Value};
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            data_directory: dir.path().to_str().unwrap().to_owned(),
+            should_download: true,
+            gallery_index: true,
+            gallery_subfolders: true,
+            dedupe_across_galleries: true,
+            ..Default::default()
+        };
+        let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+        let mut gallery = test_post("https://www.reddit.com/gallery/abc123");
+        gallery.data.gallery_data = serde_json::from_value(json!({"items": [
+            {"media_id": "one", "id": 1, "caption": "First"},
+            {"media_id": "two", "id": 2},
+            {"media_id": "three", "id": 3, "caption": "Third", "outbound_url": "https://example.com"}
+        ]}))
+        .unwrap();
+        let metadata =
+            |id: &str, mime: &str| json!({"status": "valid", "e": "Image", "m": mime, "id": id});
+        gallery.data.media_metadata = serde_json::from_value(json!({
+            "three": metadata("three", "image/png"),
+            "two": metadata("two", "image/jpg"),
+            "one": metadata("one", "image/png")
+        }))
+        .unwrap();
+
+        // saved by an earlier run
+        let folder = dir.path().join("pics/abc123_cat");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("1.png"), b"one").unwrap();
+        fs::write(folder.join("3.png"), b"three").unwrap();
+        // already saved from another gallery of this run
+        downloader.is_duplicate_media("two").await;
+
+        downloader.download_resolved(&gallery, MediaType::Gallery).await.unwrap();
+        let index = fs::read_to_string(dir.path().join("pics/abc123_cat.json")).unwrap();
+        let index: Value = serde_json::from_str(&index).unwrap();
+        assert_eq!(index["post"], json!("t3_abc123"));
+        let items = index["items"].as_array().unwrap();
+        let summary: Vec<(&Value, &Value, &Value)> =
+            items.iter().map(|item| (&item["index"], &item["caption"], &item["file"])).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (&json!(1), &json!("First"), &json!("abc123_cat/1.png")),
+                (&json!(2), &Value::Null, &Value::Null),
+                (&json!(3), &json!("Third"), &json!("abc123_cat/3.png")),
+            ]
+        );
+        assert_eq!(items[2]["outbound_url"], json!("https://example.com"));
+        assert_eq!(items[1]["media_id"], json!("two"));
+    }
+
+    #[tokio::test]
+    async fn test_rerun_skips_existing_post() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = DownloadOptions {
+            data_directory: dir.path().to_str().unwrap().to_owned(),
+            name_by_post_id: true,
+            should_download: true,
+            ..Default::default()
+        };
+        let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+        let mut post = test_post("https://redgifs.com/watch/somegif");
+        post.data.subreddit = String::from("aww");
+        // saved by an earlier run from the SD rendition
+        let saved = dir.path().join("aww/t3_abc123.mp4");
+        fs::create_dir_all(saved.parent().unwrap()).unwrap();
+        fs::write(&saved, b"earlier run").unwrap();
+
+        // this run resolves the post to the HD rendition, and another to the gifv of a mirror
+        for url in
+            ["https://media.redgifs.com/SomeGif.mp4?expires=1", "https://i.imgur.com/AbC123.gifv"]
+        {
+            let task = DownloadTask::from_post(&post, url, MP4, None);
+            assert_eq!(downloader.schedule_task(task).await, None);
+        }
+        assert_eq!(*downloader.skipped.lock().await, 2);
+        assert_eq!(*downloader.downloaded.lock().await, 0);
+        assert_eq!(fs::read(&saved).unwrap(), b"earlier run");
+    }
+
+    #[tokio::test]
+    async fn test_keep_existing() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        for (route, modified) in [
+            ("/old.jpg", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ("/new.jpg", "Fri, 01 Jan 2100 00:00:00 GMT"),
+        ] {
+            Mock::given(method("HEAD"))
+                .and(path(route))
+                .respond_with(ResponseTemplate::new(200).insert_header("Last-Modified", modified))
+                .mount(&server)
+                .await;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("abc.jpg").to_str().unwrap().to_owned();
+        let missing = dir.path().join("def.jpg").to_str().unwrap().to_owned();
+        fs::write(&file, b"jpeg").unwrap();
+        let (old, new) = (format!("{}/old.jpg", server.uri()), format!("{}/new.jpg", server.uri()));
+
+        let (file, missing, old, new) = (&file, &missing, &old, &new);
+        let keep = |on_exists| async move {
+            let options = DownloadOptions { on_exists, ..Default::default() };
+            let downloader = Downloader::new(Vec::new(), reqwest::Client::new(), options);
+            (
+                downloader.keep_existing(file, old).await,
+                downloader.keep_existing(file, new).await,
+                downloader.keep_existing(missing, old).await,
+            )
+        };
+        assert_eq!(keep(OnExists::Skip).await, (true, true, false));
+        assert_eq!(keep(OnExists::Overwrite).await, (false, false, false));
+        assert_eq!(keep(OnExists::Rename).await, (true, true, false));
+        // only the media the server changed after the file was saved is downloaded again
+        assert_eq!(keep(OnExists::Newer).await, (true, false, false));
+
+        // a file holding the media of another URL is renamed around, not overwritten
+        fs::write(source_sidecar(file), url_hash(old)).unwrap();
+        assert_eq!(&avoid_collision(file.clone(), old), file);
+        let renamed = dir.path().join("abc_1.jpg").to_str().unwrap().to_owned();
+        assert_eq!(avoid_collision(file.clone(), new), renamed);
+    }
+
+    #[test]
+    fn test_twitter_syndication_token() {
+        // tokens computed by the javascript of the embed widget
+        assert_eq!(twitter_syndication_token("1628832338187636740"), "3y54libozsy");
+        assert_eq!(twitter_syndication_token("463440424141459456"), "14fxvks611f");
+        assert_eq!(twitter_syndication_token("1870000000000000001"), "4j6smlnxuh");
+        assert_eq!(twitter_syndication_token("20"), "6dq1a2xwd93");
+    }
+
+    #[test]
+    fn test_add_suffix() {
+        assert_eq!(
+            add_suffix("data/my.videos/t3_abc.mp4", "_video"),
+            "data/my.videos/t3_abc_video.mp4"
+        );
+        assert_eq!(add_suffix("data/videos/t3_abc.m4a", "_audio"), "data/videos/t3_abc_audio.m4a");
+        assert_eq!(add_suffix("data/videos/t3_abc", "_audio"), "data/videos/t3_abc_audio");
+    }
+
+    #[test]
+    fn test_canonical_title() {
+        let mut options = DownloadOptions::default();
+        let title = "  My cat: is he  cute? ";
+        assert_eq!(canonical_title(title, &options), "__my_cat__is_he__cute__");
+
+        options.slugify = true;
+        assert_eq!(canonical_title(title, &options), "my-cat-is-he-cute");
+
+        options.title_case = true;
+        assert_eq!(canonical_title(title, &options), "My-Cat-Is-He-Cute");
+
+        options.max_title_length = Some(8);
+        assert_eq!(canonical_title(title, &options), "My-Cat");
     }
 }
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde::Serialize;
+
+use crate::errors::GertError;
+
+/// Something the downloader did, written to the event log as soon as it happens
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The media type of a post was worked out
+    Classified { post: &'a str, subreddit: &'a str, media_type: String },
+    /// A media started downloading to `file`
+    Started { post: &'a str, url: &'a str, file: &'a str },
+    /// A media was saved to `file`
+    Completed { post: &'a str, url: &'a str, file: &'a str, bytes: u64 },
+    /// The media of a post could not be downloaded
+    Failed { post: &'a str, subreddit: &'a str, reason: String },
+    /// A media was not downloaded, eg: because it exists already or is filtered out
+    Skipped { subreddit: &'a str, media_type: String, reason: &'a str },
+}
+
+/// An event with the time it happened at, in seconds since the epoch
+#[derive(Serialize)]
+struct Record<'a> {
+    time: f64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Writes one JSON object per line for each event of the run, to a file or to stderr
+pub struct EventLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLog").finish_non_exhaustive()
+    }
+}
+
+impl EventLog {
+    /// Append the events to the file at `path`, or write them to stderr if it is `-`
+    pub fn open(path: &str) -> Result<EventLog, GertError> {
+        let out: Box<dyn Write + Send> = match path {
+            "-" => Box::new(io::stderr()),
+            path => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        Ok(EventLog { out: Mutex::new(out) })
+    }
+
+    pub fn emit(&self, event: &Event) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let line = match serde_json::to_string(&Record { time, event }) {
+            Ok(line) => line,
+            Err(e) => return debug!("Could not serialize event {:?}: {}", event, e),
+        };
+        let mut out = self.out.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // the run goes on without its event log rather than failing
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            debug!("Could not write event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let log = EventLog::open(path.to_str().unwrap()).unwrap();
+        log.emit(&Event::Started { post: "t3_abc", url: "https://i.redd.it/a.jpg", file: "a.jpg" });
+        log.emit(&Event::Completed {
+            post: "t3_abc",
+            url: "https://i.redd.it/a.jpg",
+            file: "a.jpg",
+            bytes: 42,
+        });
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> =
+            contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "started");
+        assert_eq!(lines[1]["event"], "completed");
+        assert_eq!(lines[1]["bytes"], 42);
+        assert!(lines[1]["time"].as_f64().unwrap() > 0.0);
+    }
+}
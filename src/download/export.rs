@@ -0,0 +1,115 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+
+use log::debug;
+use serde_json::Value;
+
+use crate::errors::GertError;
+use crate::structs::PostData;
+
+/// Where the exported posts go: appended to the file one per line as they are processed, or
+/// kept until the end of the run and written as a single pretty printed array
+enum Output {
+    Lines(BufWriter<File>),
+    Pretty(File, Vec<Value>),
+}
+
+/// Writes the data of every post the downloader processes to a JSON file, for --export-json.
+/// Shared by all the download tasks
+pub struct PostExport {
+    out: Mutex<Output>,
+}
+
+impl fmt::Debug for PostExport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostExport").finish_non_exhaustive()
+    }
+}
+
+impl PostExport {
+    /// Create the file at `path`, replacing the export of an earlier run
+    pub fn create(path: &str, pretty: bool) -> Result<PostExport, GertError> {
+        let file = File::create(path)?;
+        let out = match pretty {
+            true => Output::Pretty(file, Vec::new()),
+            false => Output::Lines(BufWriter::new(file)),
+        };
+        Ok(PostExport { out: Mutex::new(out) })
+    }
+
+    pub fn export(&self, post: &PostData) {
+        let value = match serde_json::to_value(post) {
+            Ok(value) => value,
+            Err(e) => return debug!("Could not serialize post {}: {}", post.name, e),
+        };
+        let mut out = self.out.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match &mut *out {
+            // flushed after every post so that a crash loses at most the post being written
+            Output::Lines(file) => {
+                if let Err(e) = writeln!(file, "{}", value).and_then(|_| file.flush()) {
+                    debug!("Could not export post {}: {}", post.name, e);
+                }
+            }
+            Output::Pretty(_, posts) => posts.push(value),
+        }
+    }
+
+    /// Write the pretty printed array once every post was exported
+    pub fn finish(&self) -> Result<(), GertError> {
+        let mut out = self.out.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match &mut *out {
+            Output::Lines(file) => file.flush()?,
+            Output::Pretty(file, posts) => {
+                let array = serde_json::to_string_pretty(posts)
+                    .map_err(|e| GertError::JsonParseError(e.to_string()))?;
+                writeln!(file, "{}", array)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::test_post;
+    use serde_json::json;
+
+    fn post(id: &str) -> PostData {
+        let mut post = test_post("https://i.redd.it/a.jpg").data;
+        post.id = id.to_owned();
+        post.name = format!("t3_{}", id);
+        post
+    }
+
+    #[test]
+    fn test_post_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = dir.path().join("posts.jsonl");
+        let export = PostExport::create(lines.to_str().unwrap(), false).unwrap();
+        export.export(&post("abc"));
+        // written before the end of the run
+        let contents = std::fs::read_to_string(&lines).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        export.export(&post("def"));
+        export.finish().unwrap();
+        let contents = std::fs::read_to_string(&lines).unwrap();
+        let ids: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["id"].clone())
+            .collect();
+        assert_eq!(ids, vec![json!("abc"), json!("def")]);
+
+        let pretty = dir.path().join("posts.json");
+        let export = PostExport::create(pretty.to_str().unwrap(), true).unwrap();
+        export.export(&post("abc"));
+        export.export(&post("def"));
+        export.finish().unwrap();
+        let posts: Vec<PostData> =
+            serde_json::from_str(&std::fs::read_to_string(&pretty).unwrap()).unwrap();
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[1].name, "t3_def");
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{DownloadOptions, Downloader, MediaType};
+use crate::structs::Post;
+
+/// Downloads the media of the posts of one host. To support a new host, implement this
+/// trait and register it in [`default_hosts`]. The first host that matches a post
+/// downloads it, posts that no host matches are counted as unsupported.
+#[async_trait]
+pub trait MediaDownloader: Debug + Send + Sync {
+    /// Whether this host downloads the post. `media_type` is the type `Post::get_type`
+    /// assigned to the post, new hosts can inspect the post itself instead
+    fn matches(&self, post: &Post, media_type: MediaType) -> bool;
+
+    /// Resolve the media of the post and schedule them with the downloader
+    async fn download(
+        &self,
+        downloader: &Downloader,
+        post: &Post,
+        media_type: MediaType,
+    ) -> Result<()>;
+}
+
+/// The hosts gert supports out of the box
+pub fn default_hosts(options: &DownloadOptions) -> Vec<Box<dyn MediaDownloader>> {
+    let mut hosts: Vec<Box<dyn MediaDownloader>> = vec![
+        Box::new(Resolved),
+        Box::new(RedditVideo),
+        Box::new(Redgifs),
+        Box::new(Giphy),
+        Box::new(ImgurUnknown),
+        Box::new(Streamable),
+    ];
+    if options.enable_twitter {
+        hosts.push(Box::new(Twitter));
+    }
+    // registered last, they only download posts no other host supports
+    if options.use_video_preview {
+        hosts.push(Box::new(VideoPreview));
+    }
+    if options.thumbnail_fallback {
+        hosts.push(Box::new(Thumbnail));
+    }
+    hosts
+}
+
+/// Media whose URLs can be worked out from the post alone, see `Post::get_all_urls`
+#[derive(Debug)]
+struct Resolved;
+
+#[async_trait]
+impl MediaDownloader for Resolved {
+    fn matches(&self, _post: &Post, media_type: MediaType) -> bool {
+        matches!(
+            media_type,
+            MediaType::Gallery
+                | MediaType::RedditImage
+                | MediaType::RedditGif
+                | MediaType::ImgurGif
+                | MediaType::ImgurImage
+                | MediaType::ImgurAlbum
+        )
+    }
+
+    async fn download(
+        &self,
+        downloader: &Downloader,
+        post: &Post,
+        media_type: MediaType,
+    ) -> Result<()> {
+        downloader.download_resolved(post, media_type).await
+    }
+}
+
+#[derive(Debug)]
+struct RedditVideo;
+
+#[async_trait]
+impl MediaDownloader for RedditVideo {
+    fn matches(&self, _post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::RedditVideo
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_reddit_video(post).await
+    }
+}
+
+#[derive(Debug)]
+struct Redgifs;
+
+#[async_trait]
+impl MediaDownloader for Redgifs {
+    fn matches(&self, _post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::RedGif
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_redgif(post).await
+    }
+}
+
+#[derive(Debug)]
+struct Giphy;
+
+#[async_trait]
+impl MediaDownloader for Giphy {
+    fn matches(&self, _post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::GiphyGif
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_giphy(post).await
+    }
+}
+
+/// Imgur links without an extension, the type of the image is found by probing
+#[derive(Debug)]
+struct ImgurUnknown;
+
+#[async_trait]
+impl MediaDownloader for ImgurUnknown {
+    fn matches(&self, _post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::ImgurUnknown
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_imgur_unknown(post).await
+    }
+}
+
+#[derive(Debug)]
+struct Streamable;
+
+#[async_trait]
+impl MediaDownloader for Streamable {
+    fn matches(&self, _post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::StreamableVideo
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_streamable_video(post).await
+    }
+}
+
+/// Only registered with --enable-twitter
+#[derive(Debug)]
+struct Twitter;
+
+#[async_trait]
+impl MediaDownloader for Twitter {
+    fn matches(&self, _post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::Tweet
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_twitter(post).await
+    }
+}
+
+/// Only registered with --use-video-preview
+#[derive(Debug)]
+struct VideoPreview;
+
+#[async_trait]
+impl MediaDownloader for VideoPreview {
+    fn matches(&self, post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::Unsupported && post.get_video_preview_url().is_some()
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_video_preview(post).await
+    }
+}
+
+/// Only registered with --thumbnail-fallback, after the other hosts
+#[derive(Debug)]
+struct Thumbnail;
+
+#[async_trait]
+impl MediaDownloader for Thumbnail {
+    fn matches(&self, post: &Post, media_type: MediaType) -> bool {
+        media_type == MediaType::Unsupported && post.get_thumbnail_url().is_some()
+    }
+
+    async fn download(&self, downloader: &Downloader, post: &Post, _: MediaType) -> Result<()> {
+        downloader.download_thumbnail(post).await
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+
+use crate::errors::GertError;
+
+/// A downloaded media file and the post it belongs to, one row of the manifest
+#[derive(Debug)]
+pub struct ManifestEntry<'a> {
+    /// Fullname of the post, eg: t3_abc123
+    pub post_id: &'a str,
+    pub subreddit: &'a str,
+    pub author: &'a str,
+    pub title: &'a str,
+    pub score: i64,
+    pub created_utc: Option<i64>,
+    /// URL the media was downloaded from
+    pub url: &'a str,
+    pub media_type: String,
+    /// Path of the file after post processing, eg: the mp4 a gif was converted to
+    pub local_path: &'a str,
+    pub bytes: u64,
+}
+
+/// SQLite database indexing every media of the archive, written with --output-manifest-sqlite.
+/// Files downloaded again or found already on disk replace their earlier row
+pub struct Manifest {
+    connection: Mutex<Connection>,
+}
+
+impl fmt::Debug for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Manifest").finish_non_exhaustive()
+    }
+}
+
+impl Manifest {
+    /// Open the database at `path`, creating it and its table on the first run
+    pub fn open(path: &str) -> Result<Manifest, GertError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS media (
+                local_path TEXT PRIMARY KEY,
+                post_id TEXT NOT NULL,
+                subreddit TEXT NOT NULL,
+                author TEXT NOT NULL,
+                title TEXT NOT NULL,
+                score INTEGER NOT NULL,
+                created_utc INTEGER,
+                url TEXT NOT NULL,
+                media_type TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                downloaded_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS media_post_id ON media (post_id);",
+        )?;
+        Ok(Manifest { connection: Mutex::new(connection) })
+    }
+
+    pub fn record(&self, entry: &ManifestEntry) -> Result<(), GertError> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO media (local_path, post_id, subreddit, author, title, score,
+                created_utc, url, media_type, bytes, downloaded_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                entry.local_path,
+                entry.post_id,
+                entry.subreddit,
+                entry.author,
+                entry.title,
+                entry.score,
+                entry.created_utc,
+                entry.url,
+                entry.media_type,
+                entry.bytes as i64,
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs() as i64),
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.db");
+        let path = path.to_str().unwrap();
+        let entry = |local_path, score| ManifestEntry {
+            post_id: "t3_abc123",
+            subreddit: "pics",
+            author: "spez",
+            title: "a title",
+            score,
+            created_utc: Some(1700000000),
+            url: "https://i.redd.it/abc.jpg",
+            media_type: String::from("RedditImage"),
+            local_path,
+            bytes: 1024,
+        };
+        let manifest = Manifest::open(path).unwrap();
+        manifest.record(&entry("pics/a.jpg", 5)).unwrap();
+        manifest.record(&entry("pics/b.jpg", 20000)).unwrap();
+        manifest.record(&entry("pics/a.jpg", 7)).unwrap();
+        drop(manifest);
+
+        // reopening keeps the rows of the earlier runs
+        let manifest = Manifest::open(path).unwrap();
+        let connection = manifest.connection.lock().unwrap();
+        let count: i64 =
+            connection.query_row("SELECT COUNT(*) FROM media", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+        let popular: String = connection
+            .query_row("SELECT local_path FROM media WHERE score > 10000", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(popular, "pics/b.jpg");
+        let score: i64 = connection
+            .query_row("SELECT score FROM media WHERE local_path = 'pics/a.jpg'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(score, 7);
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use img_hash::{HasherConfig, ImageHash};
+use log::debug;
+
+use crate::errors::GertError;
+
+/// Extensions of the images that are compared by --phash-dedupe
+pub const HASHED_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// File in the output directory listing the media deleted as near duplicates, one
+/// `<url hash> <file kept>` per line, so that later runs do not download them again
+pub const NEAR_DUPLICATES_FILE: &str = ".near-duplicates";
+
+/// Perceptual hash of the image at `path`, None if it can not be decoded. The hash stays
+/// close for the same picture re-encoded, resized or slightly recompressed
+pub fn hash_file(path: &str) -> Option<ImageHash> {
+    match img_hash::image::open(path) {
+        Ok(image) => Some(HasherConfig::new().to_hasher().hash_image(&image)),
+        Err(e) => {
+            debug!("Could not decode {} to hash it: {}", path, e);
+            None
+        }
+    }
+}
+
+/// The images saved in the output directory, to find the reposts of an image that were
+/// encoded again and so do not have the same bytes
+pub struct PerceptualDedupe {
+    /// Maximum number of differing bits between the hashes of two copies of an image
+    threshold: u32,
+    hashes: Mutex<Vec<(ImageHash, String)>>,
+    /// Hashes of the URLs of the media deleted in this and earlier runs
+    removed: Mutex<HashSet<String>>,
+    log: Mutex<File>,
+}
+
+impl fmt::Debug for PerceptualDedupe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PerceptualDedupe").field("threshold", &self.threshold).finish()
+    }
+}
+
+impl PerceptualDedupe {
+    /// Load the media deleted by earlier runs from the list at `path`, creating it if needed
+    pub fn open(threshold: u32, path: &Path) -> Result<PerceptualDedupe, GertError> {
+        let removed = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .map(String::from)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(PerceptualDedupe {
+            threshold,
+            hashes: Mutex::new(Vec::new()),
+            removed: Mutex::new(removed),
+            log: Mutex::new(log),
+        })
+    }
+
+    /// Whether the media of the URL with this hash was deleted as a near duplicate
+    pub fn was_removed(&self, url_hash: &str) -> bool {
+        self.removed.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).contains(url_hash)
+    }
+
+    /// Remember that the media of the URL with this hash was deleted for looking like `kept`
+    pub fn record_removed(&self, url_hash: &str, kept: &str) {
+        self.removed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(url_hash.to_owned());
+        let mut log = self.log.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = writeln!(log, "{} {}", url_hash, kept) {
+            debug!("Could not record the near duplicate of {}: {}", kept, e);
+        }
+    }
+
+    /// The file saved earlier in the run that `hash` is a near duplicate of. Otherwise the
+    /// hash is remembered as the one of the image at `path`
+    pub fn find_or_insert(&self, hash: ImageHash, path: &str) -> Option<String> {
+        let mut hashes = self.hashes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let earlier = hashes.iter().find(|(earlier, _)| earlier.dist(&hash) <= self.threshold);
+        if let Some((_, earlier_path)) = earlier {
+            return Some(earlier_path.clone());
+        }
+        hashes.push((hash, path.to_owned()));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use img_hash::image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn test_perceptual_dedupe() {
+        let dir = tempfile::tempdir().unwrap();
+        let save = |name: &str, pixel: fn(u32, u32) -> Rgb<u8>| {
+            let path = dir.path().join(name).to_str().unwrap().to_owned();
+            ImageBuffer::from_fn(128, 128, pixel).save(&path).unwrap();
+            path
+        };
+        let gradient = save("gradient.png", |x, y| Rgb([(x * 2) as u8, (y * 2) as u8, 128]));
+        // the same picture saved in another format has other bytes
+        let reencoded = save("gradient.jpg", |x, y| Rgb([(x * 2) as u8, (y * 2) as u8, 128]));
+        let checkers = save("checkers.png", |x, y| {
+            if (x / 16 + y / 16) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        });
+
+        let list = dir.path().join(NEAR_DUPLICATES_FILE);
+        let dedupe = PerceptualDedupe::open(5, &list).unwrap();
+        assert_eq!(dedupe.find_or_insert(hash_file(&gradient).unwrap(), &gradient), None);
+        assert_eq!(dedupe.find_or_insert(hash_file(&checkers).unwrap(), &checkers), None);
+        assert_eq!(
+            dedupe.find_or_insert(hash_file(&reencoded).unwrap(), &reencoded),
+            Some(gradient.clone())
+        );
+        assert!(hash_file(dir.path().join("missing.png").to_str().unwrap()).is_none());
+
+        dedupe.record_removed("0123abcd", &gradient);
+        assert!(dedupe.was_removed("0123abcd"));
+        drop(dedupe);
+        // the next run skips the media deleted by this one
+        let dedupe = PerceptualDedupe::open(5, &list).unwrap();
+        assert!(dedupe.was_removed("0123abcd"));
+        assert!(!dedupe.was_removed("4567ef01"));
+    }
+}
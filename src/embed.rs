@@ -0,0 +1,112 @@
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+
+use chrono::DateTime;
+use little_exif::exif_tag::ExifTag;
+use little_exif::metadata::Metadata;
+use log::debug;
+
+use crate::errors::GertError;
+use crate::structs::Post;
+
+/// Details of the post a media file was downloaded from, written into the file itself
+#[derive(Debug, Clone)]
+pub struct EmbeddedMetadata {
+    pub title: String,
+    pub author: String,
+    pub permalink: String,
+    /// Creation time of the post in UTC, if reddit returned one
+    pub created_utc: Option<i64>,
+    /// Caption of the gallery item the file belongs to
+    pub caption: Option<String>,
+    /// Link attached to the gallery item the file belongs to
+    pub outbound_url: Option<String>,
+}
+
+impl EmbeddedMetadata {
+    pub fn from_post(post: &Post) -> EmbeddedMetadata {
+        EmbeddedMetadata {
+            title: post.data.title.clone().unwrap_or_default(),
+            author: post.data.author.clone().unwrap_or_default(),
+            permalink: format!("https://www.reddit.com{}", post.data.permalink),
+            created_utc: post.data.created_utc_secs(),
+            caption: None,
+            outbound_url: None,
+        }
+    }
+
+    /// The title of the post followed by the caption and link of the gallery item, if any
+    fn description(&self) -> String {
+        let mut lines = vec![self.title.as_str()];
+        lines.extend(self.caption.as_deref());
+        lines.extend(self.outbound_url.as_deref());
+        lines.join("\n")
+    }
+
+    /// Format the creation time with the given strftime format
+    fn date(&self, format: &str) -> Option<String> {
+        let date = DateTime::from_timestamp(self.created_utc?, 0)?;
+        Some(date.format(format).to_string())
+    }
+}
+
+/// Write the post details into the EXIF data of a jpg or png image
+pub fn embed_image_metadata(path: &str, metadata: &EmbeddedMetadata) -> Result<(), GertError> {
+    let path = Path::new(path);
+    let mut exif = Metadata::new_from_path(path)?;
+    exif.set_tag(ExifTag::ImageDescription(metadata.description()));
+    exif.set_tag(ExifTag::Artist(metadata.author.clone()));
+    exif.set_tag(ExifTag::Copyright(metadata.permalink.clone()));
+    if let Some(date) = metadata.date("%Y:%m:%d %H:%M:%S") {
+        exif.set_tag(ExifTag::DateTimeOriginal(date));
+    }
+    exif.write_to_file(path)?;
+    debug!("Embedded post metadata into {}", path.display());
+    Ok(())
+}
+
+/// Write the post details into the metadata tags of an mp4 with ffmpeg
+pub async fn embed_video_metadata(
+    path: &str,
+    metadata: &EmbeddedMetadata,
+) -> Result<(), GertError> {
+    // only the file name changes, the directories may hold `.mp4` too
+    let file = Path::new(path);
+    let stem = file.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let extension = file.extension().and_then(|ext| ext.to_str()).unwrap_or("mp4");
+    let output_file = file.with_file_name(format!("{}-metadata.{}", stem, extension));
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command
+        .arg("-i")
+        .arg(path)
+        .arg("-map")
+        .arg("0")
+        .arg("-c")
+        .arg("copy")
+        .arg("-metadata")
+        .arg(format!("title={}", metadata.title))
+        .arg("-metadata")
+        .arg(format!("artist={}", metadata.author))
+        .arg("-metadata")
+        .arg(format!("comment={}", metadata.permalink));
+    if let Some(date) = metadata.date("%Y-%m-%dT%H:%M:%SZ") {
+        command.arg("-metadata").arg(format!("creation_time={}", date));
+    }
+    let status = command
+        .arg(&output_file)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?
+        .wait()
+        .await?;
+
+    if status.success() {
+        fs::rename(&output_file, path)?;
+        debug!("Embedded post metadata into {}", path);
+        Ok(())
+    } else {
+        let _ = fs::remove_file(output_file);
+        Err(GertError::FfmpegError("Failed to embed metadata".into()))
+    }
+}
@@ -0,0 +1,101 @@
+use std::env;
+
+/// Where reddit listings and posts are fetched from and where the access token is requested
+pub static REDDIT_URL: &str = "https://www.reddit.com";
+/// All API requests that use a bearer token should be made to this host
+pub static REDDIT_OAUTH_URL: &str = "https://oauth.reddit.com";
+static REDGIFS_API: &str = "https://api.redgifs.com/v2";
+static GIPHY_API: &str = "https://api.giphy.com/v1/gifs";
+static TWITTER_SYNDICATION_API: &str = "https://cdn.syndication.twimg.com/tweet-result";
+static STREAMABLE_API: &str = "https://api.streamable.com/videos";
+static IMGUR_URL: &str = "https://imgur.com";
+static IMGUR_API: &str = "https://api.imgur.com/3";
+
+/// Base URLs of the services gert talks to. Each one can be replaced through an environment
+/// variable, to point gert at a mirror or at a mock server in tests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    /// $GERT_REDDIT_URL
+    pub reddit: String,
+    /// $GERT_REDDIT_OAUTH_URL
+    pub reddit_oauth: String,
+    /// $GERT_REDGIFS_API
+    pub redgifs_api: String,
+    /// $GERT_GIPHY_API
+    pub giphy_api: String,
+    /// $GERT_TWITTER_SYNDICATION_API
+    pub twitter_syndication_api: String,
+    /// $GERT_STREAMABLE_API
+    pub streamable_api: String,
+    /// $GERT_IMGUR_URL, where imgur links without an extension are probed
+    pub imgur: String,
+    /// $GERT_IMGUR_API
+    pub imgur_api: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            reddit: String::from(REDDIT_URL),
+            reddit_oauth: String::from(REDDIT_OAUTH_URL),
+            redgifs_api: String::from(REDGIFS_API),
+            giphy_api: String::from(GIPHY_API),
+            twitter_syndication_api: String::from(TWITTER_SYNDICATION_API),
+            streamable_api: String::from(STREAMABLE_API),
+            imgur: String::from(IMGUR_URL),
+            imgur_api: String::from(IMGUR_API),
+        }
+    }
+}
+
+impl Endpoints {
+    /// The default endpoints with the ones set in the environment replaced
+    pub fn from_env() -> Self {
+        Endpoints::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup<F: Fn(&str) -> Option<String>>(lookup: F) -> Self {
+        let defaults = Endpoints::default();
+        let endpoint = |key: &str, default: String| match lookup(key) {
+            Some(url) if !url.trim().is_empty() => url.trim().trim_end_matches('/').to_owned(),
+            _ => default,
+        };
+        Endpoints {
+            reddit: endpoint("GERT_REDDIT_URL", defaults.reddit),
+            reddit_oauth: endpoint("GERT_REDDIT_OAUTH_URL", defaults.reddit_oauth),
+            redgifs_api: endpoint("GERT_REDGIFS_API", defaults.redgifs_api),
+            giphy_api: endpoint("GERT_GIPHY_API", defaults.giphy_api),
+            twitter_syndication_api: endpoint(
+                "GERT_TWITTER_SYNDICATION_API",
+                defaults.twitter_syndication_api,
+            ),
+            streamable_api: endpoint("GERT_STREAMABLE_API", defaults.streamable_api),
+            imgur: endpoint("GERT_IMGUR_URL", defaults.imgur),
+            imgur_api: endpoint("GERT_IMGUR_API", defaults.imgur_api),
+        }
+    }
+
+    /// Whether any endpoint differs from the services gert talks to by default
+    pub fn is_overridden(&self) -> bool {
+        *self != Endpoints::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoints_from_lookup() {
+        assert_eq!(Endpoints::from_lookup(|_| None), Endpoints::default());
+
+        let endpoints = Endpoints::from_lookup(|key| match key {
+            "GERT_REDDIT_URL" => Some(String::from("http://127.0.0.1:8080/")),
+            "GERT_STREAMABLE_API" => Some(String::from("  ")),
+            _ => None,
+        });
+        assert_eq!(endpoints.reddit, "http://127.0.0.1:8080");
+        assert_eq!(endpoints.streamable_api, STREAMABLE_API);
+        assert!(endpoints.is_overridden());
+    }
+}
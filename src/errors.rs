@@ -23,6 +23,14 @@ pub enum GertError {
     TryFromIntError(#[from] std::num::TryFromIntError),
     #[error("Data directory not found, please check if it exists")]
     DataDirNotFound,
+    #[error("Output path `{0}` is a file, please pass a directory")]
+    DataDirNotADirectory(String),
+    #[error("Can not write to the output directory `{0}`, please check its permissions")]
+    DataDirNotWritable(String),
+    #[error(
+        "Another gert run is saving media to `{0}`, pass --force-lock if that is not the case"
+    )]
+    DataDirLocked(String),
     #[error("Could not create or save image")]
     IoError(#[from] std::io::Error),
     #[error("Unable to parse URL")]
@@ -39,4 +47,76 @@ pub enum GertError {
     ZipError(#[from] zip::result::ZipError),
     #[error("Media has been removed from imgur")]
     ImgurRemovedError,
+    #[error("Download of {0} was cut short, received {1} of {2} bytes")]
+    IncompleteDownload(String, u64, u64),
+    #[error("Invalid URL `{0}`")]
+    InvalidUrl(String),
+    #[error("Could not find a post at {0}")]
+    PostNotFound(String),
+    #[error("Post at {0} contains no media")]
+    NoMediaFound(String),
+    #[error("Could not find comment `{0}` in the thread")]
+    CommentNotFound(String),
+    #[error(
+        "{0} is quarantined, reddit only lists its posts to accounts that opted in to view it"
+    )]
+    Quarantined(String),
+    #[error(
+        "{0} is marked over 18, reddit only lists its posts after confirming being over 18. \
+        Pass --over18 to confirm it"
+    )]
+    Over18Required(String),
+    #[error("Reddit refused to list the posts of {0}, it may be private or banned")]
+    Forbidden(String),
+    #[error("Profile `{0}` not found in the environment file")]
+    ProfileNotFound(String),
+    #[error("Could not log in to Reddit: {0}")]
+    LoginFailed(String),
+    #[error("{0} posts link to media gert does not support")]
+    UnsupportedPosts(usize),
+    #[error("No saved_posts.csv or post_votes.csv in `{0}`, is it a reddit data export?")]
+    ExportNotFound(String),
+    #[error("Could not write to the manifest database")]
+    SqliteError(#[from] rusqlite::Error),
+}
+
+impl GertError {
+    /// Stable identifier of the error for scripts, printed with --error-format json
+    pub fn code(&self) -> &'static str {
+        match self {
+            GertError::EnvVarNotPresent(_) => "env_var_not_present",
+            GertError::ReqwestError(_) => "request_failed",
+            GertError::CouldNotCreateDirectory => "could_not_create_directory",
+            GertError::CouldNotSaveImageError(_) => "could_not_save_image",
+            GertError::CouldNotCreateImageError(_, _) => "could_not_create_image",
+            GertError::TokioJoinError(_) => "task_join_failed",
+            GertError::ParsingIntError(_) => "int_parse_failed",
+            GertError::TryFromIntError(_) => "int_conversion_failed",
+            GertError::DataDirNotFound => "data_dir_not_found",
+            GertError::DataDirNotADirectory(_) => "data_dir_not_a_directory",
+            GertError::DataDirNotWritable(_) => "data_dir_not_writable",
+            GertError::DataDirLocked(_) => "data_dir_locked",
+            GertError::IoError(_) => "io_error",
+            GertError::UrlError(_) => "url_parse_failed",
+            GertError::ToStringConversionError(_) => "to_string_conversion_failed",
+            GertError::FromStringConversionError(_) => "from_string_conversion_failed",
+            GertError::JsonParseError(_) => "json_parse_failed",
+            GertError::FfmpegError(_) => "ffmpeg_failed",
+            GertError::ZipError(_) => "zip_failed",
+            GertError::ImgurRemovedError => "imgur_removed",
+            GertError::IncompleteDownload(_, _, _) => "incomplete_download",
+            GertError::InvalidUrl(_) => "invalid_url",
+            GertError::PostNotFound(_) => "post_not_found",
+            GertError::NoMediaFound(_) => "no_media_found",
+            GertError::CommentNotFound(_) => "comment_not_found",
+            GertError::Quarantined(_) => "quarantined",
+            GertError::Over18Required(_) => "over18_required",
+            GertError::Forbidden(_) => "forbidden",
+            GertError::ProfileNotFound(_) => "profile_not_found",
+            GertError::LoginFailed(_) => "login_failed",
+            GertError::UnsupportedPosts(_) => "unsupported_posts",
+            GertError::ExportNotFound(_) => "export_not_found",
+            GertError::SqliteError(_) => "sqlite_failed",
+        }
+    }
 }
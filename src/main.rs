@@ -1,26 +1,49 @@
+use std::collections::HashSet;
 use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
 
-use clap::{crate_version, App, Arg};
-use env_logger::Env;
-use log::{debug, info, warn};
+use chrono::{DateTime, Utc};
+use clap::{crate_version, App, Arg, ArgMatches};
+use env_logger::{Env, WriteStyle};
+use log::{debug, error, info, warn};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 
 use auth::Client;
 
-use crate::download::Downloader;
+use crate::download::{
+    DownloadOptions, Downloader, EventLog, ImageResolution, Manifest, MediaType, MergeFormat,
+    OnExists, PerceptualDedupe, PostExport, VideoCodec, IMAGE_RESOLUTIONS, MAX_TITLE_LENGTH,
+    MERGE_FORMATS, NEAR_DUPLICATES_FILE, ON_EXISTS_POLICIES, REDDIT_VIDEO_RESOLUTIONS,
+    VIDEO_CODECS,
+};
+use crate::endpoints::Endpoints;
 use crate::errors::GertError;
-use crate::errors::GertError::DataDirNotFound;
-use crate::structs::{Post, SingleListing};
+use crate::ratelimit::{HostRateLimiter, RateLimiter};
+use crate::state::SinceState;
+use crate::structs::{CommentThread, Post, SingleListing};
 use crate::subreddit::Subreddit;
 use crate::user::User;
 use crate::utils::*;
+use crate::verify::{dedupe_report, verify_directory};
 
 mod auth;
 mod download;
+mod embed;
+mod endpoints;
 mod errors;
+mod ratelimit;
+mod state;
 mod structs;
 mod subreddit;
 mod user;
 mod utils;
+mod verify;
 
 fn exit(msg: &str) -> ! {
     let err = clap::Error::with_description(msg, clap::ErrorKind::InvalidValue);
@@ -28,7 +51,7 @@ fn exit(msg: &str) -> ! {
 }
 
 #[tokio::main]
-async fn main() -> Result<(), GertError> {
+async fn main() {
     let matches = App::new("Gert")
         .version(crate_version!())
         .author("Mike Dallas")
@@ -36,10 +59,10 @@ async fn main() -> Result<(), GertError> {
         .arg(
             Arg::with_name("url")
                 .value_name("URL")
-                .help("URL of a single post to download")
+                .help("URL of a single post, subreddit or user to download")
                 .takes_value(true)
-                .required_unless("subreddit")
-                .conflicts_with_all(&["subreddit", "period", "feed", "limit", "match", "upvotes"]),
+                .required_unless_one(&["subreddits", "user_comments", "my_subs", "json_stream", "ids_from", "from_reddit_export", "retry_failed", "verify", "dedupe_report"])
+                .conflicts_with_all(&["subreddit", "period", "feed"]),
         )
         .arg(
             Arg::with_name("environment")
@@ -49,6 +72,62 @@ async fn main() -> Result<(), GertError> {
                 .help("Set a custom .env style file with secrets")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("PROFILE")
+                .help("Use the credentials of this profile from the environment file")
+                .takes_value(true)
+                .requires("environment"),
+        )
+        .arg(
+            Arg::with_name("oauth_scope")
+                .long("oauth-scope")
+                .value_name("SCOPE")
+                .help("Scope of the access token requested when logging in")
+                .takes_value(true)
+                .default_value("*"),
+        )
+        .arg(
+            Arg::with_name("danger_accept_invalid_certs")
+                .long("danger-accept-invalid-certs")
+                .takes_value(false)
+                .help("DANGEROUS: do not verify TLS certificates, anyone on the network can read and alter the traffic. Prefer --ca-cert"),
+        )
+        .arg(
+            Arg::with_name("ca_cert")
+                .long("ca-cert")
+                .value_name("PATH")
+                .help("Trust the root certificate in this PEM file, eg: the one of a TLS intercepting proxy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pool_max_idle_per_host")
+                .long("pool-max-idle-per-host")
+                .value_name("NUM")
+                .help("Maximum number of idle connections kept open to each host for reuse, unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http2_prior_knowledge")
+                .long("http2-prior-knowledge")
+                .takes_value(false)
+                .help("Use HTTP/2 without negotiating it first. Requests to hosts that only speak HTTP/1 fail"),
+        )
+        .arg(
+            Arg::with_name("tcp_keepalive")
+                .long("tcp-keepalive")
+                .value_name("SECONDS")
+                .help("Send TCP keep-alive probes on open connections at this interval, so that idle connections stay usable")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("over18")
+                .long("over18")
+                .takes_value(false)
+                .conflicts_with("environment")
+                .help("Confirm being over 18 so that nsfw subreddits can be listed without logging in"),
+        )
         .arg(
             Arg::with_name("match")
                 .short("m")
@@ -57,6 +136,21 @@ async fn main() -> Result<(), GertError> {
                 .help("Pass a regular expresion to filter the title of the post")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("exclude_match")
+                .long("exclude-match")
+                .alias("title-blocklist")
+                .value_name("MATCH")
+                .help("Pass a regular expresion to skip the posts whose title matches it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flair")
+                .long("flair")
+                .value_name("FLAIR")
+                .help("Pass a regular expresion to filter the flair of the post")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("output_directory")
                 .short("o")
@@ -79,6 +173,33 @@ async fn main() -> Result<(), GertError> {
                 .takes_value(false)
                 .help("Dry run and print the URLs of saved media to download"),
         )
+        .arg(
+            Arg::with_name("estimate")
+                .long("estimate")
+                .takes_value(false)
+                .requires("dry_run")
+                .help("Add up the size of the media found by a dry run, with a HEAD request for each of them"),
+        )
+        .arg(
+            Arg::with_name("resolve_only")
+                .long("resolve-only")
+                .takes_value(false)
+                .conflicts_with("dry_run")
+                .help("Resolve and print the final URL of every media without saving them"),
+        )
+        .arg(
+            Arg::with_name("fetch_only")
+                .long("fetch-only")
+                .takes_value(false)
+                .conflicts_with_all(&["dry_run", "resolve_only"])
+                .help("Save the collected posts to posts.jsonl in the output directory without downloading any media, for --json-stream"),
+        )
+        .arg(
+            Arg::with_name("force_lock")
+                .long("force-lock")
+                .takes_value(false)
+                .help("Save media even if another gert run holds the lock of the output directory"),
+        )
         .arg(
             Arg::with_name("human_readable")
                 .short("H")
@@ -86,12 +207,38 @@ async fn main() -> Result<(), GertError> {
                 .takes_value(false)
                 .help("Use human readable names for files"),
         )
+        .arg(
+            Arg::with_name("name_by_post_id")
+                .long("name-by-post-id")
+                .takes_value(false)
+                .conflicts_with("human_readable")
+                .help("Name files after the id of their post instead of a hash of the media URL"),
+        )
+        .arg(
+            Arg::with_name("slugify")
+                .long("slugify")
+                .takes_value(false)
+                .help("Join the words of post titles with dashes in file and folder names"),
+        )
+        .arg(
+            Arg::with_name("title_case")
+                .long("title-case")
+                .takes_value(false)
+                .help("Capitalize the words of post titles in file and folder names"),
+        )
+        .arg(
+            Arg::with_name("max_title_length")
+                .long("max-title-length")
+                .value_name("NUM")
+                .help("Truncate post titles in file and folder names to this many characters [default: 200]")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("limit")
                 .short("l")
                 .long("limit")
                 .value_name("LIMIT")
-                .help("Limit the number of posts to download")
+                .help("Limit the number of posts to download, 0 for no limit")
                 .takes_value(true)
                 .default_value("25"),
         )
@@ -104,17 +251,38 @@ async fn main() -> Result<(), GertError> {
                 .value_delimiter(",")
                 .help("Download media from these subreddits")
                 .takes_value(true)
-                .required_unless("url")
+                .required_unless_one(&["url", "user_comments", "my_subs", "json_stream", "ids_from", "from_reddit_export", "retry_failed", "verify", "dedupe_report"])
                 .conflicts_with("url"),
         )
+        .arg(
+            Arg::with_name("my_subs")
+                .long("my-subs")
+                .alias("subreddit-from-user-subscriptions")
+                .takes_value(false)
+                .requires("environment")
+                .conflicts_with_all(&["url", "json_stream", "ids_from", "from_reddit_export", "retry_failed", "verify"])
+                .help("Download media from every subreddit the logged in user is subscribed to, besides the ones given with --subreddit"),
+        )
+        .arg(
+            Arg::with_name("user_comments")
+                .long("user-comments")
+                .multiple(true)
+                .value_name("USER")
+                .value_delimiter(",")
+                .help("Download the media linked in the comments of these users to <USER>/comments")
+                .takes_value(true)
+                .conflicts_with_all(&["url", "json_stream", "ids_from", "retry_failed", "verify"]),
+        )
         .arg(
             Arg::with_name("period")
                 .short("p")
                 .long("period")
+                .multiple(true)
                 .value_name("PERIOD")
-                .help("Time period to download from")
+                .value_delimiter(",")
+                .help("Time period(s) to download from")
                 .takes_value(true)
-                .possible_values(&["now", "hour", "day", "week", "month", "year", "all"])
+                .possible_values(&PERIODS)
                 .default_value("day"),
         )
         .arg(
@@ -124,18 +292,57 @@ async fn main() -> Result<(), GertError> {
                 .value_name("feed")
                 .help("Feed to download from")
                 .takes_value(true)
-                .possible_values(&["hot", "new", "top", "rising"])
+                .possible_values(&FEEDS)
                 .default_value("hot"),
         )
         .arg(
             Arg::with_name("upvotes")
                 .short("u")
                 .long("upvotes")
+                .alias("min-score")
+                .value_name("NUM")
+                .help("Minimum net score (upvotes minus downvotes, as reddit shows it) of the posts to download. Can be negative to include downvoted posts. Alias: --min-score")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("min_comments")
+                .long("min-comments")
                 .value_name("NUM")
-                .help("Minimum number of upvotes to download")
+                .help("Minimum number of comments to download")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("min_ratio")
+                .long("min-ratio")
+                .value_name("RATIO")
+                .help("Minimum ratio of upvotes to all votes to download, between 0.0 and 1.0")
                 .takes_value(true)
                 .default_value("0"),
         )
+        .arg(
+            Arg::with_name("max_posts_per_run")
+                .long("max-posts-per-run")
+                .value_name("NUM")
+                .help("Stop collecting posts once this many have been gathered across all sources, 0 for no limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shuffle")
+                .long("shuffle")
+                .takes_value(false)
+                .help("Download the collected posts in random order"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("NUM")
+                .help("Seed of the --shuffle order, to shuffle the same posts the same way")
+                .takes_value(true)
+                .requires("shuffle"),
+        )
         .arg(
             Arg::with_name("conserve_gifs")
                 .short("c")
@@ -144,45 +351,596 @@ async fn main() -> Result<(), GertError> {
                 .help("Disable gif to mp4 conversion")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("fix_extensions")
+                .long("fix-extensions")
+                .takes_value(false)
+                .help("Rename downloaded files whose content does not match their extension, eg: a png saved as .jpg"),
+        )
+        .arg(
+            Arg::with_name("audio_only")
+                .long("audio-only")
+                .takes_value(false)
+                .help("Only save the audio of reddit, streamable and redgifs videos, as m4a"),
+        )
+        .arg(
+            Arg::with_name("no_video_merge")
+                .long("no-video-merge")
+                .takes_value(false)
+                .conflicts_with("audio_only")
+                .help("Save the video and audio streams of reddit videos as separate _video.mp4 and _audio.m4a files instead of merging them with ffmpeg"),
+        )
+        .arg(
+            Arg::with_name("prefer_gif_variant")
+                .long("prefer-gif-variant")
+                .takes_value(false)
+                .help("Download the animated gif variant of reddit images when available"),
+        )
+        .arg(
+            Arg::with_name("prefer_reddit_mp4")
+                .long("prefer-reddit-mp4")
+                .takes_value(false)
+                .help("Download the mp4 reddit makes of i.redd.it gifs instead of the gif, no ffmpeg needed"),
+        )
+        .arg(
+            Arg::with_name("image_resolution")
+                .long("image-resolution")
+                .value_name("SIZE")
+                .help("Download a resized copy of reddit images from the post preview: the widest one, or the widest at most 1080 or 720 pixels wide")
+                .takes_value(true)
+                .possible_values(&IMAGE_RESOLUTIONS)
+                .default_value("source"),
+        )
+        .arg(
+            Arg::with_name("prefer_original")
+                .long("prefer-original")
+                .alias("prefer-original-quality")
+                .takes_value(false)
+                .help("Download the uploaded file of redgifs and streamable videos instead of the transcode when available, it can be much larger"),
+        )
+        .arg(
+            Arg::with_name("only_ext")
+                .long("only-ext")
+                .multiple(true)
+                .value_name("EXT")
+                .value_delimiter(",")
+                .help("Only download media with these extensions")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("skip_ext")
+                .long("skip-ext")
+                .multiple(true)
+                .value_name("EXT")
+                .value_delimiter(",")
+                .help("Do not download media with these extensions")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("video_quality")
+                .long("video-quality")
+                .value_name("HEIGHT")
+                .help("Highest reddit video resolution to look for when the video manifest is unavailable")
+                .takes_value(true)
+                .possible_values(&REDDIT_VIDEO_RESOLUTIONS),
+        )
+        .arg(
+            Arg::with_name("merge_format")
+                .long("merge-format")
+                .value_name("FORMAT")
+                .help("Container the video and audio of reddit videos are merged into, mkv keeps the Opus audio mp4 can not hold")
+                .takes_value(true)
+                .possible_values(&MERGE_FORMATS)
+                .default_value("mp4"),
+        )
+        .arg(
+            Arg::with_name("convert_to")
+                .long("convert-to")
+                .value_name("CODEC")
+                .help("Transcode the downloaded videos and gifs with ffmpeg to VP9 in webm, AV1 or H.265 in mp4, to save space")
+                .takes_value(true)
+                .possible_values(&VIDEO_CODECS),
+        )
+        .arg(
+            Arg::with_name("crf")
+                .long("crf")
+                .value_name("NUM")
+                .help("Quality of the --convert-to transcoding, lower is better and larger. Defaults to 32 for webm, 35 for av1 and 28 for h265")
+                .takes_value(true)
+                .requires("convert_to"),
+        )
+        .arg(
+            Arg::with_name("preset")
+                .long("preset")
+                .value_name("PRESET")
+                .help("Encoder preset of the --convert-to transcoding to av1 (0 to 13) or h265 (ultrafast to veryslow), trading speed for size")
+                .takes_value(true)
+                .requires("convert_to"),
+        )
+        .arg(
+            Arg::with_name("keep_original_after_transcode")
+                .long("keep-original-after-transcode")
+                .takes_value(false)
+                .requires("convert_to")
+                .help("Keep the downloaded files next to the ones --convert-to transcodes them to"),
+        )
+        .arg(
+            Arg::with_name("phash_dedupe")
+                .long("phash-dedupe")
+                .takes_value(false)
+                .help("Delete the downloaded images that look like another image of the run, even if resized or re-encoded, and list them in .near-duplicates so that later runs skip them"),
+        )
+        .arg(
+            Arg::with_name("phash_threshold")
+                .long("phash-threshold")
+                .value_name("BITS")
+                .help("Maximum number of differing bits between the perceptual hashes of two images --phash-dedupe considers the same. Defaults to 5")
+                .takes_value(true)
+                .requires("phash_dedupe"),
+        )
+        .arg(
+            Arg::with_name("min_duration")
+                .long("min-duration")
+                .value_name("SECONDS")
+                .help("Skip reddit and streamable videos shorter than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_duration")
+                .long("max-duration")
+                .value_name("SECONDS")
+                .help("Skip reddit and streamable videos longer than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("domain")
+                .long("domain")
+                .multiple(true)
+                .value_name("DOMAIN")
+                .value_delimiter(",")
+                .help("Only download posts linking to these domains")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude_domain")
+                .long("exclude-domain")
+                .multiple(true)
+                .value_name("DOMAIN")
+                .value_delimiter(",")
+                .help("Do not download posts linking to these domains")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gallery_subfolders")
+                .long("organize-galleries-in-subfolders")
+                .takes_value(false)
+                .help("Save the items of each gallery in their own folder"),
+        )
+        .arg(
+            Arg::with_name("gallery_index")
+                .long("include-gallery-index-json")
+                .takes_value(false)
+                .help("Write a <id>_<title>.json next to each gallery listing its items in order with their captions, links and files"),
+        )
+        .arg(
+            Arg::with_name("flatten_single_galleries")
+                .long("flatten-single-galleries")
+                .takes_value(false)
+                .help("Save galleries with a single item like a single image, without an index"),
+        )
+        .arg(
+            Arg::with_name("dedupe_across_galleries")
+                .long("dedupe-across-galleries")
+                .takes_value(false)
+                .help("Skip reddit images already downloaded as part of another post or gallery"),
+        )
+        .arg(
+            Arg::with_name("follow_crossposts_to_source")
+                .long("follow-crossposts-to-source")
+                .takes_value(false)
+                .help("Download reddit video crossposts using the media of the original post"),
+        )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .help("Colorize the log output, NO_COLOR disables it in auto mode")
+                .takes_value(true)
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("error_format")
+                .long("error-format")
+                .value_name("FORMAT")
+                .help("Print fatal errors as text or as a JSON object with a code and a message")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("no_color")
+                .long("no-color")
+                .takes_value(false)
+                .help("Do not colorize the log output, same as --color never"),
+        )
+        .arg(
+            Arg::with_name("write_urls")
+                .long("write-urls")
+                .takes_value(false)
+                .help("List the reddit permalink of every saved file in a urls.txt in its subreddit folder"),
+        )
+        .arg(
+            Arg::with_name("per_subreddit_report")
+                .long("per-subreddit-report")
+                .takes_value(false)
+                .help("Break the summary down per subreddit"),
+        )
+        .arg(
+            Arg::with_name("embed_metadata")
+                .long("embed-metadata")
+                .takes_value(false)
+                .help("Write the post title, author, link and date into the downloaded files"),
+        )
+        .arg(
+            Arg::with_name("rename_on_collision")
+                .long("rename-on-collision")
+                .takes_value(false)
+                .conflicts_with("on_exists")
+                .help("Add a numeric suffix when a file name is already taken by the media of another URL, same as --on-exists rename"),
+        )
+        .arg(
+            Arg::with_name("on_exists")
+                .long("on-exists")
+                .value_name("POLICY")
+                .help("What to do when a file already exists: keep it, replace it, save the media under a numbered name or replace it when the server has a newer version [default: skip]")
+                .takes_value(true)
+                .possible_values(&ON_EXISTS_POLICIES),
+        )
+        .arg(
+            Arg::with_name("replace_corrupt")
+                .long("replace-existing-if-corrupt")
+                .takes_value(false)
+                .help("Download existing files again when they are only a few bytes long or an HTML error page"),
+        )
+        .arg(
+            Arg::with_name("sleep_between")
+                .long("sleep-between")
+                .value_name("MS")
+                .help("Milliseconds to wait between starting the download of each post")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("requests_per_minute")
+                .long("requests-per-minute")
+                .value_name("NUM")
+                .help("Maximum number of requests per minute to the reddit API, 0 to disable [default: 60 or 100 if logged in]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("limit_rate_per_host")
+                .long("limit-rate-per-host")
+                .value_name("HOST=NUM")
+                .help("Override the requests per minute to some media hosts, 0 to disable. * sets the limit of the hosts gert has no default for")
+                .takes_value(true)
+                .multiple(true)
+                .min_values(0)
+                .value_delimiter(","),
+        )
+        .arg(
+            Arg::with_name("giphy_api_key")
+                .long("giphy-api-key")
+                .value_name("KEY")
+                .help("Giphy API key used to resolve giphy links, defaults to $GIPHY_API_KEY")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("imgur_client_id")
+                .long("imgur-client-id")
+                .value_name("ID")
+                .help("Imgur API client id used to download the images of albums whose zip is corrupt, defaults to $IMGUR_CLIENT_ID")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("enable_twitter")
+                .long("enable-twitter")
+                .takes_value(false)
+                .help("Download the images and videos of tweets"),
+        )
+        .arg(
+            Arg::with_name("use_video_preview")
+                .long("use-video-preview")
+                .takes_value(false)
+                .help("Download the reddit hosted preview of videos on unsupported sites"),
+        )
+        .arg(
+            Arg::with_name("thumbnail_fallback")
+                .long("thumbnail-fallback")
+                .takes_value(false)
+                .help("Download the thumbnail of posts on unsupported sites, so that they leave a trace in the archive"),
+        )
+        .arg(
+            Arg::with_name("nitter_instance")
+                .long("nitter-instance")
+                .value_name("URL")
+                .help("Resolve tweets through this nitter instance instead of twitter")
+                .takes_value(true)
+                .requires("enable_twitter"),
+        )
+        .arg(
+            Arg::with_name("subreddit_dir_template")
+                .long("subreddit-dir-template")
+                .value_name("TEMPLATE")
+                .help("Name of the subreddit folders, eg: {date}_{subreddit}. Supports {subreddit}, {subreddit_lower}, {date}, {year} and {month}")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("webhook")
+                .long("webhook")
+                .value_name("URL")
+                .help("POST a summary of the run to this URL when it finishes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output_json_lines")
+                .long("output-json-lines")
+                .value_name("FILE")
+                .help("Append a JSON line to this file for every post classified and media started, saved, failed or skipped, - for stderr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output_manifest_sqlite")
+                .long("output-manifest-sqlite")
+                .value_name("FILE")
+                .help("Record every media saved or already on disk, with the id, subreddit, author, title, score and date of its post, in this SQLite database")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export_json")
+                .long("export-json")
+                .value_name("FILE")
+                .help("Write the data of every post processed to this file, one JSON object per line as the posts are processed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export_json_pretty")
+                .long("export-json-pretty")
+                .takes_value(false)
+                .requires("export_json")
+                .help("Write the --export-json file as a single pretty printed JSON array at the end of the run instead"),
+        )
+        .arg(
+            Arg::with_name("json_stream")
+                .long("json-stream")
+                .takes_value(false)
+                .conflicts_with_all(&["url", "subreddits"])
+                .help("Read posts as a JSON array or one JSON object per line from stdin"),
+        )
+        .arg(
+            Arg::with_name("ids_from")
+                .long("ids-from")
+                .value_name("FILE")
+                .help("Download the posts whose ids are listed in this file, separated by commas or newlines")
+                .takes_value(true)
+                .conflicts_with_all(&["url", "subreddits", "json_stream"]),
+        )
+        .arg(
+            Arg::with_name("from_reddit_export")
+                .long("from-reddit-export")
+                .value_name("DIR")
+                .help("Download the saved and upvoted posts listed in the saved_posts.csv and post_votes.csv of a reddit data export")
+                .takes_value(true)
+                .conflicts_with_all(&["url", "subreddits", "user_comments", "json_stream", "ids_from", "retry_failed"]),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .takes_value(false)
+                .help("List the links of the posts gert does not support at the end of the run and exit with an error if there are any"),
+        )
+        .arg(
+            Arg::with_name("failures_file")
+                .long("failures-file")
+                .value_name("FILE")
+                .help("Write the ids of the posts that failed to download to this file, for --retry-failed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_failed")
+                .long("retry-failed")
+                .value_name("FILE")
+                .help("Download again the posts listed in a --failures-file, the file is updated with the posts that fail again")
+                .takes_value(true)
+                .conflicts_with_all(&["url", "subreddits", "json_stream", "ids_from", "failures_file"]),
+        )
+        .arg(
+            Arg::with_name("since_file")
+                .long("since-file")
+                .value_name("FILE")
+                .help("Only collect the posts of each subreddit and user created after the newest post of the last run, which is kept in this file")
+                .takes_value(true)
+                .conflicts_with_all(&["url", "json_stream", "ids_from", "retry_failed"]),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .takes_value(false)
+                .conflicts_with_all(&["url", "subreddits", "json_stream", "ids_from", "retry_failed"])
+                .help("Check the media in the output directory for corrupt files and exit"),
+        )
+        .arg(
+            Arg::with_name("delete_corrupt")
+                .long("delete-corrupt")
+                .takes_value(false)
+                .requires("verify")
+                .help("Delete the corrupt files found by --verify"),
+        )
+        .arg(
+            Arg::with_name("dedupe_report")
+                .long("dedupe-report")
+                .value_name("FILE")
+                .takes_value(true)
+                .min_values(0)
+                .conflicts_with_all(&["url", "subreddits", "user_comments", "json_stream", "ids_from", "retry_failed", "verify"])
+                .help("List the media in the output directory that have the same contents, and the bytes removing the copies would free, then exit. Nothing is deleted. The groups are also written as JSON to FILE when given"),
+        )
         .get_matches();
 
+    let json_errors = matches.value_of("error_format") == Some("json");
+    if let Err(e) = run(&matches).await {
+        let cause = std::error::Error::source(&e).map(|source| source.to_string());
+        if json_errors {
+            let error =
+                serde_json::json!({ "code": e.code(), "message": e.to_string(), "cause": cause });
+            eprintln!("{}", error);
+        } else {
+            match cause {
+                Some(cause) => eprintln!("Error: {}: {}", e, cause),
+                None => eprintln!("Error: {}", e),
+            }
+        }
+        process::exit(1);
+    }
+}
+
+async fn run(matches: &ArgMatches<'_>) -> Result<(), GertError> {
     let env_file = matches.value_of("environment");
+    let profile = matches.value_of("profile");
+    let oauth_scope = matches.value_of("oauth_scope").unwrap();
+    let retry_failed = matches.value_of("retry_failed");
+    // the failures file has the same format as the --ids-from file
+    let ids_from = matches.value_of("ids_from").or(retry_failed);
+    let reddit_export = matches.value_of("from_reddit_export");
+    let failures_file = matches.value_of("failures_file").or(retry_failed);
+    let strict = matches.is_present("strict");
+    let my_subs = matches.is_present("my_subs");
+    let since_file = matches.value_of("since_file");
+    let over18 = matches.is_present("over18");
+    let accept_invalid_certs = matches.is_present("danger_accept_invalid_certs");
+    let ca_cert = matches.value_of("ca_cert");
+    let client_options = ClientOptions {
+        accept_invalid_certs,
+        ca_cert,
+        pool_max_idle_per_host: matches.value_of("pool_max_idle_per_host").map(|max| {
+            max.parse::<usize>()
+                .unwrap_or_else(|_| exit("Maximum idle connections must be a number"))
+        }),
+        http2_prior_knowledge: matches.is_present("http2_prior_knowledge"),
+        over18,
+        tcp_keepalive: matches.value_of("tcp_keepalive").map(|seconds| {
+            let seconds = seconds.parse::<u64>();
+            Duration::from_secs(seconds.unwrap_or_else(|_| exit("Keep-alive must be a number")))
+        }),
+    };
+    let endpoints = Endpoints::from_env();
     let data_directory = String::from(matches.value_of("output_directory").unwrap());
     // generate the URLs to download from without actually downloading the media
     let should_download = !matches.is_present("dry_run");
+    let resolve_only = matches.is_present("resolve_only");
+    let fetch_only = matches.is_present("fetch_only");
+    let estimate_size = matches.is_present("estimate");
     // check if ffmpeg is present for combining video streams
     let ffmpeg_available = application_present(String::from("ffmpeg"));
     // generate human readable file names instead of MD5 Hashed file names
     let use_human_readable = matches.is_present("human_readable");
-    // restrict downloads to these subreddits
+    let name_by_post_id: bool = matches.is_present("name_by_post_id");
+    let slugify: bool = matches.is_present("slugify");
+    let title_case: bool = matches.is_present("title_case");
+    let max_title_length: Option<usize> =
+        matches.value_of("max_title_length").map(|length| match length.parse::<usize>() {
+            Ok(length) if length > 0 => length,
+            _ => exit("Maximum title length must be a positive number"),
+        });
+    // net score, negative for posts with more downvotes than upvotes
     let upvotes = matches
         .value_of("upvotes")
         .unwrap()
         .parse::<i64>()
-        .unwrap_or_else(|_| exit("Upvotes must be a number"));
+        .unwrap_or_else(|_| exit("Minimum score must be a whole number, eg: 100 or -10"));
+    let min_comments = matches
+        .value_of("min_comments")
+        .unwrap()
+        .parse::<i64>()
+        .unwrap_or_else(|_| exit("Minimum comments must be a number"));
+    let min_ratio = matches
+        .value_of("min_ratio")
+        .unwrap()
+        .parse::<f64>()
+        .ok()
+        .filter(|ratio| (0.0..=1.0).contains(ratio))
+        .unwrap_or_else(|| exit("Minimum ratio must be a number between 0.0 and 1.0"));
+    // 0 lifts the cap, like --limit 0
+    let max_posts_per_run: Option<usize> = matches
+        .value_of("max_posts_per_run")
+        .map(|max| {
+            max.parse::<usize>().unwrap_or_else(|_| exit("Maximum posts per run must be a number"))
+        })
+        .filter(|max| *max > 0);
+    let shuffle: bool = matches.is_present("shuffle");
+    let seed: Option<u64> = matches
+        .value_of("seed")
+        .map(|seed| seed.parse::<u64>().unwrap_or_else(|_| exit("Seed must be a number")));
 
-    let subreddits: Vec<&str> = match matches.is_present("subreddits") {
-        true => matches.values_of("subreddits").unwrap().collect(),
+    let mut subreddits: Vec<String> = match matches.is_present("subreddits") {
+        true => matches.values_of("subreddits").unwrap().map(String::from).collect(),
+        false => Vec::new(),
+    };
+    let mut users: Vec<String> = Vec::new();
+    let user_comments: Vec<String> = match matches.is_present("user_comments") {
+        true => matches.values_of("user_comments").unwrap().map(String::from).collect(),
         false => Vec::new(),
     };
+    let mut periods: Vec<String> = matches.values_of("period").unwrap().map(String::from).collect();
+    let mut feed = String::from(matches.value_of("feed").unwrap());
+    let mut comment_id: Option<String> = None;
 
     let single_url = match matches.value_of("url") {
         Some(url) => {
-            let parsed = url.parse::<url::Url>();
-            if parsed.is_err() {
-                exit("Invalid URL");
+            let parsed =
+                url.parse::<url::Url>().map_err(|_| GertError::InvalidUrl(url.to_owned()))?;
+            // subreddit and user URLs are downloaded like the equivalent flags,
+            // taking the feed and period from the URL when present
+            match parse_reddit_url(&parsed) {
+                RedditUrl::Post => Some(parsed),
+                RedditUrl::Comment { id } => {
+                    comment_id = Some(id);
+                    Some(parsed)
+                }
+                RedditUrl::Gallery { id } => {
+                    let comments = format!("{}/comments/{}", endpoints.reddit, id);
+                    Some(
+                        comments
+                            .parse::<url::Url>()
+                            .map_err(|_| GertError::InvalidUrl(comments))?,
+                    )
+                }
+                RedditUrl::Subreddit { name, feed: url_feed, period } => {
+                    subreddits.push(name);
+                    feed = url_feed.unwrap_or(feed);
+                    periods = period.map(|p| vec![p]).unwrap_or(periods);
+                    None
+                }
+                RedditUrl::User { name, feed: url_feed, period } => {
+                    users.push(name);
+                    feed = url_feed.unwrap_or(feed);
+                    periods = period.map(|p| vec![p]).unwrap_or(periods);
+                    None
+                }
             }
-            Some(parsed.unwrap())
         }
         None => None,
     };
+    // these filters pick among the posts of subreddits and users
+    let post_filters = ["limit", "match", "upvotes"];
+    if single_url.is_some() && post_filters.iter().any(|arg| matches.occurrences_of(arg) > 0) {
+        exit("--limit, --match and --upvotes do not apply to the URL of a single post");
+    }
 
     let limit = match matches.value_of("limit").unwrap().parse::<u32>() {
         Ok(limit) => limit,
         Err(_) => exit("Limit must be a number"),
     };
-    let period = matches.value_of("period");
-    let feed = matches.value_of("feed").unwrap();
     let pattern = match matches.value_of("match") {
         Some(pattern) => match regex::Regex::new(pattern) {
             Ok(reg) => reg,
@@ -190,19 +948,162 @@ async fn main() -> Result<(), GertError> {
         },
         None => regex::Regex::new(".*").unwrap(),
     };
+    let exclude_pattern = match matches.value_of("exclude_match") {
+        Some(pattern) => match regex::Regex::new(pattern) {
+            Ok(reg) => Some(reg),
+            Err(_) => exit("Invalid exclude regex pattern"),
+        },
+        None => None,
+    };
+    let flair_pattern = match matches.value_of("flair") {
+        Some(pattern) => match regex::Regex::new(pattern) {
+            Ok(reg) => reg,
+            Err(_) => exit("Invalid flair regex pattern"),
+        },
+        None => regex::Regex::new(".*").unwrap(),
+    };
     let conserve_gifs: bool = matches.is_present("conserve_gifs");
+    let fix_extensions: bool = matches.is_present("fix_extensions");
+    let audio_only: bool = matches.is_present("audio_only");
+    let no_video_merge: bool = matches.is_present("no_video_merge");
+    let prefer_gif_variant: bool = matches.is_present("prefer_gif_variant");
+    let prefer_reddit_mp4: bool = matches.is_present("prefer_reddit_mp4");
+    let image_resolution = match matches.value_of("image_resolution").unwrap() {
+        "source" => ImageResolution::Source,
+        "max" => ImageResolution::Max,
+        width => ImageResolution::Width(width.parse().unwrap()),
+    };
+    let prefer_original: bool = matches.is_present("prefer_original");
+    let gallery_subfolders: bool = matches.is_present("gallery_subfolders");
+    let gallery_index: bool = matches.is_present("gallery_index");
+    let dedupe_across_galleries: bool = matches.is_present("dedupe_across_galleries");
+    // possible_values guarantees that the height is a number
+    let video_quality: Option<u32> =
+        matches.value_of("video_quality").map(|height| height.parse().unwrap());
+    let merge_format = match matches.value_of("merge_format").unwrap() {
+        "mkv" => MergeFormat::Mkv,
+        _ => MergeFormat::Mp4,
+    };
+    let convert_to = matches.value_of("convert_to").map(|codec| match codec {
+        "av1" => VideoCodec::Av1,
+        "h265" => VideoCodec::H265,
+        _ => VideoCodec::Webm,
+    });
+    let crf: Option<u32> = matches
+        .value_of("crf")
+        .map(|crf| crf.parse::<u32>().unwrap_or_else(|_| exit("CRF must be a number")));
+    let preset: Option<String> = matches.value_of("preset").map(String::from);
+    let keep_original_after_transcode = matches.is_present("keep_original_after_transcode");
+    let phash_threshold: Option<u32> = match matches.is_present("phash_dedupe") {
+        true => Some(matches.value_of("phash_threshold").map_or(5, |bits| {
+            bits.parse::<u32>()
+                .unwrap_or_else(|_| exit("Perceptual hash threshold must be a number"))
+        })),
+        false => None,
+    };
+    let min_duration: Option<u64> = matches.value_of("min_duration").map(|seconds| {
+        seconds.parse::<u64>().unwrap_or_else(|_| exit("Minimum duration must be a number"))
+    });
+    let max_duration: Option<u64> = matches.value_of("max_duration").map(|seconds| {
+        seconds.parse::<u64>().unwrap_or_else(|_| exit("Maximum duration must be a number"))
+    });
+    if let (Some(min), Some(max)) = (min_duration, max_duration) {
+        if min > max {
+            exit("Minimum duration can not be greater than the maximum duration");
+        }
+    }
+    let embed_metadata: bool = matches.is_present("embed_metadata");
+    let write_urls: bool = matches.is_present("write_urls");
+    let per_subreddit_report: bool = matches.is_present("per_subreddit_report");
+    let webhook: Option<&str> = matches.value_of("webhook");
+    let event_log: Option<&str> = matches.value_of("output_json_lines");
+    let export_json: Option<&str> = matches.value_of("export_json");
+    let manifest: Option<&str> = matches.value_of("output_manifest_sqlite");
+    let export_json_pretty: bool = matches.is_present("export_json_pretty");
+    // --rename-on-collision conflicts with --on-exists
+    let on_exists = match matches.value_of("on_exists") {
+        _ if matches.is_present("rename_on_collision") => OnExists::Rename,
+        Some("overwrite") => OnExists::Overwrite,
+        Some("rename") => OnExists::Rename,
+        Some("newer") => OnExists::Newer,
+        _ => OnExists::Skip,
+    };
+    let replace_corrupt: bool = matches.is_present("replace_corrupt");
+    let flatten_single_galleries: bool = matches.is_present("flatten_single_galleries");
+    let subreddit_dir_template: Option<String> =
+        matches.value_of("subreddit_dir_template").map(String::from);
+    let follow_crossposts: bool = matches.is_present("follow_crossposts_to_source");
+    let enable_twitter: bool = matches.is_present("enable_twitter");
+    let use_video_preview: bool = matches.is_present("use_video_preview");
+    let thumbnail_fallback: bool = matches.is_present("thumbnail_fallback");
+    let nitter_instance: Option<String> = matches.value_of("nitter_instance").map(String::from);
+    let giphy_api_key = match matches.value_of("giphy_api_key") {
+        Some(key) => Some(String::from(key)),
+        None => env::var("GIPHY_API_KEY").ok(),
+    };
+    let imgur_client_id = match matches.value_of("imgur_client_id") {
+        Some(id) => Some(String::from(id)),
+        None => env::var("IMGUR_CLIENT_ID").ok(),
+    };
+    // reddit allows 60 requests per minute to anonymous users and 100 when authenticated
+    let requests_per_minute = match matches.value_of("requests_per_minute") {
+        Some(rpm) => {
+            rpm.parse::<u32>().unwrap_or_else(|_| exit("Requests per minute must be a number"))
+        }
+        None if env_file.is_some() => 100,
+        None => 60,
+    };
+    let host_rate_limits = {
+        let values: Vec<&str> =
+            matches.values_of("limit_rate_per_host").map(|v| v.collect()).unwrap_or_default();
+        HostRateLimiter::parse_limits(&values).unwrap_or_else(|e| exit(&e))
+    };
+    let sleep_between = matches
+        .value_of("sleep_between")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|_| exit("Sleep between must be a number"));
+    // extensions are compared without the leading dot and case insensitively
+    let normalize_ext = |ext: &str| ext.trim_start_matches('.').to_lowercase();
+    let only_extensions: Option<Vec<String>> =
+        matches.values_of("only_ext").map(|exts| exts.map(normalize_ext).collect());
+    let skip_extensions: Vec<String> = match matches.values_of("skip_ext") {
+        Some(exts) => exts.map(normalize_ext).collect(),
+        None => Vec::new(),
+    };
+    let domains: Option<Vec<String>> =
+        matches.values_of("domain").map(|domains| domains.map(str::to_lowercase).collect());
+    let excluded_domains: Vec<String> = match matches.values_of("exclude_domain") {
+        Some(domains) => domains.map(str::to_lowercase).collect(),
+        None => Vec::new(),
+    };
+    let domain_allowed = |post: &Post| {
+        let domain = post.data.domain.as_deref().unwrap_or("").to_lowercase();
+        domains.as_ref().map_or(true, |domains| domains.contains(&domain))
+            && !excluded_domains.contains(&domain)
+    };
 
     // initialize logger for the app and set logging level to info if no environment variable present
     let env = Env::default().filter("RUST_LOG").default_filter_or("info");
-    env_logger::Builder::from_env(env).init();
+    // https://no-color.org: any non empty value of NO_COLOR disables colors
+    let no_color_env = env::var("NO_COLOR").map(|value| !value.is_empty()).unwrap_or(false);
+    let write_style = match matches.value_of("color").unwrap() {
+        _ if matches.is_present("no_color") => WriteStyle::Never,
+        "always" => WriteStyle::Always,
+        "never" => WriteStyle::Never,
+        _ if no_color_env => WriteStyle::Never,
+        _ => WriteStyle::Auto,
+    };
+    env_logger::Builder::from_env(env).write_style(write_style).init();
 
     // if the option is --debug, show the configuration and return immediately
     if matches.is_present("debug") {
         info!("Current configuration:");
         info!("ENVIRONMENT_FILE = {}", &env_file.unwrap_or("None"));
+        info!("PROFILE = {}", &profile.unwrap_or("None"));
         info!("DATA_DIRECTORY = {}", &data_directory);
         if let Some(envfile) = env_file {
-            let maybe_userenv = parse_env_file(envfile);
+            let maybe_userenv = load_user_env(envfile, profile);
             match maybe_userenv {
                 Ok(userenv) => {
                     info!("CLIENT_ID = {}", &userenv.client_id);
@@ -210,6 +1111,7 @@ async fn main() -> Result<(), GertError> {
                     info!("USERNAME = {}", &userenv.username);
                     info!("PASSWORD = {}", mask_sensitive(&userenv.password));
                     info!("USER_AGENT = {}", get_user_agent_string(&userenv.username));
+                    info!("OAUTH SCOPE = {}", oauth_scope);
                 }
                 Err(e) => {
                     warn!("Error parsing environment file: {}", e);
@@ -217,42 +1119,191 @@ async fn main() -> Result<(), GertError> {
             }
         } else {
             info!("USER_AGENT = {}", get_user_agent_string("anon"));
+            info!("OVER 18 = {}", over18);
         }
+        info!("ACCEPT INVALID CERTS = {}", accept_invalid_certs);
+        if let Some(path) = ca_cert {
+            info!("CA CERT = {}", path);
+        }
+        info!("POOL MAX IDLE PER HOST = {:?}", client_options.pool_max_idle_per_host);
+        info!("HTTP2 PRIOR KNOWLEDGE = {}", client_options.http2_prior_knowledge);
+        info!("TCP KEEPALIVE = {:?}", client_options.tcp_keepalive);
         info!("SUBREDDITS = {}", &subreddits.join(","));
+        info!("MY SUBS = {}", my_subs);
+        if let Some(path) = ids_from {
+            info!("IDS FROM = {}", path);
+        }
+        if let Some(path) = reddit_export {
+            info!("FROM REDDIT EXPORT = {}", path);
+        }
+        if let Some(path) = failures_file {
+            info!("FAILURES FILE = {}", path);
+        }
+        info!("STRICT = {}", strict);
+        if let Some(path) = since_file {
+            info!("SINCE FILE = {}", path);
+        }
+        info!("USERS = {}", &users.join(","));
+        info!("USER COMMENTS = {}", &user_comments.join(","));
         info!("FFMPEG AVAILABLE = {}", ffmpeg_available);
         info!("LIMIT = {}", limit);
-        info!("PERIOD = {}", period.unwrap());
+        info!("PERIOD = {}", periods.join(","));
         info!("FEED = {}", feed);
         info!("MATCH = {}", pattern.as_str());
+        if let Some(exclude) = &exclude_pattern {
+            info!("EXCLUDE MATCH = {}", exclude.as_str());
+        }
+        info!("FLAIR = {}", flair_pattern.as_str());
+        info!("MIN SCORE = {}", upvotes);
+        info!("MIN COMMENTS = {}", min_comments);
+        info!("MIN RATIO = {}", min_ratio);
+        if let Some(max) = max_posts_per_run {
+            info!("MAX POSTS PER RUN = {}", max);
+        }
+        info!("SHUFFLE = {}", shuffle);
+        if let Some(seed) = seed {
+            info!("SEED = {}", seed);
+        }
         info!("CONSERVE GIFS = {}", conserve_gifs);
+        info!("FIX EXTENSIONS = {}", fix_extensions);
+        info!("AUDIO ONLY = {}", audio_only);
+        info!("NO VIDEO MERGE = {}", no_video_merge);
+        info!("PREFER GIF VARIANT = {}", prefer_gif_variant);
+        info!("PREFER REDDIT MP4 = {}", prefer_reddit_mp4);
+        info!("IMAGE RESOLUTION = {:?}", image_resolution);
+        info!("PREFER ORIGINAL = {}", prefer_original);
+        if let Some(only) = &only_extensions {
+            info!("ONLY EXTENSIONS = {}", only.join(","));
+        }
+        info!("SKIP EXTENSIONS = {}", skip_extensions.join(","));
+        if let Some(domains) = &domains {
+            info!("DOMAINS = {}", domains.join(","));
+        }
+        info!("EXCLUDED DOMAINS = {}", excluded_domains.join(","));
+        info!("NAME BY POST ID = {}", name_by_post_id);
+        info!("SLUGIFY = {}", slugify);
+        info!("TITLE CASE = {}", title_case);
+        info!("MAX TITLE LENGTH = {}", max_title_length.unwrap_or(MAX_TITLE_LENGTH));
+        info!("GALLERY SUBFOLDERS = {}", gallery_subfolders);
+        info!("GALLERY INDEX JSON = {}", gallery_index);
+        info!("FLATTEN SINGLE GALLERIES = {}", flatten_single_galleries);
+        info!("DEDUPE ACROSS GALLERIES = {}", dedupe_across_galleries);
+        if let Some(height) = video_quality {
+            info!("VIDEO QUALITY = {}", height);
+        }
+        info!("MERGE FORMAT = {:?}", merge_format);
+        if let Some(codec) = convert_to {
+            info!("CONVERT TO = {:?}", codec);
+            info!("CRF = {:?}", crf);
+            info!("PRESET = {:?}", preset);
+            info!("KEEP ORIGINAL AFTER TRANSCODE = {}", keep_original_after_transcode);
+        }
+        if let Some(bits) = phash_threshold {
+            info!("PHASH THRESHOLD = {}", bits);
+        }
+        if let Some(seconds) = min_duration {
+            info!("MIN DURATION = {}s", seconds);
+        }
+        if let Some(seconds) = max_duration {
+            info!("MAX DURATION = {}s", seconds);
+        }
+        info!("RESOLVE ONLY = {}", resolve_only);
+        info!("FETCH ONLY = {}", fetch_only);
+        info!("ESTIMATE = {}", estimate_size);
+        info!("EMBED METADATA = {}", embed_metadata);
+        info!("WRITE URLS = {}", write_urls);
+        info!("PER SUBREDDIT REPORT = {}", per_subreddit_report);
+        info!("ON EXISTS = {:?}", on_exists);
+        info!("REPLACE EXISTING IF CORRUPT = {}", replace_corrupt);
+        if let Some(template) = &subreddit_dir_template {
+            info!("SUBREDDIT DIR TEMPLATE = {}", template);
+        }
+        info!("FOLLOW CROSSPOSTS TO SOURCE = {}", follow_crossposts);
+        info!("ENABLE TWITTER = {}", enable_twitter);
+        info!("USE VIDEO PREVIEW = {}", use_video_preview);
+        info!("THUMBNAIL FALLBACK = {}", thumbnail_fallback);
+        if let Some(instance) = &nitter_instance {
+            info!("NITTER INSTANCE = {}", instance);
+        }
+        if endpoints.is_overridden() {
+            info!("ENDPOINTS = {:?}", endpoints);
+        }
+        let masked_giphy_key = giphy_api_key.as_deref().map(mask_sensitive);
+        info!("GIPHY API KEY = {}", masked_giphy_key.unwrap_or_else(|| String::from("None")));
+        let masked_imgur_id = imgur_client_id.as_deref().map(mask_sensitive);
+        info!("IMGUR CLIENT ID = {}", masked_imgur_id.unwrap_or_else(|| String::from("None")));
+        info!("REQUESTS PER MINUTE = {}", requests_per_minute);
+        let mut limits: Vec<String> =
+            host_rate_limits.iter().map(|(host, rpm)| format!("{}={}", host, rpm)).collect();
+        limits.sort();
+        info!("LIMIT RATE PER HOST = {}", limits.join(","));
+        // webhook URLs embed the secret token that allows posting to them
+        info!("WEBHOOK = {}", webhook.map(mask_sensitive).unwrap_or_else(|| String::from("None")));
+        if let Some(path) = event_log {
+            info!("OUTPUT JSON LINES = {}", path);
+        }
+        if let Some(path) = manifest {
+            info!("OUTPUT MANIFEST SQLITE = {}", path);
+        }
+        if let Some(path) = export_json {
+            info!("EXPORT JSON = {}", path);
+            info!("EXPORT JSON PRETTY = {}", export_json_pretty);
+        }
+        info!("SLEEP BETWEEN = {}ms", sleep_between);
 
         return Ok(());
     }
 
+    if matches.is_present("verify") {
+        let delete_corrupt = matches.is_present("delete_corrupt");
+        check_data_directory(&data_directory, delete_corrupt)?;
+        return verify_directory(&data_directory, delete_corrupt);
+    }
+
+    if matches.is_present("dedupe_report") {
+        check_data_directory(&data_directory, false)?;
+        return dedupe_report(&data_directory, matches.value_of("dedupe_report"));
+    }
+
+    // nothing is written with --dry-run or --resolve-only
+    let writes_media = should_download && !resolve_only && !fetch_only;
+    check_data_directory(&data_directory, writes_media || fetch_only)?;
+    // held until the end of the run
+    let _lock = match writes_media {
+        true => Some(lock_data_directory(&data_directory, matches.is_present("force_lock"))?),
+        false => None,
+    };
+
+    if accept_invalid_certs {
+        warn!("TLS certificates are not verified, the connections to reddit and the media hosts are not secure");
+    }
+    let rate_limiter = RateLimiter::new(requests_per_minute);
     let session = match env_file {
         Some(envfile) => {
-            let user_env = parse_env_file(envfile)?;
+            let user_env = load_user_env(envfile, profile)?;
 
-            let client_sess = reqwest::Client::builder()
-                .cookie_store(true)
-                .user_agent(get_user_agent_string(&user_env.username))
-                .build()?;
+            let client_sess = build_client(&client_options, &user_env.username)?;
 
             let client = Client::new(
                 &user_env.client_id,
                 &user_env.client_secret,
                 &user_env.username,
                 &user_env.password,
+                oauth_scope,
                 &client_sess,
-            );
+            )
+            .with_base_urls(&endpoints.reddit, &endpoints.reddit_oauth)
+            .with_rate_limiter(&rate_limiter);
             // login to reddit using the credentials provided and get API bearer token
             let auth = client.login().await?;
 
             info!("Successfully logged in to Reddit as {}", user_env.username);
             debug!("Authentication details: {:#?}", auth);
+            // the token is refreshed when it expires or gets rejected
+            info!("Access token expires at {}", DateTime::<Utc>::from(auth.expires_at()));
 
             // get information about the user to display
-            let user = User::new(&auth, &user_env.username, &client_sess);
+            let user = User::new(&client, &user_env.username, &client_sess, &rate_limiter);
 
             let user_info = user.about().await?;
 
@@ -262,71 +1313,339 @@ async fn main() -> Result<(), GertError> {
             info!("Comment Karma: {:#?}", user_info.data.comment_karma);
             info!("Link Karma: {:#?}", user_info.data.link_karma);
 
+            if my_subs {
+                let (followed, profiles) = user.subscriptions().await?;
+                for name in followed {
+                    if !subreddits.contains(&name) {
+                        subreddits.push(name);
+                    }
+                }
+                users.extend(profiles);
+            }
+
             client_sess
         }
         None => {
             info!("No environment file provided, using default values");
-            reqwest::Client::builder()
-                .cookie_store(true)
-                .user_agent(get_user_agent_string("anon"))
-                .build()?
+            build_client(&client_options, "anon")?
         }
     };
 
-    if !check_path_present(&data_directory) {
-        return Err(DataDirNotFound);
+    if !ffmpeg_available && convert_to.is_some() {
+        exit("Ffmpeg needs to be installed to transcode videos with --convert-to");
     }
-
     if !ffmpeg_available {
-        warn!(
-            "No ffmpeg Installation available. \
-            Videos hosted by Reddit use separate video and audio streams. \
-            Ffmpeg needs be installed to combine the audio and video into a single mp4."
-        );
+        // the streams are not merged with --no-video-merge anyway
+        if !no_video_merge {
+            warn!(
+                "No ffmpeg Installation available. \
+                Videos hosted by Reddit use separate video and audio streams. \
+                Ffmpeg needs be installed to combine the audio and video into a single mp4."
+            );
+        }
+        if audio_only {
+            warn!("The audio of streamable and redgifs videos can not be extracted without ffmpeg");
+        }
     };
 
     info!("Starting data gathering from Reddit. This might take some time. Hold on....");
 
-    let mut posts: Vec<Post> = Vec::with_capacity(limit as usize * subreddits.len());
-    if let Some(url) = single_url {
-        let url = format!("{}.json", url);
-        let single_listing: SingleListing = match session.get(&url).send().await {
-            Ok(response) => response.json().await.map_err(|_| GertError::JsonParseError(url))?,
-            Err(_) => exit(&format!("Error fetching data from {}", &url)),
-        };
-
-        let post = single_listing.0.data.children.into_iter().next().unwrap();
-        if post.data.url.is_none() {
-            exit("Post contains no media")
-        }
-        posts.push(post);
-    } else {
-        for subreddit in &subreddits {
-            let subposts =
-                Subreddit::new(subreddit, &session).get_posts(feed, limit, period).await?;
+    let mut posts: Vec<Post> =
+        Vec::with_capacity(limit as usize * (subreddits.len() + users.len()) * periods.len());
+    // with --since-file, the state of the last run and the one saved at the end of this run
+    let mut since: Option<SinceState> = None;
+    let mut newest: Option<SinceState> = None;
+    if matches.is_present("json_stream") {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        // posts without a link can not contain any media
+        posts.extend(parse_posts(&input)?.into_iter().filter(|post| post.data.url.is_some()));
+        info!("Read {} posts from stdin", posts.len());
+    } else if let Some(path) = ids_from {
+        let ids = parse_post_ids(&fs::read_to_string(path)?);
+        let found = get_posts_by_id(&endpoints.reddit, &session, &rate_limiter, &ids).await?;
+        info!("Found {} of the {} posts listed in {}", found.len(), ids.len(), path);
+        posts.extend(found.into_iter().filter(|post| post.data.url.is_some()));
+    } else if let Some(path) = reddit_export {
+        let ids = read_reddit_export(Path::new(path))?;
+        let found = get_posts_by_id(&endpoints.reddit, &session, &rate_limiter, &ids).await?;
+        info!("Found {} of the {} posts listed in the export", found.len(), ids.len());
+        posts.extend(found.into_iter().filter(|post| post.data.url.is_some()));
+    } else if let Some(url) = single_url {
+        let mut url = url;
+        url.set_path(&format!("{}.json", url.path()));
+        // stops reddit from escaping the & in the media URLs as &amp;
+        url.query_pairs_mut().append_pair("raw_json", "1");
+        let url = url.to_string();
+        rate_limiter.acquire().await;
+        let response = session.get(&url).send().await?;
+        if let Some(comment_id) = comment_id {
+            // download the links found in the body of the comment
+            let thread: CommentThread =
+                response.json().await.map_err(|_| GertError::JsonParseError(url.clone()))?;
+            let post = match thread.0.data.children.into_iter().next() {
+                Some(post) => post,
+                None => return Err(GertError::PostNotFound(url)),
+            };
+            let comment = match thread.1.find(&comment_id) {
+                Some(comment) => comment,
+                None => return Err(GertError::CommentNotFound(comment_id)),
+            };
             posts.extend(
-                subposts
-                    .into_iter()
-                    .filter(|post| {
-                        post.data.url.is_some() && !post.data.is_self && post.data.score > upvotes
+                comment
+                    .urls()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, media_url)| {
+                        post.with_comment_media(&comment_id, media_url, index)
                     })
-                    .filter(|post| {
-                        pattern.is_match(post.data.title.as_ref().unwrap_or(&"".to_string()))
-                    }),
+                    .filter(|post| post.get_type() != MediaType::Unsupported),
             );
+            if posts.is_empty() {
+                return Err(GertError::NoMediaFound(url));
+            }
+        } else {
+            let single_listing: SingleListing =
+                response.json().await.map_err(|_| GertError::JsonParseError(url.clone()))?;
+
+            let post = match single_listing.0.data.children.into_iter().next() {
+                Some(post) => post,
+                None => return Err(GertError::PostNotFound(url)),
+            };
+            if post.data.url.is_none() {
+                return Err(GertError::NoMediaFound(url));
+            }
+            posts.push(post);
+        }
+    } else {
+        // the same post can show up under more than one period, only keep the first one
+        let mut seen: HashSet<String> = HashSet::new();
+        if let Some(path) = since_file {
+            since = Some(SinceState::load(Path::new(path))?);
+            newest = since.clone();
+        }
+        let sources: Vec<Subreddit> = subreddits
+            .iter()
+            .map(|name| Subreddit::new(name, &endpoints.reddit, &session, &rate_limiter))
+            .chain(
+                users
+                    .iter()
+                    .map(|name| Subreddit::user(name, &endpoints.reddit, &session, &rate_limiter)),
+            )
+            .collect();
+        'collect: for source in &sources {
+            for period in &periods {
+                let subposts = match source.get_posts(&feed, limit, Some(period.as_str())).await {
+                    Ok(subposts) => subposts,
+                    Err(
+                        e @ (GertError::Quarantined(_)
+                        | GertError::Over18Required(_)
+                        | GertError::Forbidden(_)),
+                    ) => {
+                        // the other periods of the source would fail the same way
+                        error!("{}", e);
+                        continue 'collect;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let source_name = source.display_name();
+                let collected = posts.len();
+                posts.extend(
+                    subposts
+                        .into_iter()
+                        .filter(|post| {
+                            // the text and polls of self posts are kept in posts.jsonl
+                            (fetch_only || (post.data.url.is_some() && !post.data.is_self))
+                                && post.data.score >= upvotes
+                                && post.data.num_comments.unwrap_or(0) >= min_comments
+                                && post.data.upvote_ratio.unwrap_or(1.0) >= min_ratio
+                        })
+                        .filter(|post| {
+                            let title = post.data.title.as_deref().unwrap_or("");
+                            pattern.is_match(title)
+                                && !exclude_pattern.as_ref().is_some_and(|ex| ex.is_match(title))
+                        })
+                        .filter(|post| {
+                            flair_pattern
+                                .is_match(post.data.link_flair_text.as_deref().unwrap_or(""))
+                        })
+                        .filter(|post| domain_allowed(post))
+                        .filter(|post| {
+                            since.as_ref().map_or(true, |since| {
+                                since.is_new(&source_name, post.data.created_utc_secs())
+                            })
+                        })
+                        .filter(|post| seen.insert(post.data.id.clone())),
+                );
+                let reached_max = max_posts_per_run.filter(|max| posts.len() >= *max);
+                if let Some(max) = reached_max {
+                    posts.truncate(max);
+                }
+                if let Some(newest) = newest.as_mut() {
+                    for post in posts.iter().skip(collected) {
+                        newest.record(&source_name, post.data.created_utc_secs());
+                    }
+                }
+                if let Some(max) = reached_max {
+                    info!("Reached the maximum of {} posts per run, stopped collecting", max);
+                    break 'collect;
+                }
+            }
+        }
+        for name in &user_comments {
+            let source = Subreddit::user_comments(name, &endpoints.reddit, &session, &rate_limiter);
+            let folder = format!("{}/comments", name);
+            for period in &periods {
+                let comments = match source.get_comments(&feed, limit, Some(period.as_str())).await
+                {
+                    Ok(comments) => comments,
+                    Err(e @ (GertError::Over18Required(_) | GertError::Forbidden(_))) => {
+                        error!("{}", e);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                // download the links found in the body of each comment
+                for comment in comments.iter().filter(|c| c.score.unwrap_or(0) >= upvotes) {
+                    let id = match &comment.id {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    let post = comment.to_post(&folder);
+                    posts.extend(
+                        comment
+                            .urls()
+                            .iter()
+                            .enumerate()
+                            .map(|(index, media_url)| post.with_comment_media(id, media_url, index))
+                            .filter(|post| post.get_type() != MediaType::Unsupported)
+                            .filter(|post| seen.insert(post.data.name.clone())),
+                    );
+                }
+            }
+        }
+        if let Some(max) = max_posts_per_run {
+            posts.truncate(max);
         }
     }
-    let mut downloader = Downloader::new(
-        posts,
-        &data_directory,
+    normalize_timestamps(&mut posts);
+    if follow_crossposts {
+        posts.iter_mut().for_each(Post::use_crosspost_parent_media);
+    }
+    if shuffle {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        posts.shuffle(&mut rng);
+    }
+    if fetch_only {
+        let path = Path::new(&data_directory).join(POSTS_FILE);
+        write_posts(&path, &posts)?;
+        info!("Saved {} posts to {}", posts.len(), path.display());
+        if let (Some(path), Some(newest)) = (since_file, &newest) {
+            newest.save(Path::new(path))?;
+        }
+        return Ok(());
+    }
+    let options = DownloadOptions {
+        data_directory,
         should_download,
+        estimate_size,
+        resolve_only,
         use_human_readable,
+        name_by_post_id,
+        slugify,
+        title_case,
+        max_title_length,
         ffmpeg_available,
-        session,
         conserve_gifs,
-    );
+        fix_extensions,
+        audio_only,
+        no_video_merge,
+        prefer_gif_variant,
+        prefer_reddit_mp4,
+        image_resolution,
+        prefer_original,
+        only_extensions,
+        skip_extensions,
+        gallery_subfolders,
+        gallery_index,
+        giphy_api_key,
+        imgur_client_id,
+        host_rate_limits,
+        dedupe_across_galleries,
+        video_quality,
+        merge_format,
+        convert_to,
+        crf,
+        preset,
+        keep_original_after_transcode,
+        min_duration,
+        max_duration,
+        enable_twitter,
+        use_video_preview,
+        thumbnail_fallback,
+        nitter_instance,
+        endpoints,
+        sleep_between,
+        embed_metadata,
+        write_urls,
+        per_subreddit_report,
+        on_exists,
+        replace_corrupt,
+        subreddit_dir_template,
+        flatten_single_galleries,
+    };
+    let mut downloader = Downloader::new(posts, session.clone(), options);
+    if let Some(path) = event_log {
+        downloader.set_event_log(EventLog::open(path)?);
+    }
+    if let Some(path) = manifest {
+        downloader.set_manifest(Manifest::open(path)?);
+    }
+    if let Some(threshold) = phash_threshold.filter(|_| writes_media) {
+        let path = Path::new(&data_directory).join(NEAR_DUPLICATES_FILE);
+        downloader.set_perceptual_dedupe(PerceptualDedupe::open(threshold, &path)?);
+    }
+    if let Some(path) = export_json {
+        downloader.set_export(PostExport::create(path, export_json_pretty)?);
+    }
 
-    downloader.run().await?;
+    let summary = downloader.run().await?;
+
+    // dry runs leave the state as it is, the posts they found have not been saved
+    if let (Some(path), Some(newest), true) = (since_file, &newest, writes_media) {
+        newest.save(Path::new(path))?;
+        debug!("Saved the time of the newest post of each source to {}", path);
+    }
+
+    if let Some(path) = failures_file {
+        let mut failures = summary.failed_posts.join("\n");
+        if !failures.is_empty() {
+            failures.push('\n');
+        }
+        fs::write(path, failures)?;
+        info!("Wrote the ids of {} failed posts to {}", summary.failed_posts.len(), path);
+    }
+
+    if let Some(webhook) = webhook {
+        // the media are already saved, a failing webhook should not fail the run
+        match send_webhook(&session, webhook, &summary).await {
+            Ok(_) => debug!("Sent the run summary to {}", mask_sensitive(webhook)),
+            Err(e) => {
+                warn!("Could not send the run summary to {}: {}", mask_sensitive(webhook), e)
+            }
+        }
+    }
+
+    if strict && !summary.unsupported_urls.is_empty() {
+        error!("Unsupported links:");
+        for url in summary.unsupported_urls.iter() {
+            error!("    {}", url);
+        }
+        return Err(GertError::UnsupportedPosts(summary.unsupported_urls.len()));
+    }
 
     Ok(())
 }
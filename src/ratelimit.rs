@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::{sleep, Instant};
+use url::Url;
+
+/// Maximum number of requests that can be made back to back after being idle
+const BURST: f64 = 10.0;
+
+/// Requests per minute to the media hosts gert knows, overridden by --limit-rate-per-host.
+/// `*` applies to each other host
+pub static DEFAULT_HOST_LIMITS: [(&str, u32); 7] = [
+    ("redd.it", 300),
+    ("reddit.com", 60),
+    ("imgur.com", 120),
+    ("redgifs.com", 120),
+    ("giphy.com", 300),
+    ("streamable.com", 120),
+    ("*", 120),
+];
+
+/// Token bucket shared by the requests to the reddit API so that gert stays under its
+/// limits instead of reacting to rate limit errors. Cloning it shares the bucket.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Option<Arc<AsyncMutex<Bucket>>>,
+    /// Tokens added to the bucket every second
+    refill_rate: f64,
+    /// Maximum number of tokens the bucket can hold
+    capacity: f64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `requests_per_minute` requests. Zero disables limiting.
+    pub fn new(requests_per_minute: u32) -> RateLimiter {
+        if requests_per_minute == 0 {
+            return RateLimiter { bucket: None, refill_rate: 0.0, capacity: 0.0 };
+        }
+        let capacity = BURST.min(requests_per_minute as f64);
+        let bucket = Bucket { tokens: capacity, last_refill: Instant::now() };
+        RateLimiter {
+            bucket: Some(Arc::new(AsyncMutex::new(bucket))),
+            refill_rate: requests_per_minute as f64 / 60.0,
+            capacity,
+        }
+    }
+
+    /// Wait until a request is allowed to be made
+    pub async fn acquire(&self) {
+        let bucket = match &self.bucket {
+            Some(bucket) => bucket,
+            None => return,
+        };
+        loop {
+            let wait = {
+                let mut bucket = bucket.lock().await;
+                let now = Instant::now();
+                let elapsed = (now - bucket.last_refill).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(self.capacity);
+                bucket.last_refill = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_rate)
+            };
+            debug!("Rate limit reached, waiting {:.2} seconds", wait.as_secs_f64());
+            sleep(wait).await;
+        }
+    }
+}
+
+/// A token bucket for each host media are downloaded from, so that a slow host does not
+/// hold back the downloads from the others. Subdomains share the bucket of their domain
+#[derive(Debug, Clone)]
+pub struct HostRateLimiter {
+    /// Requests per minute for each domain, see `DEFAULT_HOST_LIMITS`
+    limits: HashMap<String, u32>,
+    limiters: Arc<Mutex<HashMap<String, RateLimiter>>>,
+}
+
+impl HostRateLimiter {
+    pub fn new(limits: HashMap<String, u32>) -> HostRateLimiter {
+        HostRateLimiter { limits, limiters: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// The default limits with the ones given as `HOST=NUM` replaced
+    pub fn parse_limits(values: &[&str]) -> Result<HashMap<String, u32>, String> {
+        let mut limits: HashMap<String, u32> =
+            DEFAULT_HOST_LIMITS.iter().map(|(host, rpm)| (host.to_string(), *rpm)).collect();
+        for value in values {
+            let (host, rpm) = value
+                .split_once('=')
+                .ok_or_else(|| format!("Expected HOST=NUM, got `{}`", value))?;
+            let rpm = rpm
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("Requests per minute for {} must be a number", host))?;
+            let host = host.trim().trim_start_matches("www.").to_lowercase();
+            limits.insert(host, rpm);
+        }
+        Ok(limits)
+    }
+
+    /// The domain whose bucket requests to `host` use and its requests per minute. The
+    /// longest configured domain matching the host wins, other hosts get a bucket of their own
+    fn limit_for(&self, host: &str) -> (String, u32) {
+        let matching = self
+            .limits
+            .iter()
+            .filter(|(domain, _)| {
+                host == domain.as_str() || host.ends_with(&format!(".{}", domain))
+            })
+            .max_by_key(|(domain, _)| domain.len());
+        match matching {
+            Some((domain, rpm)) => (domain.clone(), *rpm),
+            None => (host.to_owned(), self.limits.get("*").copied().unwrap_or(0)),
+        }
+    }
+
+    /// Wait until a request to the host of `url` is allowed to be made
+    pub async fn acquire(&self, url: &str) {
+        let host = match Url::parse(url).ok().and_then(|url| url.host_str().map(String::from)) {
+            Some(host) => host.to_lowercase(),
+            None => return,
+        };
+        let (domain, rpm) = self.limit_for(&host);
+        let limiter = {
+            let mut limiters = self.limiters.lock().unwrap_or_else(|e| e.into_inner());
+            limiters.entry(domain).or_insert_with(|| RateLimiter::new(rpm)).clone()
+        };
+        limiter.acquire().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_limits() {
+        let limits = HostRateLimiter::parse_limits(&["i.imgur.com=600", "*=0"]).unwrap();
+        let limiter = HostRateLimiter::new(limits);
+        assert_eq!(limiter.limit_for("i.imgur.com"), (String::from("i.imgur.com"), 600));
+        assert_eq!(limiter.limit_for("imgur.com"), (String::from("imgur.com"), 120));
+        assert_eq!(limiter.limit_for("v.redd.it"), (String::from("redd.it"), 300));
+        assert_eq!(limiter.limit_for("example.com"), (String::from("example.com"), 0));
+        // a domain only matches whole labels
+        assert_eq!(limiter.limit_for("notimgur.com"), (String::from("notimgur.com"), 0));
+
+        assert!(HostRateLimiter::parse_limits(&["imgur.com"]).is_err());
+        assert!(HostRateLimiter::parse_limits(&["imgur.com=fast"]).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::GertError;
+
+/// Creation time of the newest post collected from each subreddit or user, kept between runs
+/// by --since-file so that each source only yields the posts created since its last run
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SinceState(BTreeMap<String, i64>);
+
+impl SinceState {
+    /// Read the state saved at `path`. A missing file is an empty state, as on the first run
+    pub fn load(path: &Path) -> Result<SinceState, GertError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SinceState::default()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&contents)
+            .map_err(|_| GertError::JsonParseError(path.display().to_string()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), GertError> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| GertError::JsonParseError(format!("{} ({})", path.display(), e)))?;
+        fs::write(path, contents + "\n")?;
+        Ok(())
+    }
+
+    /// Whether a post of `source` was created after the newest post of the last run. Posts
+    /// without a creation time are always new
+    pub fn is_new(&self, source: &str, created_utc: Option<i64>) -> bool {
+        match (self.0.get(source), created_utc) {
+            (Some(newest), Some(created)) => created > *newest,
+            _ => true,
+        }
+    }
+
+    /// Remember the post of `source` if it is the newest one so far
+    pub fn record(&mut self, source: &str, created_utc: Option<i64>) {
+        if let Some(created) = created_utc {
+            let newest = self.0.entry(source.to_owned()).or_insert(created);
+            *newest = (*newest).max(created);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_since_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("since.json");
+        let mut state = SinceState::load(&path).unwrap();
+        assert_eq!(state, SinceState::default());
+        assert!(state.is_new("r/pics", Some(1700000000)));
+
+        state.record("r/pics", Some(1700000000));
+        state.record("r/pics", Some(1600000000));
+        state.record("u/spez", None);
+        state.save(&path).unwrap();
+
+        let state = SinceState::load(&path).unwrap();
+        assert!(!state.is_new("r/pics", Some(1700000000)));
+        assert!(state.is_new("r/pics", Some(1700000001)));
+        assert!(state.is_new("r/pics", None));
+        assert!(state.is_new("u/spez", Some(1)));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(SinceState::load(&path).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::utils::has_extension;
+use crate::utils::{contains_any, decode_html_entities, has_extension, normalize_imgur_url};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use std::{collections::HashMap, ops::Add};
@@ -73,6 +73,121 @@ impl<'de> Deserialize<'de> for SingleListing {
     }
 }
 
+/// Listing of subreddits, eg: the subreddits a user is subscribed to
+#[derive(Deserialize, Debug)]
+pub struct SubredditListing {
+    pub data: SubredditListingData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubredditListingData {
+    pub after: Option<String>,
+    pub children: Vec<SubredditChild>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubredditChild {
+    pub data: SubredditData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubredditData {
+    /// Name of the subreddit without the r/ prefix, eg: pics. User profiles a user follows
+    /// are listed as u_<name>
+    pub display_name: String,
+}
+
+/// A post together with its comments, as returned for a comment permalink
+#[derive(Deserialize, Debug)]
+pub struct CommentThread(pub Listing, pub CommentListing);
+
+/// Listing of comments. Comments are missing most of the fields of posts, so they are
+/// deserialized separately and only with the fields gert needs
+#[derive(Deserialize, Debug)]
+pub struct CommentListing {
+    pub data: CommentListingData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommentListingData {
+    pub children: Vec<Comment>,
+    /// Cursor of the next page, only sent for the comment listings of users
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Comment {
+    /// t1 for comments, more for the "load more comments" placeholders
+    pub kind: String,
+    pub data: CommentData,
+}
+
+/// Every field is optional: placeholders, deleted comments and the different listings
+/// reddit returns comments in all leave out some of them
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CommentData {
+    pub id: Option<String>,
+    /// The markdown body of the comment
+    pub body: Option<String>,
+    /// Either an empty string or a listing of the replies to the comment
+    pub replies: Value,
+    pub subreddit: Option<String>,
+    pub author: Option<String>,
+    pub score: Option<i64>,
+    pub permalink: Option<String>,
+    /// Title of the post the comment was made on
+    pub link_title: Option<String>,
+    pub created_utc: Value,
+}
+
+impl CommentListing {
+    /// Find a comment by its id among these comments and their replies
+    pub fn find(self, id: &str) -> Option<CommentData> {
+        for comment in self.data.children {
+            if comment.data.id.as_deref() == Some(id) {
+                return Some(comment.data);
+            }
+            let replies = serde_json::from_value::<CommentListing>(comment.data.replies).ok();
+            if let Some(found) = replies.and_then(|replies| replies.find(id)) {
+                return Some(found);
+            }
+        }
+        None
+    }
+}
+
+impl CommentData {
+    /// The links in the body of the comment
+    pub fn urls(&self) -> Vec<String> {
+        let link = regex::Regex::new(r"https?://[^\s()\[\]<>]+").unwrap();
+        let body = decode_html_entities(self.body.as_deref().unwrap_or(""));
+        link.find_iter(&body).map(|m| m.as_str().trim_end_matches(['.', ',']).to_owned()).collect()
+    }
+
+    /// A post standing in for the comment, without media, whose media is saved to `folder`.
+    /// Give it the links of the comment with `Post::with_comment_media`
+    pub fn to_post(&self, folder: &str) -> Post {
+        let id = self.id.clone().unwrap_or_default();
+        Post {
+            kind: String::from("t1"),
+            data: PostData {
+                subreddit: folder.to_owned(),
+                name: format!("t1_{}", id),
+                id,
+                score: self.score.unwrap_or(0),
+                permalink: self.permalink.clone().unwrap_or_default(),
+                author: self.author.clone(),
+                title: self.link_title.clone(),
+                created: self.created_utc.clone(),
+                created_utc: self.created_utc.clone(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
 /// The contents of a call to a 'listing' endpoint.
 #[derive(Deserialize, Debug)]
 pub struct ListingData {
@@ -85,7 +200,7 @@ pub struct ListingData {
     pub dist: i32,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Post {
     /// The kind of object this is. eg: Comment, Account, Subreddit, etc.
     pub kind: String,
@@ -94,7 +209,7 @@ pub struct Post {
 }
 
 /// Represents all types of link posts and self posts on Reddit.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct PostData {
     pub subreddit: String,
     /// The ID of the post in base-36 form, as used in Reddit's links.
@@ -112,6 +227,8 @@ pub struct PostData {
     pub saved: bool,
     /// The permanent, long link for this submission.
     pub permalink: String,
+    /// The username of the author of the post, "[deleted]" if the account was deleted
+    pub author: Option<String>,
     /// The full 'Thing ID', consisting of a 'kind' and a base-36 identifier. The valid kinds are:
     /// - t1_ - Comment
     /// - t2_ - Account
@@ -126,10 +243,16 @@ pub struct PostData {
     pub created: Value,
     /// The linked URL, if this is a link post.
     pub url: Option<String>,
+    /// The linked URL when `url` points to the comments page of the post instead
+    pub url_overridden_by_dest: Option<String>,
     /// The title of the post.
     pub title: Option<String>,
     /// A timestamp of the time when the post was created, in **UTC**.
     pub created_utc: Value,
+    /// `created_utc` as seconds since the epoch, filled in by `normalize_created` when the
+    /// posts are collected
+    #[serde(skip)]
+    pub created_utc_seconds: Option<f64>,
     /// Media Metadata
     pub media_metadata: Option<HashMap<String, MediaMetadata>>,
     /// Gallery metadata
@@ -138,11 +261,100 @@ pub struct PostData {
     pub is_video: Option<bool>,
     /// Reddit Media info
     pub media: Option<PostMedia>,
+    /// Same as `media`, some posts only carry this one
+    pub secure_media: Option<PostMedia>,
+    /// Preview images generated by reddit
+    pub preview: Option<Preview>,
+    /// Number of comments on the post
+    pub num_comments: Option<i64>,
+    /// Fraction of the votes on the post that are upvotes, between 0 and 1
+    pub upvote_ratio: Option<f64>,
+    /// Why the post was removed, eg: "moderator", "deleted", "reddit". Null for live posts
+    pub removed_by_category: Option<String>,
+    /// The domain the post links to, eg: "i.redd.it", "imgur.com" or "self.aww" for self posts
+    pub domain: Option<String>,
+    /// The text of the link flair of the post
+    pub link_flair_text: Option<String>,
+    /// The posts this post is a crosspost of, the original post first
+    pub crosspost_parent_list: Option<Vec<CrosspostParent>>,
+    /// The markdown body of a self post, empty for link posts
+    pub selftext: Option<String>,
+    /// The options and votes of a poll post
+    pub poll_data: Option<PollData>,
 
     pub is_self: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl PostData {
+    /// The creation time of the post in seconds since the epoch (UTC). Reddit sends it
+    /// as an integer, a float or a string depending on the endpoint.
+    pub fn created_utc_secs(&self) -> Option<i64> {
+        if let Some(seconds) = self.created_utc_seconds {
+            return Some(seconds as i64);
+        }
+        match &self.created_utc {
+            Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+            Value::String(s) => s
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .or_else(|| s.trim().parse::<f64>().ok().map(|f| f as i64)),
+            _ => None,
+        }
+    }
+
+    /// Parse the creation time into `created_utc_seconds` once, so that every feature reads
+    /// the same timestamp. Falls back to `created` when `created_utc` can not be parsed.
+    /// Returns false when neither holds a number or a numeric string.
+    pub fn normalize_created(&mut self) -> bool {
+        let seconds = |value: &Value| match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        };
+        self.created_utc_seconds = seconds(&self.created_utc).or_else(|| seconds(&self.created));
+        self.created_utc_seconds.is_some()
+    }
+}
+
+/// A media file of a post, with the index it has among the media of the post
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedMedia {
+    pub url: String,
+    pub extension: String,
+    pub index: Option<usize>,
+    /// Reddit media id, shared by gallery items and i.redd.it images
+    pub media_id: Option<String>,
+    /// Caption of a gallery item
+    pub caption: Option<String>,
+    /// Link attached to a gallery item
+    pub outbound_url: Option<String>,
+}
+
+/// The parts of the original post of a crosspost that gert uses
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CrosspostParent {
+    pub media: Option<PostMedia>,
+}
+
+/// A poll attached to a post. Vote counts are only sent once voting has ended
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PollData {
+    #[serde(default)]
+    pub options: Vec<PollOption>,
+    pub total_vote_count: Option<i64>,
+    /// When voting ends, in milliseconds since the epoch
+    pub voting_end_timestamp: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PollOption {
+    pub id: String,
+    pub text: String,
+    pub vote_count: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MediaMetadata {
     pub status: String,
     pub e: String,
@@ -150,19 +362,61 @@ pub struct MediaMetadata {
     pub id: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PostMedia {
     pub reddit_video: Option<RedditVideo>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RedditVideo {
     pub fallback_url: String,
     pub is_gif: bool,
     pub dash_url: String,
+    /// Length of the video in seconds
+    pub duration: Option<i64>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Preview {
+    /// Previews of the images in the post, usually a single one
+    pub images: Vec<PreviewImage>,
+    /// Reddit hosted copy of the video of some links to external sites
+    pub reddit_video_preview: Option<RedditVideo>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PreviewImage {
+    /// The full size preview image
+    pub source: PreviewSource,
+    /// Smaller copies of the image, narrowest first
+    #[serde(default)]
+    pub resolutions: Vec<PreviewSource>,
+    /// Alternative formats of the preview such as animated gifs
+    pub variants: Option<PreviewVariants>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PreviewSource {
+    /// HTML-entity-encoded URL of the preview
+    pub url: String,
+    pub width: i64,
+    pub height: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PreviewVariants {
+    /// Animated version of the preview, present when the original media is a gif
+    pub gif: Option<PreviewVariant>,
+    /// Video version of an animated gif
+    pub mp4: Option<PreviewVariant>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PreviewVariant {
+    pub source: PreviewSource,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct GalleryItems {
     /// Representation containing a list of gallery items
     pub items: Vec<GalleryItem>,
@@ -174,6 +428,10 @@ pub struct GalleryItem {
     pub media_id: String,
     /// Unique numerical ID for the specific media item
     pub id: i64,
+    /// Caption the author gave to this item
+    pub caption: Option<String>,
+    /// Link the author attached to this item
+    pub outbound_url: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -183,14 +441,87 @@ pub struct GfyItem {
     #[serde(rename = "mp4Url")]
     pub mp4_url: String,
 }
+/// Response of the imgur API listing the images of an album
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImgurAlbumResponse {
+    pub data: Vec<ImgurAlbumImage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImgurAlbumImage {
+    /// Direct link to the image on i.imgur.com, with its extension
+    pub link: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GiphyApiResponse {
+    pub data: GiphyData,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GiphyData {
+    pub images: GiphyImages,
+    /// Only present for clips
+    pub video: Option<GiphyVideo>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GiphyImages {
+    pub original: GiphyRendition,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GiphyRendition {
+    /// URL of the gif
+    pub url: Option<String>,
+    /// URL of the mp4 version of the gif
+    pub mp4: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GiphyVideo {
+    /// Video renditions keyed by resolution, the original is under `source`
+    pub assets: HashMap<String, GiphyRendition>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TwitterSyndicationResponse {
+    #[serde(rename = "mediaDetails")]
+    pub media_details: Option<Vec<TwitterMedia>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TwitterMedia {
+    /// One of "photo", "video" or "animated_gif"
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub media_url_https: String,
+    pub video_info: Option<TwitterVideoInfo>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TwitterVideoInfo {
+    pub variants: Vec<TwitterVideoVariant>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TwitterVideoVariant {
+    pub content_type: String,
+    pub url: String,
+    pub bitrate: Option<u64>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct StreamableApiResponse {
+    /// Transcodes of the video keyed by format, eg: mp4, mp4-mobile and original
     pub files: HashMap<String, StreamableFile>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct StreamableFile {
     pub url: Option<String>,
+    /// Length of the video in seconds
+    pub duration: Option<f64>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -220,7 +551,7 @@ use log::warn;
 use url::{Position, Url};
 impl Post {
     pub fn get_url(&self) -> Option<String> {
-        let original = self.data.url.as_ref().unwrap();
+        let original = self.link_url()?;
         if let Ok(mut parsed) = Url::parse(original) {
             match parsed.path_segments_mut() {
                 Ok(mut p) => p.pop_if_empty(),
@@ -231,7 +562,215 @@ impl Post {
         None
     }
 
+    /// The URL the post links to, preferring `url_overridden_by_dest` when `url` is a link
+    /// to the comments of a reddit post
+    pub fn link_url(&self) -> Option<&String> {
+        let url = self.data.url.as_ref();
+        let is_comments_link =
+            url.is_some_and(|url| url.contains("reddit.com/") && url.contains("/comments/"));
+        match &self.data.url_overridden_by_dest {
+            Some(dest) if url.is_none() || is_comments_link => Some(dest),
+            _ => url,
+        }
+    }
+
+    /// Get the media of the post whose URLs can be worked out without calling any API.
+    /// Media types that need an API call to be resolved return no media
+    pub fn get_all_urls(&self) -> Vec<ResolvedMedia> {
+        let url = match self.get_url() {
+            Some(url) => url,
+            None => return Vec::new(),
+        };
+        let single = |url: String, extension: &str| {
+            vec![ResolvedMedia {
+                url,
+                extension: extension.to_owned(),
+                index: None,
+                media_id: None,
+                caption: None,
+                outbound_url: None,
+            }]
+        };
+        match self.get_type() {
+            MediaType::Gallery => {
+                let gallery = self.data.gallery_data.as_ref().unwrap();
+                let media_metadata = self.data.media_metadata.as_ref().unwrap();
+                gallery
+                    .items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| {
+                        let extension = match media_metadata.get(&item.media_id) {
+                            Some(media) => media.m.split('/').last().unwrap(),
+                            None => JPG,
+                        };
+                        ResolvedMedia {
+                            url: format!(
+                                "https://{}/{}.{}",
+                                REDDIT_IMAGE_SUBDOMAIN, item.media_id, extension
+                            ),
+                            extension: extension.to_owned(),
+                            index: Some(index),
+                            media_id: Some(item.media_id.clone()),
+                            caption: item.caption.clone(),
+                            outbound_url: item.outbound_url.clone(),
+                        }
+                    })
+                    .collect()
+            }
+            MediaType::RedditImage | MediaType::RedditGif => {
+                let extension = url.split('.').last().unwrap().to_owned();
+                // i.redd.it images are named after the same media id used in galleries
+                let media_id = url
+                    .contains(REDDIT_IMAGE_SUBDOMAIN)
+                    .then(|| url.rsplit('/').next().unwrap().split('.').next().unwrap().to_owned());
+                let url = if contains_any(&url, &REDDIT_PREVIEW_SUBDOMAINS) {
+                    // preview URLs are signed, they 403 without the (entity encoded) query string
+                    decode_html_entities(self.link_url().unwrap())
+                } else {
+                    url
+                };
+                vec![ResolvedMedia {
+                    url,
+                    extension,
+                    index: None,
+                    media_id,
+                    caption: None,
+                    outbound_url: None,
+                }]
+            }
+            MediaType::ImgurGif => {
+                // if the extension is gifv, then replace gifv->mp4 to get the video URL
+                let url = normalize_imgur_url(&url).unwrap_or(url);
+                single(url.replace(".gifv", ".mp4"), MP4)
+            }
+            MediaType::ImgurImage => {
+                let url = normalize_imgur_url(&url).unwrap_or(url);
+                let extension = url.split('.').last().unwrap().to_owned();
+                single(url, &extension)
+            }
+            MediaType::ImgurAlbum => {
+                // imgur serves all the images of an album as a single zip file
+                let url = normalize_imgur_url(&url).unwrap_or(url);
+                single(format!("{}/zip", url), ZIP)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Get the decoded URL of the animated gif variant from the post preview, if any
+    pub fn get_gif_variant_url(&self) -> Option<String> {
+        let image = self.data.preview.as_ref()?.images.first()?;
+        let gif = image.variants.as_ref()?.gif.as_ref()?;
+        Some(decode_html_entities(&gif.source.url))
+    }
+
+    /// Get the decoded URL of the mp4 reddit made of a gif from the post preview, if any
+    pub fn get_mp4_variant_url(&self) -> Option<String> {
+        let image = self.data.preview.as_ref()?.images.first()?;
+        let mp4 = image.variants.as_ref()?.mp4.as_ref()?;
+        Some(decode_html_entities(&mp4.source.url))
+    }
+
+    /// Get the decoded URL of the widest resized copy of the image that is at most `max_width`
+    /// pixels wide, or of the widest copy when there is no maximum. Falls back to the
+    /// narrowest copy when they are all wider
+    pub fn get_preview_resolution_url(&self, max_width: Option<i64>) -> Option<String> {
+        let resolutions = &self.data.preview.as_ref()?.images.first()?.resolutions;
+        let fitting = resolutions
+            .iter()
+            .filter(|resolution| max_width.map_or(true, |max| resolution.width <= max))
+            .max_by_key(|resolution| resolution.width);
+        let resolution = fitting.or_else(|| resolutions.iter().min_by_key(|r| r.width))?;
+        Some(decode_html_entities(&resolution.url))
+    }
+
+    /// Get the URL of the reddit hosted preview of an external video, if any
+    pub fn get_video_preview_url(&self) -> Option<String> {
+        let video = self.data.preview.as_ref()?.reddit_video_preview.as_ref()?;
+        Some(video.fallback_url.replace("?source=fallback", ""))
+    }
+
+    /// Get the URL of the thumbnail reddit generated for the post, if it is not a placeholder
+    /// such as "self", "default", "nsfw" or "spoiler"
+    pub fn get_thumbnail_url(&self) -> Option<String> {
+        let thumbnail = self.data.thumbnail.as_deref()?;
+        if !thumbnail.starts_with("http") {
+            return None;
+        }
+        Some(decode_html_entities(thumbnail))
+    }
+
+    /// A copy of the post that links to media found in one of its comments, named after
+    /// the comment so that the files of several links do not collide
+    pub fn with_comment_media(&self, comment_id: &str, url: &str, index: usize) -> Post {
+        let mut post = self.clone();
+        post.data.url = Some(url.to_owned());
+        post.data.name = format!("t1_{}_{}", comment_id, index);
+        post.data.is_self = false;
+        post.data.is_video = None;
+        post.data.media = None;
+        post.data.secure_media = None;
+        post.data.media_metadata = None;
+        post.data.gallery_data = None;
+        post.data.preview = None;
+        post.data.crosspost_parent_list = None;
+        post
+    }
+
+    /// Reddit video crossposts only carry the video media on the original post, copy it
+    /// over so that the crosspost can be downloaded like any reddit video
+    pub fn use_crosspost_parent_media(&mut self) {
+        let is_reddit_video =
+            self.data.url.as_ref().is_some_and(|url| url.contains(REDDIT_VIDEO_SUBDOMAIN));
+        if !is_reddit_video || self.reddit_video().is_some() {
+            return;
+        }
+        let parent_media = self
+            .data
+            .crosspost_parent_list
+            .as_ref()
+            .and_then(|parents| parents.first())
+            .and_then(|parent| parent.media.as_ref())
+            .filter(|media| media.reddit_video.is_some());
+        if let Some(media) = parent_media {
+            self.data.media = Some(media.clone());
+        }
+    }
+
+    /// The reddit video of the post, from `media` or else from `secure_media`
+    pub fn reddit_video(&self) -> Option<&RedditVideo> {
+        let from_media = self.data.media.as_ref().and_then(|media| media.reddit_video.as_ref());
+        from_media.or_else(|| {
+            self.data.secure_media.as_ref().and_then(|media| media.reddit_video.as_ref())
+        })
+    }
+
+    /// Whether the post is a gallery wrapping a single image
+    pub fn is_single_item_gallery(&self) -> bool {
+        self.get_type() == MediaType::Gallery
+            && self.data.gallery_data.as_ref().is_some_and(|gallery| gallery.items.len() == 1)
+    }
+
+    /// Whether the post has been removed by the moderators, reddit or its author
+    pub fn is_removed(&self) -> bool {
+        self.data.removed_by_category.is_some()
+    }
+
+    /// Whether the post has been deleted by its author
+    pub fn is_deleted(&self) -> bool {
+        self.data.removed_by_category.as_deref() == Some("deleted")
+    }
+
+    /// Classify the media of the post by the host and the extension of the URL it links to.
+    /// Galleries are recognized by their metadata and reddit videos need the video details
+    /// reddit attaches to the post. Removed posts and posts without a link are unsupported,
+    /// as are the links no host downloads.
     pub fn get_type(&self) -> MediaType {
+        if self.is_removed() {
+            // the media of removed posts is either gone or replaced by a placeholder
+            return MediaType::Unsupported;
+        }
         if self.data.gallery_data.is_some() && self.data.media_metadata.is_some() {
             return MediaType::Gallery;
         }
@@ -240,8 +779,8 @@ impl Post {
             None => return MediaType::Unsupported,
         };
 
-        if url.contains(REDDIT_IMAGE_SUBDOMAIN) {
-            // if the URL uses the reddit image subdomain and if the extension is
+        if url.contains(REDDIT_IMAGE_SUBDOMAIN) || contains_any(&url, &REDDIT_PREVIEW_SUBDOMAINS) {
+            // if the URL uses the reddit image or preview subdomains and if the extension is
             // jpg, png or gif, then we can use the URL as is.
             if has_extension(&url, &[JPG, PNG, JPEG]) {
                 return MediaType::RedditImage;
@@ -252,7 +791,7 @@ impl Post {
             }
         }
         if url.contains(REDDIT_VIDEO_SUBDOMAIN) {
-            if self.data.media.is_none() {
+            if self.reddit_video().is_none() {
                 return MediaType::Unsupported;
             } else {
                 return MediaType::RedditVideo;
@@ -266,6 +805,7 @@ impl Post {
             return MediaType::GiphyGif;
         }
         if url.contains(IMGUR_DOMAIN) {
+            let url = normalize_imgur_url(&url).unwrap_or(url);
             if url.contains(format!("{}/a/", IMGUR_DOMAIN).as_str()) {
                 return MediaType::ImgurAlbum;
             }
@@ -284,10 +824,24 @@ impl Post {
         if url.contains(STREAMABLE_DOMAIN) {
             return MediaType::StreamableVideo;
         }
+        if is_tweet_url(&url) {
+            return MediaType::Tweet;
+        }
         MediaType::Unsupported
     }
 }
 
+/// Check if the URL points to a tweet on twitter.com or x.com
+fn is_tweet_url(url: &str) -> bool {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return false,
+    };
+    let host = parsed.host_str().unwrap_or("");
+    let host = host.trim_start_matches("www.").trim_start_matches("mobile.");
+    (host == TWITTER_DOMAIN || host == X_DOMAIN) && parsed.path().contains("/status/")
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct TokenResponse {
     pub token: String,
@@ -301,6 +855,8 @@ pub struct TokenResponse {
 pub struct MediaInfo {
     pub sd: String,
     pub hd: String,
+    /// The file as it was uploaded, only present for some gifs
+    pub original: Option<String>,
     pub poster: String,
     pub thumbnail: String,
     pub vthumbnail: String,
@@ -314,3 +870,347 @@ pub struct InnerGif {
 pub struct RedGif {
     pub gif: InnerGif,
 }
+
+/// Build a minimal link post of r/pics pointing to the given URL
+#[cfg(test)]
+pub(crate) fn test_post(url: &str) -> Post {
+    serde_json::from_value(serde_json::json!({
+        "kind": "t3",
+        "data": {
+            "subreddit": "pics",
+            "id": "abc123",
+            "score": 1,
+            "subreddit_id": "t5_2qh0u",
+            "saved": false,
+            "permalink": "/r/pics/comments/abc123/test/",
+            "name": "t3_abc123",
+            "created": 1700000000.0,
+            "created_utc": 1700000000.0,
+            "url": url,
+            "title": "test",
+            "is_self": false
+        }
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_find_comment_urls() {
+        let comments: CommentListing = serde_json::from_value(json!({
+            "kind": "Listing",
+            "data": {
+                "children": [
+                    {
+                        "kind": "t1",
+                        "data": {
+                            "id": "c1",
+                            "body": "no links here",
+                            "replies": {
+                                "kind": "Listing",
+                                "data": {
+                                    "children": [{
+                                        "kind": "t1",
+                                        "data": {
+                                            "id": "c2",
+                                            "body": "[full res](https://i.redd.it/xyz.jpg). Also https://imgur.com/AbC?a=1&amp;b=2",
+                                            "replies": ""
+                                        }
+                                    }]
+                                }
+                            }
+                        }
+                    },
+                    {"kind": "more", "data": {"id": "c3", "children": ["c4"]}}
+                ]
+            }
+        }))
+        .unwrap();
+        let comment = comments.find("c2").unwrap();
+        assert_eq!(
+            comment.urls(),
+            vec!["https://i.redd.it/xyz.jpg", "https://imgur.com/AbC?a=1&b=2"]
+        );
+    }
+
+    #[test]
+    fn test_get_all_urls() {
+        let media = test_post("https://i.redd.it/xyz.png").get_all_urls();
+        assert_eq!(
+            media,
+            vec![ResolvedMedia {
+                url: String::from("https://i.redd.it/xyz.png"),
+                extension: String::from("png"),
+                index: None,
+                media_id: Some(String::from("xyz")),
+                caption: None,
+                outbound_url: None,
+            }]
+        );
+        let media = test_post("https://imgur.com/a/AbC123?x=1").get_all_urls();
+        assert_eq!(media[0].url, "https://imgur.com/a/AbC123/zip");
+        assert_eq!(media[0].extension, ZIP);
+        assert!(test_post("https://redgifs.com/watch/abc").get_all_urls().is_empty());
+    }
+
+    #[test]
+    fn test_is_single_item_gallery() {
+        let mut p = test_post("https://www.reddit.com/gallery/abc123");
+        assert!(!p.is_single_item_gallery());
+        let item = |media_id: &str| json!({"media_id": media_id, "id": 1});
+        let metadata = |media_id: &str| json!({"status": "valid", "e": "Image", "m": "image/png", "id": media_id});
+        p.data.gallery_data = serde_json::from_value(json!({"items": [item("one")]})).unwrap();
+        p.data.media_metadata =
+            serde_json::from_value(json!({"one": metadata("one"), "two": metadata("two")}))
+                .unwrap();
+        assert!(p.is_single_item_gallery());
+        assert_eq!(p.get_all_urls()[0].url, "https://i.redd.it/one.png");
+        p.data.gallery_data =
+            serde_json::from_value(json!({"items": [item("one"), item("two")]})).unwrap();
+        assert!(!p.is_single_item_gallery());
+        // items are numbered in gallery order, whatever the order of the media metadata
+        let media = p.get_all_urls();
+        let numbered: Vec<_> = media.iter().map(|m| (m.index, m.url.as_str())).collect();
+        assert_eq!(
+            numbered,
+            vec![(Some(0), "https://i.redd.it/one.png"), (Some(1), "https://i.redd.it/two.png")]
+        );
+    }
+
+    #[test]
+    fn test_created_utc_secs() {
+        let mut p = test_post("https://i.redd.it/abc.jpg");
+        assert_eq!(p.data.created_utc_secs(), Some(1700000000));
+        p.data.created_utc = json!(1700000000);
+        assert_eq!(p.data.created_utc_secs(), Some(1700000000));
+        p.data.created_utc = json!(1700000000.75);
+        assert_eq!(p.data.created_utc_secs(), Some(1700000000));
+        p.data.created_utc = json!("1700000000");
+        assert_eq!(p.data.created_utc_secs(), Some(1700000000));
+        p.data.created_utc = json!("1700000000.5");
+        assert_eq!(p.data.created_utc_secs(), Some(1700000000));
+        p.data.created_utc = json!("yesterday");
+        assert_eq!(p.data.created_utc_secs(), None);
+        p.data.created_utc = Value::Null;
+        assert_eq!(p.data.created_utc_secs(), None);
+    }
+
+    #[test]
+    fn test_normalize_created() {
+        let mut p = test_post("https://i.redd.it/abc.jpg");
+        p.data.created_utc = json!("1700000000.5");
+        assert!(p.data.normalize_created());
+        assert_eq!(p.data.created_utc_seconds, Some(1700000000.5));
+        assert_eq!(p.data.created_utc_secs(), Some(1700000000));
+
+        // created is used when created_utc is unusable
+        p.data.created_utc = json!({"unexpected": true});
+        p.data.created = json!(1600000000);
+        assert!(p.data.normalize_created());
+        assert_eq!(p.data.created_utc_secs(), Some(1600000000));
+
+        p.data.created = Value::Null;
+        assert!(!p.data.normalize_created());
+        assert_eq!(p.data.created_utc_seconds, None);
+    }
+
+    #[test]
+    fn test_get_type() {
+        let cases = [
+            ("https://i.redd.it/abc123.jpg", MediaType::RedditImage),
+            ("https://i.redd.it/abc123.jpeg", MediaType::RedditImage),
+            ("https://i.redd.it/abc123.png", MediaType::RedditImage),
+            ("https://i.redd.it/abc123.gif", MediaType::RedditGif),
+            ("https://i.redd.it/abc123.mp4", MediaType::Unsupported),
+            ("https://preview.redd.it/abc123.png?width=640&s=0123", MediaType::RedditImage),
+            ("https://external-preview.redd.it/abc123.gif", MediaType::RedditGif),
+            ("https://v.redd.it/abc123", MediaType::Unsupported),
+            ("https://redgifs.com/watch/somegif", MediaType::RedGif),
+            ("https://www.redgifs.com/watch/somegif", MediaType::RedGif),
+            ("https://giphy.com/gifs/some-slug-AbC123", MediaType::GiphyGif),
+            ("https://media.giphy.com/media/AbC123/giphy.gif", MediaType::GiphyGif),
+            ("https://imgur.com/a/XyZ789", MediaType::ImgurAlbum),
+            ("https://imgur.com/gallery/XyZ789", MediaType::ImgurUnknown),
+            ("https://imgur.com/AbC123", MediaType::ImgurUnknown),
+            ("https://m.imgur.com/AbC123", MediaType::ImgurUnknown),
+            ("https://i.imgur.com/AbC123.jpg", MediaType::ImgurImage),
+            ("https://i.imgur.com/AbC123.jpeg", MediaType::ImgurImage),
+            ("https://i.imgur.com/AbC123.png", MediaType::ImgurImage),
+            ("https://i.imgur.com/AbC123.gif", MediaType::ImgurGif),
+            ("https://i.imgur.com/AbC123.gifv", MediaType::ImgurGif),
+            ("https://i.imgur.com/AbC123.mp4", MediaType::ImgurGif),
+            ("https://i.imgur.com/AbC123", MediaType::Unsupported),
+            ("https://streamable.com/abc123", MediaType::StreamableVideo),
+            ("https://twitter.com/someone/status/1234567890", MediaType::Tweet),
+            ("https://x.com/someone/status/1234567890/photo/1", MediaType::Tweet),
+            ("https://twitter.com/someone", MediaType::Unsupported),
+            ("https://www.youtube.com/watch?v=abc123", MediaType::Unsupported),
+            ("https://example.com/image.jpg", MediaType::Unsupported),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(test_post(url).get_type(), expected, "{}", url);
+        }
+
+        let mut p = test_post("https://v.redd.it/abc123");
+        p.data.media = serde_json::from_value(json!({
+            "reddit_video": {
+                "fallback_url": "https://v.redd.it/abc123/DASH_720.mp4?source=fallback",
+                "is_gif": false,
+                "dash_url": "https://v.redd.it/abc123/DASHPlaylist.mpd"
+            }
+        }))
+        .unwrap();
+        assert_eq!(p.get_type(), MediaType::RedditVideo);
+
+        // removed posts are unsupported whatever they link to
+        p.data.removed_by_category = Some(String::from("moderator"));
+        assert_eq!(p.get_type(), MediaType::Unsupported);
+
+        let mut p = test_post("https://www.reddit.com/gallery/abc123");
+        assert_eq!(p.get_type(), MediaType::Unsupported);
+        p.data.gallery_data = serde_json::from_value(json!({
+            "items": [{"media_id": "one", "id": 1}]
+        }))
+        .unwrap();
+        p.data.media_metadata = serde_json::from_value(json!({
+            "one": {"status": "valid", "e": "Image", "m": "image/jpg", "id": "one"}
+        }))
+        .unwrap();
+        assert_eq!(p.get_type(), MediaType::Gallery);
+
+        p.data.url = None;
+        p.data.gallery_data = None;
+        assert_eq!(p.get_type(), MediaType::Unsupported);
+    }
+
+    #[test]
+    fn test_url_overridden_by_dest() {
+        let mut p = test_post("https://www.reddit.com/r/pics/comments/abc123/test/");
+        assert_eq!(p.get_type(), MediaType::Unsupported);
+        p.data.url_overridden_by_dest = Some(String::from("https://i.redd.it/xyz.jpg"));
+        assert_eq!(p.get_url().as_deref(), Some("https://i.redd.it/xyz.jpg"));
+        assert_eq!(p.get_type(), MediaType::RedditImage);
+
+        // the override is only used when the url does not already point to the media
+        let mut p = test_post("https://i.imgur.com/AbC123.jpg");
+        p.data.url_overridden_by_dest = Some(String::from("https://i.redd.it/xyz.jpg"));
+        assert_eq!(p.get_url().as_deref(), Some("https://i.imgur.com/AbC123.jpg"));
+    }
+
+    #[test]
+    fn test_secure_media_video() {
+        let mut p = test_post("https://v.redd.it/abc123");
+        assert_eq!(p.get_type(), MediaType::Unsupported);
+        p.data.secure_media = serde_json::from_value(json!({
+            "reddit_video": {
+                "fallback_url": "https://v.redd.it/abc123/DASH_720.mp4?source=fallback",
+                "is_gif": false,
+                "dash_url": "https://v.redd.it/abc123/DASHPlaylist.mpd",
+                "duration": 42
+            }
+        }))
+        .unwrap();
+        assert_eq!(p.get_type(), MediaType::RedditVideo);
+        assert_eq!(p.reddit_video().unwrap().dash_url, "https://v.redd.it/abc123/DASHPlaylist.mpd");
+        assert_eq!(p.reddit_video().unwrap().duration, Some(42));
+    }
+
+    #[test]
+    fn test_video_preview_url() {
+        let mut p = test_post("https://example.com/videos/abc123");
+        assert_eq!(p.get_video_preview_url(), None);
+        p.data.preview = serde_json::from_value(json!({
+            "images": [],
+            "reddit_video_preview": {
+                "fallback_url": "https://v.redd.it/xyz789/DASH_480.mp4?source=fallback",
+                "is_gif": false,
+                "dash_url": "https://v.redd.it/xyz789/DASHPlaylist.mpd"
+            }
+        }))
+        .unwrap();
+        assert_eq!(p.get_type(), MediaType::Unsupported);
+        assert_eq!(
+            p.get_video_preview_url().as_deref(),
+            Some("https://v.redd.it/xyz789/DASH_480.mp4")
+        );
+    }
+
+    #[test]
+    fn test_thumbnail_url() {
+        let mut p = test_post("https://example.com/videos/abc123");
+        assert_eq!(p.get_thumbnail_url(), None);
+        for placeholder in ["self", "default", "nsfw", "spoiler", "image", ""] {
+            p.data.thumbnail = Some(String::from(placeholder));
+            assert_eq!(p.get_thumbnail_url(), None);
+        }
+        p.data.thumbnail =
+            Some(String::from("https://b.thumbs.redditmedia.com/abc.jpg?a=1&amp;b=2"));
+        assert_eq!(
+            p.get_thumbnail_url().as_deref(),
+            Some("https://b.thumbs.redditmedia.com/abc.jpg?a=1&b=2")
+        );
+    }
+
+    #[test]
+    fn test_preview_resolution_url() {
+        let mut p = test_post("https://i.redd.it/abc.jpg");
+        assert_eq!(p.get_preview_resolution_url(None), None);
+        p.data.preview = serde_json::from_value(json!({
+            "images": [{
+                "source": {"url": "https://preview.redd.it/abc.jpg?s=0", "width": 4000, "height": 3000},
+                "resolutions": [
+                    {"url": "https://preview.redd.it/abc.jpg?width=640&amp;s=1", "width": 640, "height": 480},
+                    {"url": "https://preview.redd.it/abc.jpg?width=960&amp;s=2", "width": 960, "height": 720},
+                    {"url": "https://preview.redd.it/abc.jpg?width=1080&amp;s=3", "width": 1080, "height": 810}
+                ]
+            }]
+        }))
+        .unwrap();
+        let url = |max_width| p.get_preview_resolution_url(max_width);
+        assert_eq!(url(None).as_deref(), Some("https://preview.redd.it/abc.jpg?width=1080&s=3"));
+        assert_eq!(
+            url(Some(1080)).as_deref(),
+            Some("https://preview.redd.it/abc.jpg?width=1080&s=3")
+        );
+        assert_eq!(
+            url(Some(720)).as_deref(),
+            Some("https://preview.redd.it/abc.jpg?width=640&s=1")
+        );
+        assert_eq!(
+            url(Some(320)).as_deref(),
+            Some("https://preview.redd.it/abc.jpg?width=640&s=1")
+        );
+    }
+
+    #[test]
+    fn test_mp4_variant_url() {
+        let mut p = test_post("https://i.redd.it/abc.gif");
+        assert_eq!(p.get_mp4_variant_url(), None);
+        p.data.preview = serde_json::from_value(json!({
+            "images": [{
+                "source": {"url": "https://preview.redd.it/abc.gif?s=0", "width": 400, "height": 300},
+                "variants": {
+                    "gif": {"source": {"url": "https://preview.redd.it/abc.gif?format=png8&amp;s=1", "width": 400, "height": 300}},
+                    "mp4": {"source": {"url": "https://preview.redd.it/abc.gif?format=mp4&amp;s=2", "width": 400, "height": 300}}
+                }
+            }]
+        }))
+        .unwrap();
+        assert_eq!(
+            p.get_mp4_variant_url().as_deref(),
+            Some("https://preview.redd.it/abc.gif?format=mp4&s=2")
+        );
+    }
+
+    #[test]
+    fn test_preview_image_type() {
+        let p = test_post("https://preview.redd.it/abc.jpg?width=640&amp;s=123");
+        assert_eq!(p.get_type(), MediaType::RedditImage);
+        let p = test_post("https://external-preview.redd.it/abc.png?auto=webp&amp;s=123");
+        assert_eq!(p.get_type(), MediaType::RedditImage);
+    }
+}
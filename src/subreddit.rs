@@ -1,34 +1,102 @@
 use crate::errors::GertError;
-use crate::structs::{Listing, Post};
+use crate::ratelimit::RateLimiter;
+use crate::structs::{CommentData, CommentListing, Listing, Post};
+use futures::stream::{self, Stream, StreamExt};
 use log::{debug, error};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use std::fmt::Write;
 
 pub struct Subreddit<'a> {
     /// Name of subreddit.
     pub name: String,
     url: String,
+    /// Whether this lists the submissions of a user instead of a subreddit
+    is_user: bool,
     client: &'a Client,
+    rate_limiter: RateLimiter,
 }
 
 impl Subreddit<'_> {
     /// Create a new `Subreddit` instance.
-    pub fn new<'a>(name: &'a str, session: &'a Client) -> Subreddit<'a> {
-        let subreddit_url = format!("https://www.reddit.com/r/{}", name);
+    pub fn new<'a>(
+        name: &'a str,
+        reddit_url: &str,
+        session: &'a Client,
+        rate_limiter: &RateLimiter,
+    ) -> Subreddit<'a> {
+        let subreddit_url = format!("{}/r/{}", reddit_url, name);
 
-        Subreddit { name: name.to_owned(), url: subreddit_url, client: session }
+        Subreddit {
+            name: name.to_owned(),
+            url: subreddit_url,
+            is_user: false,
+            client: session,
+            rate_limiter: rate_limiter.clone(),
+        }
+    }
+
+    /// Create a new `Subreddit` instance that lists the submissions of a user.
+    pub fn user<'a>(
+        name: &'a str,
+        reddit_url: &str,
+        session: &'a Client,
+        rate_limiter: &RateLimiter,
+    ) -> Subreddit<'a> {
+        let user_url = format!("{}/user/{}/submitted", reddit_url, name);
+
+        Subreddit {
+            name: name.to_owned(),
+            url: user_url,
+            is_user: true,
+            client: session,
+            rate_limiter: rate_limiter.clone(),
+        }
+    }
+
+    /// Create a new `Subreddit` instance that lists the comments of a user.
+    /// Use `get_comments` instead of `get_posts` on it.
+    pub fn user_comments<'a>(
+        name: &'a str,
+        reddit_url: &str,
+        session: &'a Client,
+        rate_limiter: &RateLimiter,
+    ) -> Subreddit<'a> {
+        let comments_url = format!("{}/user/{}/comments", reddit_url, name);
+
+        Subreddit {
+            name: name.to_owned(),
+            url: comments_url,
+            is_user: true,
+            client: session,
+            rate_limiter: rate_limiter.clone(),
+        }
     }
 
-    async fn get_feed(
+    /// Name of the subreddit or user as reddit displays it, eg: r/pics or u/spez
+    pub fn display_name(&self) -> String {
+        let prefix = if self.is_user { "u" } else { "r" };
+        format!("{}/{}", prefix, self.name)
+    }
+
+    async fn get_feed<T: DeserializeOwned>(
         &self,
         ty: &str,
         limit: u32,
         period: Option<&str>,
         after: Option<&str>,
-    ) -> Result<Listing, GertError> {
-        let url = &mut format!("{}/{}.json?limit={}", self.url, ty, limit);
+    ) -> Result<T, GertError> {
+        // user pages take the feed as a sort parameter instead of a path segment.
+        // raw_json stops reddit from escaping the & in the media URLs as &amp;
+        let url = &mut if self.is_user {
+            format!("{}.json?sort={}&limit={}&raw_json=1", self.url, ty, limit)
+        } else {
+            format!("{}/{}.json?limit={}&raw_json=1", self.url, ty, limit)
+        };
 
-        if let Some(p) = period {
+        // reddit only uses the time period for the top and controversial feeds
+        if let Some(p) = period.filter(|_| ty == "top" || ty == "controversial") {
             let _ = write!(url, "&t={}", p);
         }
 
@@ -37,56 +105,139 @@ impl Subreddit<'_> {
         }
         let url = &url.to_owned();
         debug!("Fetching posts from {}]", url);
-        Ok(self.client.get(url).send().await.expect("Bad response").json::<Listing>().await?)
-        // Ok(self.client.get(url).send().await.expect("Bad response").json::<Listing>().await.expect("Failed to parse JSON"))
+        self.rate_limiter.acquire().await;
+        let response = self.client.get(url).send().await?;
+        // anonymous requests for nsfw subreddits are redirected to a confirmation page
+        if response.url().path().starts_with("/over18") {
+            return Err(GertError::Over18Required(self.display_name()));
+        }
+        if response.status() == StatusCode::FORBIDDEN {
+            let body: Value = response.json().await.unwrap_or_default();
+            return Err(match body["reason"].as_str() {
+                Some("quarantined") => GertError::Quarantined(self.display_name()),
+                Some("over18") => GertError::Over18Required(self.display_name()),
+                _ => GertError::Forbidden(self.display_name()),
+            });
+        }
+        Ok(response.json::<T>().await?)
     }
 
+    /// Get up to `limit` posts of the feed, or every post reddit lists when `limit` is 0
     pub async fn get_posts(
         &self,
         feed: &str,
         limit: u32,
         period: Option<&str>,
     ) -> Result<Vec<Post>, GertError> {
-        if limit <= 100 {
+        if limit > 0 && limit <= 100 {
             return Ok(self
-                .get_feed(feed, limit, period, None)
+                .get_feed::<Listing>(feed, limit, period, None)
                 .await?
                 .data
                 .children
                 .into_iter()
                 .collect());
         }
-        let mut page = 1;
-        let mut posts: Vec<Post> = Vec::new();
-        let mut after = None;
-        let mut remaining = limit;
-        while remaining > 0 {
-            debug!("Fetching page {} of {} from r/{} [{}]", page, limit / 100, self.name, feed);
-            let limit = if remaining > 100 { 100 } else { remaining };
-            let listing_result = self.get_feed(feed, limit, period, after).await;
-
-            match listing_result {
-                Ok(listing) => {
-                    if !listing.data.children.is_empty() {
-                        posts.extend(listing.data.children.into_iter().collect::<Vec<Post>>());
-                        let last_post = posts.last().unwrap();
-                        after = Some(&last_post.data.name);
-                        remaining -= limit;
-                        page += 1;
-                    } else {
-                        error!("Failed to fetch posts from r/{}", self.name);
-                        remaining = 0;
-                    }
-                }
-                Err(_error) => {
-                    error!("Failed to fetch posts from r/{}", self.name);
-                    remaining = 0;
+        let mut posts = Vec::new();
+        let mut stream = Box::pin(self.posts_stream(feed, limit, period));
+        while let Some(post) = stream.next().await {
+            match post {
+                Ok(post) => posts.push(post),
+                // keep the posts of the pages fetched before the one that failed
+                Err(e) if !posts.is_empty() => {
+                    error!("Failed to fetch posts from {}: {}", self.display_name(), e);
+                    break;
                 }
+                Err(e) => return Err(e),
             }
         }
         Ok(posts)
     }
 
+    /// Stream the posts of the feed page by page, so that callers can start working on the
+    /// posts of a page before the next one is fetched. A `limit` of 0 streams every post
+    /// reddit lists. Fetching stops at the last page or at the first page that fails, whose
+    /// error is the last item of the stream.
+    pub fn posts_stream<'s>(
+        &'s self,
+        feed: &'s str,
+        limit: u32,
+        period: Option<&'s str>,
+    ) -> impl Stream<Item = Result<Post, GertError>> + 's {
+        let subreddit: &'s Subreddit<'s> = self;
+        let pages = if limit == 0 { None } else { Some(limit.div_ceil(100)) };
+        // remaining is None when there is no limit
+        let remaining = if limit == 0 { None } else { Some(limit) };
+        let state = (None::<String>, remaining, 1, false);
+        stream::unfold(state, move |(after, remaining, page, last_page)| async move {
+            if last_page || remaining == Some(0) {
+                return None;
+            }
+            debug!(
+                "Fetching page {} of {} from {} [{}]",
+                page,
+                pages.map_or(String::from("?"), |pages| pages.to_string()),
+                subreddit.display_name(),
+                feed
+            );
+            let limit = remaining.map_or(100, |remaining| remaining.min(100));
+
+            match subreddit.get_feed::<Listing>(feed, limit, period, after.as_deref()).await {
+                Ok(listing) if !listing.data.children.is_empty() => {
+                    // reddit does not return a cursor after the last page
+                    let last_page = listing.data.after.is_none();
+                    let posts = listing.data.children;
+                    let after = posts.last().map(|post| post.data.name.clone());
+                    let remaining = remaining.map(|remaining| remaining - limit);
+                    let posts: Vec<_> = posts.into_iter().map(Ok).collect();
+                    Some((stream::iter(posts), (after, remaining, page + 1, last_page)))
+                }
+                Ok(_) if remaining.is_none() => None,
+                Ok(_) => {
+                    error!("Failed to fetch posts from {}", subreddit.display_name());
+                    None
+                }
+                Err(e) => Some((stream::iter(vec![Err(e)]), (after, remaining, page, true))),
+            }
+        })
+        .flatten()
+    }
+
+    /// Get up to `limit` comments of the feed of a user, or every comment reddit lists when
+    /// `limit` is 0
+    pub async fn get_comments(
+        &self,
+        feed: &str,
+        limit: u32,
+        period: Option<&str>,
+    ) -> Result<Vec<CommentData>, GertError> {
+        let mut comments = Vec::new();
+        let mut after: Option<String> = None;
+        loop {
+            let remaining = if limit == 0 { 100 } else { (limit - comments.len() as u32).min(100) };
+            let listing: CommentListing =
+                self.get_feed(feed, remaining, period, after.as_deref()).await?;
+            let last_page = listing.data.after.is_none() || listing.data.children.is_empty();
+            after = listing.data.after;
+            // skip the "load more comments" placeholders
+            comments.extend(
+                listing
+                    .data
+                    .children
+                    .into_iter()
+                    .filter(|comment| comment.kind == "t1")
+                    .map(|comment| comment.data),
+            );
+            if last_page || (limit > 0 && comments.len() as u32 >= limit) {
+                break;
+            }
+        }
+        if limit > 0 {
+            comments.truncate(limit as usize);
+        }
+        Ok(comments)
+    }
+
     #[allow(dead_code)]
     /// Get hot posts.
     pub async fn hot(&self, limit: u32, options: Option<&str>) -> Result<Listing, GertError> {
@@ -111,3 +262,110 @@ impl Subreddit<'_> {
         self.get_feed("new", limit, period, None).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::test_post;
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_get_posts_requests_raw_json() {
+        let server = MockServer::start().await;
+        let preview = "https://preview.redd.it/abc.jpg?width=640&format=pjpg&s=0123";
+        let mut post = test_post("https://example.com/abc");
+        post.data.preview = serde_json::from_value(json!({
+            "images": [{"source": {"url": preview, "width": 640, "height": 480}}]
+        }))
+        .unwrap();
+        let listing = json!({
+            "kind": "Listing",
+            "data": {"after": null, "before": null, "dist": 1, "children": [post]}
+        });
+        // without raw_json the request is not matched and the listing fails to parse
+        Mock::given(method("GET"))
+            .and(path("/r/pics/hot.json"))
+            .and(query_param("raw_json", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing))
+            .mount(&server)
+            .await;
+
+        let session = Client::new();
+        let uri = server.uri();
+        let subreddit = Subreddit::new("pics", &uri, &session, &RateLimiter::new(0));
+        let posts = subreddit.get_posts("hot", 1, None).await.unwrap();
+
+        let url = &posts[0].data.preview.as_ref().unwrap().images[0].source.url;
+        assert_eq!(url, preview);
+        assert!(!url.contains("&amp;"));
+    }
+
+    #[tokio::test]
+    async fn test_get_posts_quarantined() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/r/pics/hot.json"))
+            .respond_with(
+                ResponseTemplate::new(403).set_body_json(json!({"reason": "quarantined"})),
+            )
+            .mount(&server)
+            .await;
+
+        let session = Client::new();
+        let uri = server.uri();
+        let subreddit = Subreddit::new("pics", &uri, &session, &RateLimiter::new(0));
+        // a single page and an unlimited listing both report why reddit refused
+        for limit in [1, 0] {
+            let result = subreddit.get_posts("hot", limit, None).await;
+            assert!(matches!(result, Err(GertError::Quarantined(_))), "limit {}", limit);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_comments() {
+        let server = MockServer::start().await;
+        let page = |children: Value, after: Value| json!({"kind": "Listing", "data": {"after": after, "dist": 2, "children": children}});
+        Mock::given(method("GET"))
+            .and(path("/user/spez/comments.json"))
+            .and(query_param("after", "t1_c2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                json!([{"kind": "t1", "data": {"id": "c3", "body": "[deleted]"}}]),
+                Value::Null,
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/user/spez/comments.json"))
+            .and(query_param("sort", "new"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                json!([
+                    {"kind": "t1", "data": {
+                        "id": "c1", "subreddit": "pics", "score": 5, "link_title": "a post",
+                        "body": "look https://i.redd.it/abc.jpg", "created_utc": "1700000000"
+                    }},
+                    {"kind": "t1", "data": {"id": "c2", "body": null}}
+                ]),
+                json!("t1_c2"),
+            )))
+            .mount(&server)
+            .await;
+
+        let session = Client::new();
+        let uri = server.uri();
+        let user = Subreddit::user_comments("spez", &uri, &session, &RateLimiter::new(0));
+        let comments = user.get_comments("new", 0, None).await.unwrap();
+        let ids: Vec<_> = comments.iter().map(|comment| comment.id.as_deref().unwrap()).collect();
+        assert_eq!(ids, vec!["c1", "c2", "c3"]);
+
+        let post = comments[0].to_post("spez/comments");
+        let post = post.with_comment_media("c1", &comments[0].urls()[0], 0);
+        assert_eq!(post.data.subreddit, "spez/comments");
+        assert_eq!(post.data.title.as_deref(), Some("a post"));
+        assert_eq!(post.data.url.as_deref(), Some("https://i.redd.it/abc.jpg"));
+        assert_eq!(post.data.created_utc_secs(), Some(1700000000));
+
+        assert_eq!(user.get_comments("new", 1, None).await.unwrap().len(), 1);
+    }
+}
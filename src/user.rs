@@ -1,19 +1,20 @@
-use crate::auth::Auth;
+use crate::auth::Client;
 use crate::errors::GertError;
-use crate::structs::{Listing, UserAbout};
+use crate::ratelimit::RateLimiter;
+use crate::structs::{Listing, SubredditListing, UserAbout};
 use log::{debug, info};
 use std::borrow::Borrow;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
-#[derive(Debug)]
 pub struct User<'a> {
-    /// Contains authentication information about the user
-    auth: &'a Auth,
+    /// Logs in and keeps the access token of the user fresh
+    client: &'a Client<'a>,
     /// Username of the user who authorized the application
     name: &'a str,
     /// Reqwest client
     session: &'a reqwest::Client,
+    rate_limiter: RateLimiter,
 }
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -32,29 +33,75 @@ impl Display for ListingType {
 }
 
 impl<'a> User<'a> {
-    pub fn new(auth: &'a Auth, name: &'a str, session: &'a reqwest::Client) -> Self {
-        User { auth, name, session }
+    pub fn new(
+        client: &'a Client<'a>,
+        name: &'a str,
+        session: &'a reqwest::Client,
+        rate_limiter: &RateLimiter,
+    ) -> Self {
+        User { client, name, session, rate_limiter: rate_limiter.clone() }
+    }
+
+    /// GET an oauth endpoint with the bearer token, logging in again once if it was rejected
+    async fn get(&self, url: &str, query: &[(&str, u32)]) -> Result<reqwest::Response, GertError> {
+        let token = self.client.access_token().await?;
+        self.rate_limiter.acquire().await;
+        // reddit will forbid you from accessing the API if the provided user agent is not unique
+        let response = self.session.get(url).bearer_auth(&token).query(query).send().await?;
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let token = self.client.refresh().await?;
+        self.rate_limiter.acquire().await;
+        Ok(self.session.get(url).bearer_auth(&token).query(query).send().await?)
     }
 
     pub async fn about(&self) -> Result<UserAbout, GertError> {
         // all API requests that use a bearer token should be made to oauth.reddit.com instead
-        let url = format!("https://oauth.reddit.com/user/{}/about", self.name);
+        let url = format!("{}/user/{}/about", self.client.oauth_url(), self.name);
 
-        let response = self
-            .session
-            .get(&url)
-            .bearer_auth(&self.auth.access_token)
-            // reddit will forbid you from accessing the API if the provided user agent is not unique
-            .send()
-            .await?
-            .json::<UserAbout>()
-            .await?;
+        let response = self.get(&url, &[]).await?.json::<UserAbout>().await?;
 
         debug!("About Response: {:#?}", response);
 
         Ok(response)
     }
 
+    /// Names of the subreddits and of the user profiles the user is subscribed to
+    pub async fn subscriptions(&self) -> Result<(Vec<String>, Vec<String>), GertError> {
+        let (mut subreddits, mut users) = (Vec::new(), Vec::new());
+        let mut after: Option<String> = None;
+        loop {
+            let mut url = format!("{}/subreddits/mine/subscriber", self.client.oauth_url());
+            if let Some(after) = &after {
+                url = format!("{}?after={}", url, after);
+            }
+            let response = self
+                .get(&url, &[("limit", 100), ("raw_json", 1)])
+                .await?
+                .json::<SubredditListing>()
+                .await?;
+            for child in response.data.children {
+                let name = child.data.display_name;
+                // followed user profiles are listed as subreddits named u_<name>
+                match name.strip_prefix("u_") {
+                    Some(user) => users.push(user.to_owned()),
+                    None => subreddits.push(name),
+                }
+            }
+            after = response.data.after;
+            if after.is_none() {
+                break;
+            }
+        }
+        info!(
+            "The user is subscribed to {} subreddits and {} users",
+            subreddits.len(),
+            users.len()
+        );
+        Ok((subreddits, users))
+    }
+
     #[allow(dead_code)]
     pub async fn listing(&self, listing_type: &ListingType) -> Result<Vec<Listing>, GertError> {
         let mut complete = false;
@@ -66,26 +113,20 @@ impl<'a> User<'a> {
             // in subsequent calls, we use the value for after from the response of the
             //  previous request and continue doing so till the value of after is null
             let url = if processed == 0 {
-                format!("https://oauth.reddit.com/user/{}/{}", self.name, listing_type)
+                format!("{}/user/{}/{}", self.client.oauth_url(), self.name, listing_type)
             } else {
                 format!(
-                    "https://oauth.reddit.com/user/{}/{}?after={}",
+                    "{}/user/{}/{}?after={}",
+                    self.client.oauth_url(),
                     self.name,
                     listing_type,
                     after.as_ref().unwrap()
                 )
             };
 
-            let response = self
-                .session
-                .get(&url)
-                .bearer_auth(&self.auth.access_token)
-                // the maximum number of items returned by the API in a single request is 100
-                .query(&[("limit", 100)])
-                .send()
-                .await?
-                .json::<Listing>()
-                .await?;
+            // the maximum number of items returned by the API in a single request is 100
+            let response =
+                self.get(&url, &[("limit", 100), ("raw_json", 1)]).await?.json::<Listing>().await?;
 
             // total number of items processed by the method
             // note that not all of these items are media, so the downloaded media will be
@@ -1,34 +1,142 @@
+use crate::download::{RunSummary, IMGUR_DOMAIN, IMGUR_SUBDOMAIN};
 use crate::errors::GertError;
-use log::debug;
+use crate::ratelimit::RateLimiter;
+use crate::structs::{Listing, Post};
+use chrono::DateTime;
+use fs2::FileExt;
+use log::{debug, warn};
 use mime::Mime;
 use reqwest::header::CONTENT_TYPE;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
 use which::which;
 use xml::reader::{EventReader, XmlEvent};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Feeds that can be downloaded from a subreddit
+pub const FEEDS: [&str; 4] = ["hot", "new", "top", "rising"];
+/// Time periods reddit accepts for the `t` query parameter
+pub const PERIODS: [&str; 7] = ["now", "hour", "day", "week", "month", "year", "all"];
+/// Maximum number of posts reddit returns from a single /api/info request
+const INFO_BATCH_SIZE: usize = 100;
+/// Name of the file `--fetch-only` saves the posts to, in the output directory
+pub const POSTS_FILE: &str = "posts.jsonl";
+/// Files of a reddit data export that list posts: the saved and the voted on ones
+const EXPORT_FILES: [&str; 2] = ["saved_posts.csv", "post_votes.csv"];
+
 /// Generate user agent string of the form <name>:<version>.
 /// If no arguments passed generate random name and number
 pub fn get_user_agent_string(username: &str) -> String {
     format!("gert:{} (by u/{})", VERSION, username)
 }
 
+/// Path segments imgur appends after the hash of an image or album
+const IMGUR_TRAILING_SEGMENTS: [&str; 5] = ["embed", "zip", "new", "layout", "all"];
+
+/// Normalize an imgur URL to https://imgur.com/<hash>, https://imgur.com/a/<hash> or
+/// https://i.imgur.com/<hash>.<ext>, dropping the query, fragment and any trailing path
+pub fn normalize_imgur_url(url: &str) -> Option<String> {
+    let parsed = Url::parse(url).ok()?;
+    let host = parsed.host_str()?;
+    let segments: Vec<&str> = parsed
+        .path_segments()?
+        .filter(|segment| !segment.is_empty())
+        .take_while(|segment| !IMGUR_TRAILING_SEGMENTS.contains(segment))
+        .collect();
+
+    if host == IMGUR_SUBDOMAIN {
+        return Some(format!("https://{}/{}", IMGUR_SUBDOMAIN, segments.first()?));
+    }
+    if !host.ends_with(IMGUR_DOMAIN) {
+        return None;
+    }
+    match segments.as_slice() {
+        ["a", hash, ..] => Some(format!("https://{}/a/{}", IMGUR_DOMAIN, hash)),
+        [.., hash] => Some(format!("https://{}/{}", IMGUR_DOMAIN, hash)),
+        [] => None,
+    }
+}
+
+/// POST the summary of a run to a webhook. The `content` and `text` fields carry a
+/// readable message for Discord and Slack, the counts are also sent as separate fields
+pub async fn send_webhook(
+    session: &reqwest::Client,
+    url: &str,
+    summary: &RunSummary,
+) -> Result<(), GertError> {
+    let message = format!(
+        "gert finished in {:.0}s: {} downloaded, {} skipped, {} failed, {} unsupported",
+        summary.seconds, summary.downloaded, summary.skipped, summary.failed, summary.unsupported
+    );
+    let mut payload =
+        serde_json::to_value(summary).map_err(|e| GertError::JsonParseError(e.to_string()))?;
+    payload["content"] = Value::String(message.clone());
+    payload["text"] = Value::String(message);
+    // reqwest errors name the URL, which embeds the secret token of the webhook
+    let response = session.post(url).json(&payload).send().await.map_err(|e| e.without_url())?;
+    response.error_for_status().map_err(|e| e.without_url())?;
+    Ok(())
+}
+
 /// Check if a particular path is present on the filesystem
 pub fn check_path_present(file_path: &str) -> bool {
     Path::new(file_path).exists()
 }
 
+/// Check that the output directory exists and is a directory. With `writable`, also check
+/// that files can be created in it, so that a run fails before fetching any posts
+pub fn check_data_directory(directory: &str, writable: bool) -> Result<(), GertError> {
+    let path = Path::new(directory);
+    if !path.exists() {
+        return Err(GertError::DataDirNotFound);
+    }
+    if !path.is_dir() {
+        return Err(GertError::DataDirNotADirectory(directory.to_owned()));
+    }
+    if writable {
+        // the probe file is deleted as soon as it is dropped
+        tempfile::NamedTempFile::new_in(path)
+            .map_err(|_| GertError::DataDirNotWritable(directory.to_owned()))?;
+    }
+    Ok(())
+}
+
+/// Take the lock file of the output directory so that two runs do not save media to the
+/// same directory at the same time. The lock is released when the returned file is dropped,
+/// or by the operating system when gert exits. With `force` a held lock only logs a warning.
+pub fn lock_data_directory(directory: &str, force: bool) -> Result<fs::File, GertError> {
+    let path = Path::new(directory).join(".gert.lock");
+    let mut file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+    if file.try_lock_exclusive().is_err() {
+        if !force {
+            return Err(GertError::DataDirLocked(directory.to_owned()));
+        }
+        warn!("Ignoring the lock of another gert run on {}", directory);
+        return Ok(file);
+    }
+    // record who holds the lock to help debugging stuck runs
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(file)
+}
+
 /// Function that masks sensitive data such as password and client secrets
 pub fn mask_sensitive(word: &str) -> String {
-    let word_length = word.len();
+    let word_length = word.chars().count();
     return if word.is_empty() {
         // return with indication if string is empty
         String::from("<EMPTY>")
-    } else if word_length > 0 && word_length <= 3 {
-        // if string length is between 1-3, mask all characters
+    } else if word_length <= 5 {
+        // if string length is between 1-5, mask all characters
         "*".repeat(word_length)
     } else {
         // if string length greater than 5, mask all characters
@@ -40,17 +148,62 @@ pub fn mask_sensitive(word: &str) -> String {
     };
 }
 
+/// Settings shared by every HTTP client gert builds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientOptions<'a> {
+    /// Trust any certificate at all
+    pub accept_invalid_certs: bool,
+    /// Extra root certificate to trust, for networks behind TLS intercepting proxies
+    pub ca_cert: Option<&'a str>,
+    /// Idle connections kept open to each host for reuse, unlimited by default
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Speak HTTP/2 right away instead of negotiating it
+    pub http2_prior_knowledge: bool,
+    /// Interval of the TCP keep-alive probes of open connections
+    pub tcp_keepalive: Option<Duration>,
+    /// Send the cookie that confirms being over 18 to reddit
+    pub over18: bool,
+}
+
+/// Build the HTTP client of a run, sending the user agent of `username`. Every request gert
+/// makes goes through a client built here, so that they all share the same settings
+pub fn build_client(options: &ClientOptions, username: &str) -> Result<reqwest::Client, GertError> {
+    // keeps the cookies reddit sets, like a cookie store
+    let jar = reqwest::cookie::Jar::default();
+    if options.over18 {
+        // reddit skips the over 18 confirmation page for clients sending this cookie
+        let reddit = Url::parse("https://www.reddit.com").unwrap();
+        jar.add_cookie_str("over18=1; Domain=reddit.com; Path=/", &reddit);
+    }
+    let mut builder = reqwest::Client::builder()
+        .user_agent(get_user_agent_string(username))
+        .cookie_provider(Arc::new(jar))
+        .danger_accept_invalid_certs(options.accept_invalid_certs)
+        .tcp_keepalive(options.tcp_keepalive);
+    if let Some(path) = options.ca_cert {
+        let cert = reqwest::Certificate::from_pem(&fs::read(path)?)?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(max) = options.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if options.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    Ok(builder.build()?)
+}
+
 /// Check if the given application is present in the $PATH
 pub fn application_present(name: String) -> bool {
     which(name).is_ok()
 }
 
 pub async fn check_url_has_mime_type(
+    session: &reqwest::Client,
     url: &str,
     mime_type: mime::Name<'_>,
 ) -> Result<bool, GertError> {
-    let client = reqwest::Client::new();
-    let response = client.head(url).send().await?;
+    let response = session.head(url).send().await?;
     let headers = response.headers();
 
     match headers.get(CONTENT_TYPE) {
@@ -83,11 +236,84 @@ pub fn parse_env_file(path: &str) -> Result<UserEnv, GertError> {
     Ok(UserEnv { username, password, client_id, client_secret })
 }
 
-pub async fn parse_mpd(url: &str) -> (Option<String>, Option<String>) {
-    // Parse the MPD file to get the highest quality video and audio URLs
-    let response = reqwest::get(url).await.expect("Failed to fetch the URL");
+/// Load the credentials of a named profile from an env file. Profiles are either
+/// defined in `[name]` sections or with a `_NAME` suffix on the keys, eg: `CLIENT_ID_WORK`
+pub fn parse_env_profile(path: &str, profile: &str) -> Result<UserEnv, GertError> {
+    let contents = fs::read_to_string(path)?;
+    let mut profiles = parse_profiles(&contents);
+    profiles
+        .remove(&profile.to_lowercase())
+        .ok_or_else(|| GertError::ProfileNotFound(profile.to_owned()))
+}
+
+/// Load the credentials from an env file, using the given profile if any
+pub fn load_user_env(path: &str, profile: Option<&str>) -> Result<UserEnv, GertError> {
+    match profile {
+        Some(profile) => parse_env_profile(path, profile),
+        None => parse_env_file(path),
+    }
+}
 
-    let mpd_content = response.text().await.expect("Failed to read the response");
+/// Parse every complete profile in the contents of an env file, keyed by lowercase name
+fn parse_profiles(contents: &str) -> HashMap<String, UserEnv> {
+    const KEYS: [&str; 4] = ["CLIENT_ID", "CLIENT_SECRET", "USERNAME", "PASSWORD"];
+
+    let mut values: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut section = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_lowercase();
+            continue;
+        }
+        let (key, value) = match line.trim_start_matches("export ").split_once('=') {
+            Some((key, value)) => {
+                (key.trim(), value.trim().trim_matches(|c: char| c == '"' || c == '\''))
+            }
+            None => continue,
+        };
+        // outside of a section, `CLIENT_ID_WORK` is the client id of the `work` profile
+        let suffixed = KEYS.iter().find_map(|k| {
+            key.strip_prefix(k).and_then(|rest| rest.strip_prefix('_')).map(|name| (*k, name))
+        });
+        let (profile, key) = match suffixed {
+            Some((k, name)) if section.is_empty() => (name.to_lowercase(), k),
+            _ => (section.clone(), key),
+        };
+        values.entry(profile).or_default().insert(key.to_owned(), value.to_owned());
+    }
+
+    values
+        .into_iter()
+        .filter(|(profile, _)| !profile.is_empty())
+        .filter_map(|(profile, mut keys)| {
+            let user_env = UserEnv {
+                client_id: keys.remove("CLIENT_ID")?,
+                client_secret: keys.remove("CLIENT_SECRET")?,
+                username: keys.remove("USERNAME")?,
+                password: keys.remove("PASSWORD")?,
+            };
+            Some((profile, user_env))
+        })
+        .collect()
+}
+
+pub async fn parse_mpd(session: &reqwest::Client, url: &str) -> (Option<String>, Option<String>) {
+    // Parse the MPD file to get the highest quality video and audio URLs
+    let mpd_content = match session.get(url).send().await {
+        Ok(response) if response.status().is_success() => response.text().await.unwrap_or_default(),
+        Ok(response) => {
+            debug!("Could not fetch MPD file {}: {}", url, response.status());
+            return (None, None);
+        }
+        Err(e) => {
+            debug!("Could not fetch MPD file {}: {}", url, e);
+            return (None, None);
+        }
+    };
 
     let parser = EventReader::from_str(&mpd_content);
     let mut max_video_bandwidth = 0;
@@ -152,3 +378,507 @@ pub fn has_extension(url: &str, extensions: &[&str]) -> bool {
 pub fn contains_any(url: &str, substrings: &[&str]) -> bool {
     substrings.iter().any(|&substring| url.contains(substring))
 }
+
+/// Decode the HTML entities reddit uses when escaping URLs in its JSON responses
+pub fn decode_html_entities(text: &str) -> String {
+    // `&amp;` goes last so that an escaped entity such as `&amp;lt;` is only decoded once
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The kind of reddit page a URL points to
+#[derive(Debug, PartialEq, Eq)]
+pub enum RedditUrl {
+    /// A single post, eg: /r/<subreddit>/comments/<id>/<slug>
+    Post,
+    /// A comment permalink, eg: /r/<subreddit>/comments/<id>/<slug>/<comment_id>
+    Comment { id: String },
+    /// A gallery link, eg: /gallery/<id>. Its JSON is not a post, the post is at /comments/<id>
+    Gallery { id: String },
+    /// A subreddit listing, eg: /r/<subreddit>/top/?t=week
+    Subreddit { name: String, feed: Option<String>, period: Option<String> },
+    /// The submissions of a user, eg: /user/<name>/submitted/?sort=top&t=week
+    User { name: String, feed: Option<String>, period: Option<String> },
+}
+
+/// Work out whether a URL points to a post, a subreddit or a user and extract the
+/// feed and time period from it. Anything that is not recognised is treated as a post
+pub fn parse_reddit_url(url: &Url) -> RedditUrl {
+    let segments: Vec<&str> = match url.path_segments() {
+        Some(segments) => segments.filter(|s| !s.is_empty()).collect(),
+        None => Vec::new(),
+    };
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let period = query.get("t").filter(|t| PERIODS.contains(&t.as_str())).cloned();
+
+    match segments.as_slice() {
+        ["r" | "user" | "u", _, "comments", _, _, comment_id, ..] => {
+            RedditUrl::Comment { id: comment_id.to_string() }
+        }
+        ["gallery", id, ..] => RedditUrl::Gallery { id: id.to_string() },
+        ["r", name] => RedditUrl::Subreddit { name: name.to_string(), feed: None, period },
+        ["r", name, feed] if FEEDS.contains(feed) => {
+            RedditUrl::Subreddit { name: name.to_string(), feed: Some(feed.to_string()), period }
+        }
+        // posts made directly to a user's profile
+        ["user" | "u", _, "comments", ..] => RedditUrl::Post,
+        ["user" | "u", name, ..] => {
+            let feed = query.get("sort").filter(|f| FEEDS.contains(&f.as_str())).cloned();
+            RedditUrl::User { name: name.to_string(), feed, period }
+        }
+        _ => RedditUrl::Post,
+    }
+}
+
+/// Parse posts from either a JSON array or newline delimited JSON objects
+pub fn parse_posts(input: &str) -> Result<Vec<Post>, GertError> {
+    let input = input.trim();
+    if input.starts_with('[') {
+        return serde_json::from_str(input)
+            .map_err(|e| GertError::JsonParseError(format!("stdin ({})", e)));
+    }
+    input
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .map_err(|e| GertError::JsonParseError(format!("stdin ({})", e)))
+        })
+        .collect()
+}
+
+/// Parse the creation time of the posts once they are collected, see `PostData::normalize_created`.
+/// Posts whose time can not be parsed are reported once for all of them
+pub fn normalize_timestamps(posts: &mut [Post]) {
+    let mut unexpected = Vec::new();
+    for post in posts.iter_mut() {
+        if !post.data.normalize_created() {
+            unexpected.push(post.data.name.clone());
+        }
+    }
+    if let Some(first) = unexpected.first() {
+        warn!(
+            "{} posts have a creation time in an unexpected format, eg: {}. \
+            Date filters and folder templates treat them as undated",
+            unexpected.len(),
+            first
+        );
+    }
+}
+
+/// Write the posts as one JSON object per line, the format `--json-stream` reads
+pub fn write_posts(path: &Path, posts: &[Post]) -> Result<(), GertError> {
+    let mut file = fs::File::create(path)?;
+    for post in posts {
+        let line = serde_json::to_string(post)
+            .map_err(|e| GertError::JsonParseError(format!("post {} ({})", post.data.name, e)))?;
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Read the saved and upvoted posts of a reddit data export into unique fullnames of the form
+/// t3_<id>. Posts are taken from the id column, or from the permalink when there is none
+pub fn read_reddit_export(directory: &Path) -> Result<Vec<String>, GertError> {
+    let mut ids = Vec::new();
+    let mut found = false;
+    for name in EXPORT_FILES {
+        let path = directory.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        found = true;
+        let contents = fs::read_to_string(&path)?;
+        let mut rows = contents.lines().filter(|line| !line.trim().is_empty()).map(split_csv_line);
+        let header = match rows.next() {
+            Some(header) => header,
+            None => continue,
+        };
+        let column = |name: &str| header.iter().position(|column| column.trim() == name);
+        let (id, permalink, direction) = (column("id"), column("permalink"), column("direction"));
+        for row in rows {
+            let cell = |index: Option<usize>| index.and_then(|i| row.get(i)).map(|c| c.trim());
+            // post_votes.csv also lists the downvoted posts
+            if cell(direction).is_some_and(|direction| direction != "up") {
+                continue;
+            }
+            let post_id = cell(id).filter(|id| !id.is_empty()).map(String::from).or_else(|| {
+                let url = Url::parse(cell(permalink)?).ok()?;
+                let segments: Vec<&str> = url.path_segments()?.collect();
+                let position = segments.iter().position(|segment| *segment == "comments")?;
+                segments.get(position + 1).map(|id| id.to_string())
+            });
+            ids.extend(post_id);
+        }
+        debug!("Read {} from the reddit export", path.display());
+    }
+    if !found {
+        return Err(GertError::ExportNotFound(directory.display().to_string()));
+    }
+    Ok(parse_post_ids(&ids.join("\n")))
+}
+
+/// Split a line of a CSV file into its cells, unquoting the quoted ones
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut cells = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cells.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => cells.push(String::new()),
+            c => cells.last_mut().unwrap().push(c),
+        }
+    }
+    cells
+}
+
+/// Parse post ids separated by commas, whitespace or newlines into unique fullnames of
+/// the form t3_<id>. Lines starting with # are ignored.
+pub fn parse_post_ids(input: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    input
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .map(|id| id.trim_matches('"'))
+        .filter(|id| !id.is_empty())
+        .map(|id| if id.starts_with("t3_") { id.to_owned() } else { format!("t3_{}", id) })
+        .filter(|id| seen.insert(id.clone()))
+        .collect()
+}
+
+/// Fetch posts by their fullname with the /api/info endpoint, 100 posts per request
+pub async fn get_posts_by_id(
+    reddit_url: &str,
+    session: &reqwest::Client,
+    rate_limiter: &RateLimiter,
+    ids: &[String],
+) -> Result<Vec<Post>, GertError> {
+    let mut posts = Vec::with_capacity(ids.len());
+    for batch in ids.chunks(INFO_BATCH_SIZE) {
+        let url = format!("{}/api/info.json?id={}&raw_json=1", reddit_url, batch.join(","));
+        debug!("Fetching {} posts from {}", batch.len(), url);
+        rate_limiter.acquire().await;
+        let listing: Listing = session
+            .get(&url)
+            .send()
+            .await?
+            .json()
+            .await
+            .map_err(|_| GertError::JsonParseError(url.clone()))?;
+        posts.extend(listing.data.children);
+    }
+    Ok(posts)
+}
+
+/// Render the name of the folder the media of a subreddit are saved to. The template
+/// supports {subreddit}, {subreddit_lower}, {date}, {year} and {month}, where the date
+/// is the creation date of the post. Falls back to the subreddit name if the result is
+/// not a usable folder name.
+pub fn render_subreddit_dir(template: &str, subreddit: &str, created_utc: Option<i64>) -> String {
+    let date = created_utc.and_then(|secs| DateTime::from_timestamp(secs, 0));
+    let format_date = |format: &str| match date {
+        Some(date) => date.format(format).to_string(),
+        None => String::from("unknown"),
+    };
+    let rendered = template
+        .replace("{subreddit_lower}", &subreddit.to_lowercase())
+        .replace("{subreddit}", subreddit)
+        .replace("{date}", &format_date("%Y-%m-%d"))
+        .replace("{year}", &format_date("%Y"))
+        .replace("{month}", &format_date("%m"));
+
+    let disallowed_chars = ['/', '\\', ':', '?', '"', '<', '>', '|', '*'];
+    let sanitized: String = rendered
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if disallowed_chars.contains(&c) { '_' } else { c })
+        .take(200)
+        .collect();
+    let sanitized = sanitized.trim();
+    if sanitized.is_empty() || sanitized.chars().all(|c| c == '.') {
+        return String::from(subreddit);
+    }
+    String::from(sanitized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::test_post;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_build_client() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("user-agent", get_user_agent_string("spez").as_str()))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let client = build_client(&ClientOptions::default(), "spez").unwrap();
+        let response = client.get(server.uri()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let options = ClientOptions { ca_cert: Some("missing-cert.pem"), ..Default::default() };
+        assert!(build_client(&options, "spez").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_webhook() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)).mount(&server).await;
+
+        let summary = RunSummary {
+            supported: 5,
+            unsupported: 1,
+            downloaded: 3,
+            skipped: 1,
+            failed: 1,
+            hosts: Default::default(),
+            subreddits: Default::default(),
+            seconds: 12.3,
+            bytes: 1024,
+            megabytes_per_second: 0.1,
+            failed_posts: vec![String::from("t3_abc")],
+            unsupported_urls: vec![],
+            estimated_bytes: None,
+        };
+        let session = reqwest::Client::new();
+        send_webhook(&session, &server.uri(), &summary).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let payload: Value = serde_json::from_slice(&requests[0].body).unwrap();
+        let message = "gert finished in 12s: 3 downloaded, 1 skipped, 1 failed, 1 unsupported";
+        // Discord reads `content` and Slack reads `text`
+        assert_eq!(payload["content"], message);
+        assert_eq!(payload["text"], message);
+        assert_eq!(payload["downloaded"], 3);
+        assert_eq!(payload["failed_posts"][0], "t3_abc");
+
+        // an error status is reported instead of ignored, without the URL of the webhook
+        let error = send_webhook(&session, &server.uri(), &summary).await.unwrap_err();
+        assert!(!error.to_string().contains(&server.uri()));
+    }
+
+    #[test]
+    fn test_parse_reddit_url() {
+        let parse = |url: &str| parse_reddit_url(&Url::parse(url).unwrap());
+        let subreddit =
+            |name: &str, feed: Option<&str>, period: Option<&str>| RedditUrl::Subreddit {
+                name: name.to_owned(),
+                feed: feed.map(String::from),
+                period: period.map(String::from),
+            };
+        assert_eq!(parse("https://www.reddit.com/r/pics/"), subreddit("pics", None, None));
+        assert_eq!(
+            parse("https://old.reddit.com/r/pics/top/?t=week"),
+            subreddit("pics", Some("top"), Some("week"))
+        );
+        // unknown periods are left to the --period default
+        assert_eq!(
+            parse("https://www.reddit.com/r/pics/new?t=never"),
+            subreddit("pics", Some("new"), None)
+        );
+        assert_eq!(
+            parse("https://www.reddit.com/user/spez/submitted/?sort=top&t=all"),
+            RedditUrl::User {
+                name: String::from("spez"),
+                feed: Some(String::from("top")),
+                period: Some(String::from("all"))
+            }
+        );
+        assert_eq!(
+            parse("https://www.reddit.com/u/spez"),
+            RedditUrl::User { name: String::from("spez"), feed: None, period: None }
+        );
+        assert_eq!(
+            parse("https://www.reddit.com/user/spez/comments/abc123/a_post/"),
+            RedditUrl::Post
+        );
+        assert_eq!(parse("https://redd.it/abc123"), RedditUrl::Post);
+    }
+
+    #[test]
+    fn test_parse_comment_url() {
+        let url =
+            Url::parse("https://www.reddit.com/r/pics/comments/abc123/some_title/def456/").unwrap();
+        assert_eq!(parse_reddit_url(&url), RedditUrl::Comment { id: String::from("def456") });
+        let url = Url::parse("https://www.reddit.com/r/pics/comments/abc123/some_title/").unwrap();
+        assert_eq!(parse_reddit_url(&url), RedditUrl::Post);
+    }
+
+    #[test]
+    fn test_parse_gallery_url() {
+        let url = Url::parse("https://www.reddit.com/gallery/abc123").unwrap();
+        assert_eq!(parse_reddit_url(&url), RedditUrl::Gallery { id: String::from("abc123") });
+        let url = Url::parse("https://reddit.com/gallery/abc123/?utm_source=share").unwrap();
+        assert_eq!(parse_reddit_url(&url), RedditUrl::Gallery { id: String::from("abc123") });
+    }
+
+    #[test]
+    fn test_render_subreddit_dir() {
+        let created = Some(1700000000); // 2023-11-14
+        assert_eq!(render_subreddit_dir("{date}_{subreddit}", "Pics", created), "2023-11-14_Pics");
+        assert_eq!(render_subreddit_dir("{subreddit_lower}", "Pics", created), "pics");
+        assert_eq!(
+            render_subreddit_dir("{year}/{month} {subreddit}", "Pics", None),
+            "unknown_unknown Pics"
+        );
+        assert_eq!(render_subreddit_dir("../{subreddit}", "Pics", created), ".._Pics");
+        assert_eq!(render_subreddit_dir("..", "Pics", created), "Pics");
+        assert_eq!(render_subreddit_dir("  ", "Pics", created), "Pics");
+    }
+
+    #[test]
+    fn test_normalize_imgur_url() {
+        let cases = [
+            ("https://imgur.com/AbC123?foo=bar#x", "https://imgur.com/AbC123"),
+            ("https://m.imgur.com/AbC123/", "https://imgur.com/AbC123"),
+            ("https://imgur.com/r/pics/AbC123", "https://imgur.com/AbC123"),
+            ("https://imgur.com/a/XyZ789/embed?pub=true", "https://imgur.com/a/XyZ789"),
+            ("https://imgur.com/a/XyZ789#0", "https://imgur.com/a/XyZ789"),
+            ("https://i.imgur.com/AbC123.gifv?1", "https://i.imgur.com/AbC123.gifv"),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(normalize_imgur_url(url).as_deref(), Some(expected));
+        }
+        assert_eq!(normalize_imgur_url("https://example.com/AbC123"), None);
+        assert_eq!(normalize_imgur_url("https://imgur.com/"), None);
+    }
+
+    #[test]
+    fn test_mask_sensitive() {
+        assert_eq!(mask_sensitive(""), "<EMPTY>");
+        assert_eq!(mask_sensitive("a"), "*");
+        assert_eq!(mask_sensitive("abc"), "***");
+        assert_eq!(mask_sensitive("abcd"), "****");
+        assert_eq!(mask_sensitive("abcdefgh"), "ab*****h");
+    }
+
+    #[test]
+    fn test_check_data_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap();
+        assert!(check_data_directory(directory, true).is_ok());
+        let file = dir.path().join("file.jpg");
+        fs::write(&file, b"").unwrap();
+        assert!(matches!(
+            check_data_directory(file.to_str().unwrap(), false),
+            Err(GertError::DataDirNotADirectory(_))
+        ));
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            check_data_directory(missing.to_str().unwrap(), false),
+            Err(GertError::DataDirNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_lock_data_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap();
+        let lock = lock_data_directory(directory, false).unwrap();
+        assert!(matches!(lock_data_directory(directory, false), Err(GertError::DataDirLocked(_))));
+        assert!(lock_data_directory(directory, true).is_ok());
+        drop(lock);
+        assert!(lock_data_directory(directory, false).is_ok());
+    }
+
+    #[test]
+    fn test_write_posts() {
+        let mut post = test_post("https://i.redd.it/abc.jpg");
+        post.data.score = 42;
+        post.data.selftext = Some(String::new());
+        post.data.num_comments = Some(7);
+        post.data.poll_data = serde_json::from_value(serde_json::json!({
+            "total_vote_count": 3, "voting_end_timestamp": 1700086400000_i64,
+            "options": [{"id": "1", "text": "Yes", "vote_count": 2},
+                {"id": "2", "text": "No", "vote_count": 1}]
+        }))
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("posts.jsonl");
+        write_posts(&path, &[post.clone(), post]).unwrap();
+
+        let written = parse_posts(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0].data.name, "t3_abc123");
+        assert_eq!(written[0].data.score, 42);
+        assert_eq!(written[0].data.url.as_deref(), Some("https://i.redd.it/abc.jpg"));
+        assert_eq!(written[0].data.selftext.as_deref(), Some(""));
+        assert_eq!(written[0].data.num_comments, Some(7));
+        let poll = written[0].data.poll_data.as_ref().unwrap();
+        assert_eq!(poll.total_vote_count, Some(3));
+        assert_eq!(poll.options[1].text, "No");
+        assert_eq!(poll.options[1].vote_count, Some(1));
+    }
+
+    #[test]
+    fn test_parse_post_ids() {
+        let input = "# exported ids\nabc123, t3_def456\n\n\"ghi789\" abc123\n";
+        assert_eq!(parse_post_ids(input), vec!["t3_abc123", "t3_def456", "t3_ghi789"]);
+        assert!(parse_post_ids("# nothing\n \n").is_empty());
+    }
+
+    #[test]
+    fn test_read_reddit_export() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_reddit_export(dir.path()).is_err());
+
+        let saved = "id,permalink\nabc123,https://www.reddit.com/r/pics/comments/abc123/a_title/\n\
+            ,\"https://www.reddit.com/r/pics/comments/def456/with,comma/\"\n";
+        fs::write(dir.path().join("saved_posts.csv"), saved).unwrap();
+        let votes =
+            "id,permalink,direction\nghi789,https://www.reddit.com/r/aww/comments/ghi789/x/,up\n\
+            jkl012,https://www.reddit.com/r/aww/comments/jkl012/y/,down\n\
+            abc123,https://www.reddit.com/r/pics/comments/abc123/a_title/,up\n";
+        fs::write(dir.path().join("post_votes.csv"), votes).unwrap();
+
+        assert_eq!(
+            read_reddit_export(dir.path()).unwrap(),
+            vec!["t3_abc123", "t3_def456", "t3_ghi789"]
+        );
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        let contents = r#"
+CLIENT_ID="flat_id"
+CLIENT_ID_WORK=work_id
+CLIENT_SECRET_WORK=work_secret
+USERNAME_WORK=work_user
+PASSWORD_WORK=work_pass
+
+[personal]
+CLIENT_ID='personal_id'
+CLIENT_SECRET=personal_secret
+USERNAME=personal_user
+PASSWORD=personal_pass
+
+[incomplete]
+CLIENT_ID=id
+"#;
+        let profiles = parse_profiles(contents);
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles["work"].client_id, "work_id");
+        assert_eq!(profiles["work"].password, "work_pass");
+        assert_eq!(profiles["personal"].client_id, "personal_id");
+        assert_eq!(profiles["personal"].username, "personal_user");
+    }
+}
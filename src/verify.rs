@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::errors::GertError;
+use crate::utils::application_present;
+
+/// Extensions of the files gert downloads
+static MEDIA_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "mp4", "mkv", "webm", "m4a"];
+
+/// Files smaller than this are error responses or placeholders rather than media
+pub const MIN_MEDIA_SIZE: u64 = 256;
+
+/// Bytes at the end of an image searched for its end marker, encoders may pad after it
+const TRAILER_SIZE: u64 = 64;
+
+/// Walk the output directory and check that every media file is valid, reporting
+/// (and optionally deleting) the broken ones so that the next run downloads them again
+pub fn verify_directory(directory: &str, delete: bool) -> Result<(), GertError> {
+    let ffprobe_available = application_present(String::from("ffprobe"));
+    if !ffprobe_available {
+        warn!("No ffprobe installation available, only checking the file headers");
+    }
+
+    let mut files = Vec::new();
+    collect_media_files(Path::new(directory), &mut files)?;
+
+    let mut corrupt = 0;
+    for file in files.iter() {
+        let problem = match check_file(file)? {
+            Some(problem) => Some(problem),
+            None if ffprobe_available => probe_file(file)?,
+            None => None,
+        };
+        if let Some(problem) = problem {
+            corrupt += 1;
+            if delete {
+                fs::remove_file(file)?;
+                warn!("Deleted {}: {}", file.display(), problem);
+            } else {
+                warn!("{}: {}", file.display(), problem);
+            }
+        } else {
+            debug!("{} is valid", file.display());
+        }
+    }
+
+    info!("#####################################");
+    info!("Verification Summary:");
+    info!("Number of media checked: {}", files.len());
+    info!("Number of corrupt media: {}", corrupt);
+    info!("#####################################");
+
+    Ok(())
+}
+
+/// Media files with the same contents
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// MD5 of the contents of the files
+    pub hash: String,
+    /// Size of each file in bytes
+    pub size: u64,
+    pub files: Vec<String>,
+}
+
+impl DuplicateGroup {
+    /// Bytes freed by keeping a single file of the group
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.files.len() as u64 - 1)
+    }
+}
+
+/// Group the media files in the output directory by their contents. Only files that share
+/// their size with another file are hashed
+pub fn find_duplicates(directory: &str) -> Result<Vec<DuplicateGroup>, GertError> {
+    let mut files = Vec::new();
+    collect_media_files(Path::new(directory), &mut files)?;
+    files.sort();
+
+    let mut by_size: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        by_size.entry(fs::metadata(&file)?.len()).or_default().push(file);
+    }
+
+    let mut groups = Vec::new();
+    for (size, files) in by_size.into_iter().filter(|(_, files)| files.len() > 1) {
+        let mut by_hash: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for file in files {
+            by_hash.entry(file_hash(&file)?).or_default().push(file.display().to_string());
+        }
+        groups.extend(
+            by_hash
+                .into_iter()
+                .filter(|(_, files)| files.len() > 1)
+                .map(|(hash, files)| DuplicateGroup { hash, size, files }),
+        );
+    }
+    Ok(groups)
+}
+
+/// Report the duplicate media in the output directory, and write the groups as JSON to
+/// `output` when given. Nothing is deleted
+pub fn dedupe_report(directory: &str, output: Option<&str>) -> Result<(), GertError> {
+    let groups = find_duplicates(directory)?;
+    for group in groups.iter() {
+        info!("{} copies of {} bytes:", group.files.len(), group.size);
+        for file in group.files.iter() {
+            info!("    {}", file);
+        }
+    }
+    if let Some(path) = output {
+        let report = serde_json::to_string_pretty(&groups)
+            .map_err(|e| GertError::JsonParseError(format!("{} ({})", path, e)))?;
+        fs::write(path, report + "\n")?;
+    }
+
+    info!("#####################################");
+    info!("Duplicates Summary:");
+    info!("Number of duplicate groups: {}", groups.len());
+    info!(
+        "Number of redundant files: {}",
+        groups.iter().map(|group| group.files.len() - 1).sum::<usize>()
+    );
+    info!("Reclaimable bytes: {}", groups.iter().map(DuplicateGroup::reclaimable).sum::<u64>());
+    info!("#####################################");
+
+    Ok(())
+}
+
+/// MD5 of the contents of a file, read in chunks
+fn file_hash(path: &Path) -> Result<String, GertError> {
+    let mut file = fs::File::open(path)?;
+    let mut context = md5::Context::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.consume(&buffer[..read]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+fn collect_media_files(directory: &Path, files: &mut Vec<PathBuf>) -> Result<(), GertError> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_media_files(&path, files)?;
+            continue;
+        }
+        let is_media = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| MEDIA_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            .unwrap_or(false);
+        if is_media {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Check the size and the magic bytes of a file against its extension
+fn check_file(path: &Path) -> Result<Option<String>, GertError> {
+    let mut header = Vec::with_capacity(16);
+    fs::File::open(path)?.take(16).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(Some(String::from("file is empty")));
+    }
+
+    if is_html(&header) {
+        return Ok(Some(String::from("file is an HTML page")));
+    }
+
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("").to_lowercase();
+    let valid = match extension.as_str() {
+        "jpg" | "jpeg" => header.starts_with(&[0xFF, 0xD8, 0xFF]),
+        "png" => header.starts_with(&[0x89, b'P', b'N', b'G']),
+        "gif" => header.starts_with(b"GIF8"),
+        "mp4" | "m4a" => header.len() >= 8 && &header[4..8] == b"ftyp",
+        _ => true,
+    };
+    if !valid {
+        return Ok(Some(format!("content does not look like a {} file", extension)));
+    }
+    if is_truncated(path, &extension)? {
+        return Ok(Some(format!("{} file is truncated", extension)));
+    }
+    Ok(None)
+}
+
+/// Whether a JPEG or PNG image stops before its end marker, as the downloads cut short do
+fn is_truncated(path: &Path, extension: &str) -> io::Result<bool> {
+    let marker: &[u8] = match extension {
+        "jpg" | "jpeg" => &[0xFF, 0xD9],
+        "png" => b"IEND",
+        _ => return Ok(false),
+    };
+    let mut file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(size.saturating_sub(TRAILER_SIZE)))?;
+    let mut trailer = Vec::with_capacity(TRAILER_SIZE as usize);
+    file.read_to_end(&mut trailer)?;
+    Ok(!trailer.windows(marker.len()).any(|window| window == marker))
+}
+
+/// Quick check for the files left behind by failed downloads: tiny files, HTML error pages
+/// and images cut short. Cheaper than `check_file` as it does not check the magic bytes
+pub fn looks_corrupt(path: &Path) -> bool {
+    let mut header = Vec::with_capacity(16);
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("").to_lowercase();
+    let read = fs::File::open(path).and_then(|file| {
+        let size = file.metadata()?.len();
+        file.take(16).read_to_end(&mut header)?;
+        Ok((size, is_truncated(path, &extension)?))
+    });
+    match read {
+        Ok((size, truncated)) => size < MIN_MEDIA_SIZE || is_html(&header) || truncated,
+        Err(e) => {
+            debug!("Could not read {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+fn is_html(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header).trim_start().to_lowercase();
+    text.starts_with("<!doctype") || text.starts_with("<html")
+}
+
+/// Let ffprobe decode the file, any error it reports means the file is truncated or broken
+fn probe_file(path: &Path) -> Result<Option<String>, GertError> {
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stderr.trim().is_empty() {
+        let reason = stderr.lines().next().unwrap_or("ffprobe failed").to_owned();
+        return Ok(Some(reason));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, contents: &[u8]| {
+            let path = dir.path().join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        };
+        let mut jpg = vec![0xFF, 0xD8, 0xFF, 0xE0];
+        jpg.resize(1022, 0);
+        jpg.extend([0xFF, 0xD9]);
+        assert!(!looks_corrupt(&file("image.jpg", &jpg)));
+        assert!(looks_corrupt(&file("empty.jpg", b"")));
+        assert!(looks_corrupt(&file("tiny.jpg", &jpg[..100])));
+        // a download cut short misses the end of image marker
+        assert!(looks_corrupt(&file("truncated.jpg", &jpg[..800])));
+        assert_eq!(
+            check_file(&file("cut.jpg", &jpg[..800])).unwrap().unwrap(),
+            "jpg file is truncated"
+        );
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.resize(1012, 0);
+        png.extend(b"IEND\xaeB`\x82");
+        assert!(!looks_corrupt(&file("image.png", &png)));
+        assert_eq!(check_file(&file("valid.png", &png)).unwrap(), None);
+        assert!(looks_corrupt(&file("truncated.png", &png[..600])));
+
+        let mut page = b"  <!DOCTYPE html><html><body>Not Found</body></html>".to_vec();
+        page.resize(1024, b' ');
+        assert!(looks_corrupt(&file("page.mp4", &page)));
+        assert!(!looks_corrupt(&dir.path().join("missing.mp4")));
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, contents: &[u8]| {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path.display().to_string()
+        };
+        let a = file("pics/a.jpg", b"same bytes");
+        let b = file("aww/b.jpg", b"same bytes");
+        file("pics/c.jpg", b"diff bytes");
+        file("pics/d.png", b"other");
+        file("pics/notes.txt", b"same bytes");
+
+        let groups = find_duplicates(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            groups,
+            vec![DuplicateGroup {
+                hash: format!("{:x}", md5::compute(b"same bytes")),
+                size: 10,
+                files: vec![b, a],
+            }]
+        );
+        assert_eq!(groups[0].reclaimable(), 10);
+    }
+}
@@ -235,3 +235,46 @@ async fn run_test_case(test_case: TestCase) {
     }
 
 }
+
+#[tokio::test]
+async fn test_fetch_only_keeps_self_posts() {
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let server = MockServer::start().await;
+    let post = |id: &str, url: Value, is_self: bool| {
+        json!({"kind": "t3", "data": {
+            "subreddit": "pics", "id": id, "score": 1, "subreddit_id": "t5_2qh0u",
+            "saved": false, "permalink": format!("/r/pics/comments/{}/", id),
+            "name": format!("t3_{}", id), "created": 1700000000.0,
+            "created_utc": 1700000000.0, "url": url, "title": "test", "is_self": is_self,
+            "selftext": if is_self { "Which one?" } else { "" }
+        }})
+    };
+    let listing = json!({"kind": "Listing", "data": {"after": null, "dist": 2, "children": [
+        post("abc123", json!("https://i.redd.it/abc.jpg"), false),
+        post("def456", json!("https://www.reddit.com/r/pics/comments/def456/"), true),
+    ]}});
+    Mock::given(method("GET"))
+        .and(path("/r/pics/hot.json"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(listing))
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = Command::cargo_bin("gert")
+        .unwrap()
+        .env("GERT_REDDIT_URL", server.uri())
+        .args(["-s", "pics", "--fetch-only", "-o"])
+        .arg(dir.path())
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let written = fs::read_to_string(dir.path().join("posts.jsonl")).unwrap();
+    let posts: Vec<Value> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(posts.len(), 2);
+    assert_eq!(posts[1]["data"]["is_self"], json!(true));
+    assert_eq!(posts[1]["data"]["selftext"], json!("Which one?"));
+}